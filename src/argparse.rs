@@ -13,18 +13,22 @@ pub fn parse_args(
     let mut argsi = args.into_iter();
     while let Some(arg) = argsi.next() {
         if arg.starts_with("-") {
-            let mut argnames: Vec<&str>;
-            if arg.chars().nth(1) == Some('-') {
-                let long_argname = arg.split_at(2).1;
-                argnames = Vec::<&str>::new();
-                argnames.push(long_argname);
+            // value given as --option=value instead of a separate argument
+            let mut inline_value: Option<String> = None;
+            let argnames: Vec<&str> = if arg.chars().nth(1) == Some('-') {
+                let mut long_argname = arg.split_at(2).1;
+                if let Some((name, value)) = long_argname.split_once('=') {
+                    long_argname = name;
+                    inline_value = Some(value.to_string());
+                }
+                vec![long_argname]
             } else {
-                argnames = arg
+                arg
                     .split_at(1)
                     .1.split("")
                     .filter(|x| x != &"")
-                    .collect();
-            }
+                    .collect()
+            };
             for argname in argnames {
                 let mut value: String = "".to_string();
                 let argname_dsp = format!(
@@ -45,7 +49,7 @@ pub fn parse_args(
                 macro_rules! opt_with_arg {
                     ($longhand:expr) => {
                         {
-                            match argsi.next() {
+                            match inline_value.take().or_else(|| argsi.next()) {
                                 Some(val) => {
                                     value = val;
                                     longhand = $longhand;
@@ -60,6 +64,12 @@ pub fn parse_args(
                 macro_rules! opt_no_arg {
                     ($longhand:expr) => {
                         {
+                            if inline_value.is_some() {
+                                return Err((
+                                    format!("Option \"{}\" does not take a value.\nRun without arguments for the help page.", argname_dsp),
+                                    ExitReason::OptionBadValue,
+                                ));
+                            }
                             longhand = $longhand;
                         }
                     };
@@ -72,6 +82,16 @@ pub fn parse_args(
                     "soft-silent" | "S" => opt_no_arg!("soft-silent"),
                     "verbose" | "v" => opt_no_arg!("verbose"),
                     "debug" => opt_no_arg!("debug"),
+                    "ipt" => opt_with_arg!("ipt"),
+                    "ticks" => opt_with_arg!("ticks"),
+                    "once" => opt_no_arg!("once"),
+                    "all-frames" => opt_no_arg!("all-frames"),
+                    "source-map" => opt_no_arg!("source-map"),
+                    "opt-level" => opt_with_arg!("opt-level"),
+                    "explain-opt" => opt_no_arg!("explain-opt"),
+                    "minify-names" => opt_no_arg!("minify-names"),
+                    "emit" => opt_with_arg!("emit"),
+                    "target" => opt_with_arg!("target"),
                     _ => {
                         return Err((
                             format!("Unknown option \"{}\".\nRun without arguments for the help page.", argname_dsp),
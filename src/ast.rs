@@ -0,0 +1,439 @@
+//! Syntax tree produced by `compile::parse_file`. Every node kind has its
+//! own variant holding exactly the fields it needs, and every node knows
+//! the range of source text it was parsed from.
+
+use std::fmt::Display;
+
+/// Range of source text a node was parsed from. `end_col` is the column
+/// of the last character, including a closing quote.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: u64,
+    pub col: u64,
+    pub end_line: u64,
+    pub end_col: u64,
+}
+
+/// Kind of a value. Literals are `Str` or `Num`, the other kinds come from
+/// globals, instructions and `let` annotations. A `Char` is a `Num` holding
+/// a codepoint, which char literals like `'A'` are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarType {
+    Str,
+    /// A codepoint for `printchar`, interchangeable with `Num`.
+    Char,
+    Num,
+    /// Items, liquids, blocks and unit types like `@copper`, and the
+    /// properties `sensor` reads like `@health`.
+    Content,
+    Building,
+    Unit,
+    Team,
+    Null,
+}
+
+impl VarType {
+    /// Kind named by a `let` annotation, like `Num` or `num`.
+    pub fn from_name(name: &str) -> Option<VarType> {
+        Some(match name {
+            "Str" | "str" => VarType::Str,
+            "Char" | "char" => VarType::Char,
+            "Num" | "num" => VarType::Num,
+            "Content" | "content" => VarType::Content,
+            "Building" | "building" => VarType::Building,
+            "Unit" | "unit" => VarType::Unit,
+            "Team" | "team" => VarType::Team,
+            "Null" | "null" => VarType::Null,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal { vt: VarType, value: String, span: Span },
+    /// A variable, or a global like `@time` when the name starts with `@`.
+    Var { name: String, span: Span },
+    Binary { op: String, left: Box<Expr>, right: Box<Expr>, span: Span },
+    Unary { op: String, operand: Box<Expr>, span: Span },
+    Call { name: String, args: Vec<Expr>, span: Span },
+    Macro { name: String, args: Vec<Expr>, span: Span },
+    /// `Enum::Variant`
+    Variant { enum_name: String, variant: String, span: Span },
+    /// `array[index]`
+    Index { name: String, index: Box<Expr>, span: Span },
+    /// `Name { field: value, ... }`, a value of a struct.
+    Struct { name: String, fields: Vec<(String, Expr)>, span: Span },
+    /// `base.field`, where `base` is a variable or another field.
+    Field { base: Box<Expr>, field: String, span: Span },
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Literal { span, .. } |
+            Expr::Var { span, .. } |
+            Expr::Binary { span, .. } |
+            Expr::Unary { span, .. } |
+            Expr::Call { span, .. } |
+            Expr::Macro { span, .. } |
+            Expr::Variant { span, .. } |
+            Expr::Index { span, .. } |
+            Expr::Struct { span, .. } |
+            Expr::Field { span, .. } => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    /// `x = value`. Compound assignments like `x += 1` are stored as
+    /// `x = x + 1`.
+    Assign { target: String, value: Expr, span: Span },
+    /// `let name: Type = value`, both the annotation and value optional.
+    /// `var` instead of `let` declares a global, which is not limited to
+    /// the block it is declared in.
+    Let { name: String, ty: Option<VarType>, value: Option<Expr>, global: bool, span: Span },
+    /// `let name: [Type; len]`, an array of variables, or with `in cell1`
+    /// the first `len` slots of a memory cell or bank.
+    Array { name: String, ty: VarType, len: Expr, memory: Option<Expr>, global: bool, span: Span },
+    /// `name[index] = value`
+    Store { name: String, index: Expr, value: Expr, span: Span },
+    /// `base.field = value`
+    SetField { base: Expr, field: String, value: Expr, span: Span },
+    /// `{ body }`, a scope for the variables declared in it.
+    Block { body: Vec<Stmt>, span: Span },
+    /// `match value { 0 => ..., 1 | 2 => ..., _ => ... }`, running the first
+    /// arm with a pattern equal to the value.
+    Match { value: Expr, arms: Vec<Arm>, span: Span },
+    Return { value: Option<Expr>, span: Span },
+    /// An expression evaluated for its effect, like a call.
+    Expr(Expr),
+}
+
+impl Stmt {
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Assign { span, .. } |
+            Stmt::Let { span, .. } |
+            Stmt::Array { span, .. } |
+            Stmt::Store { span, .. } |
+            Stmt::SetField { span, .. } |
+            Stmt::Block { span, .. } |
+            Stmt::Match { span, .. } |
+            Stmt::Return { span, .. } => *span,
+            Stmt::Expr(expr) => expr.span(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// A value known at compile time.
+    Value(Expr),
+    /// `_`, matching everything.
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arm {
+    pub patterns: Vec<Pattern>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub span: Span,
+}
+
+/// `fn name(params) { body }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub body: Vec<Stmt>,
+    /// The `## doc comment` lines before the function.
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+/// `test "name" { body }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Test {
+    pub name: String,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// `const NAME = value;`, evaluated at compile time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Const {
+    pub name: String,
+    pub value: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub span: Span,
+}
+
+/// `struct Name { x, y }`. A variable holding one is a group of variables,
+/// one per field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+    pub span: Span,
+}
+
+/// `enum Name { A, B }`, the variants numbered from 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Enum {
+    pub name: String,
+    pub variants: Vec<Variant>,
+    pub span: Span,
+}
+
+/// What a source file is made of: functions, tests, constants, enums and
+/// structs are only allowed at the top level, between the statements of
+/// the program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Fn(Function),
+    Test(Test),
+    Const(Const),
+    Enum(Enum),
+    Struct(Struct),
+    Stmt(Stmt),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub items: Vec<Item>,
+}
+
+impl Module {
+    /// Top level statements, the program itself.
+    pub fn statements(&self) -> impl Iterator<Item = &Stmt> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Stmt(stmt) => Some(stmt),
+            _ => None,
+        });
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Fn(func) => Some(func),
+            _ => None,
+        });
+    }
+
+    pub fn tests(&self) -> impl Iterator<Item = &Test> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Test(test) => Some(test),
+            _ => None,
+        });
+    }
+
+    pub fn consts(&self) -> impl Iterator<Item = &Const> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Const(constant) => Some(constant),
+            _ => None,
+        });
+    }
+
+    pub fn enums(&self) -> impl Iterator<Item = &Enum> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Enum(enumeration) => Some(enumeration),
+            _ => None,
+        });
+    }
+
+    pub fn structs(&self) -> impl Iterator<Item = &Struct> {
+        return self.items.iter().filter_map(|x| match x {
+            Item::Struct(structure) => Some(structure),
+            _ => None,
+        });
+    }
+}
+
+fn join<T: Display>(items: &[T]) -> String {
+    return items.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ");
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Literal { vt, value, .. } => write!(f, "<Literal \"{}\" ({:?})>", value.replace('\n', "\\n"), vt),
+            Expr::Var { name, .. } => write!(f, "<Var \"{}\">", name),
+            Expr::Binary { op, left, right, .. } => write!(f, "<Binary {} \"{}\" {}>", left, op, right),
+            Expr::Unary { op, operand, .. } => write!(f, "<Unary \"{}\" {}>", op, operand),
+            Expr::Call { name, args, .. } => write!(f, "<Call {}([{}])>", name, join(args)),
+            Expr::Macro { name, args, .. } => write!(f, "<Macro {}!([{}])>", name, join(args)),
+            Expr::Variant { enum_name, variant, .. } => write!(f, "<Variant {}::{}>", enum_name, variant),
+            Expr::Index { name, index, .. } => write!(f, "<Index \"{}\" {}>", name, index),
+            Expr::Struct { name, fields, .. } => {
+                let fields = fields.iter().map(|(field, value)| format!("{}: {}", field, value)).collect::<Vec<_>>().join(", ");
+                write!(f, "<Struct {} {{{}}}>", name, fields)
+            }
+            Expr::Field { base, field, .. } => write!(f, "<Field {} \"{}\">", base, field),
+        }
+    }
+}
+
+impl Display for Stmt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stmt::Assign { target, value, .. } => write!(f, "<Assign \"{}\" {}>", target, value),
+            Stmt::Let { name, ty, value, global, .. } => {
+                write!(f, "<{} \"{}\"", if *global { "GlobalLet" } else { "Let" }, name)?;
+                if let Some(ty) = ty {
+                    write!(f, ": {:?}", ty)?;
+                }
+                if let Some(value) = value {
+                    write!(f, " {}", value)?;
+                }
+                write!(f, ">")
+            }
+            Stmt::Array { name, ty, len, memory, global, .. } => {
+                write!(f, "<{} \"{}\": [{:?}; {}]", if *global { "GlobalArray" } else { "Array" }, name, ty, len)?;
+                if let Some(memory) = memory {
+                    write!(f, " in {}", memory)?;
+                }
+                write!(f, ">")
+            }
+            Stmt::Store { name, index, value, .. } => write!(f, "<Store \"{}\" {} {}>", name, index, value),
+            Stmt::SetField { base, field, value, .. } => write!(f, "<SetField {} \"{}\" {}>", base, field, value),
+            Stmt::Block { body, .. } => write!(f, "<Block [{}]>", join(body)),
+            Stmt::Match { value, arms, .. } => write!(f, "<Match {} [{}]>", value, join(arms)),
+            Stmt::Return { value: Some(value), .. } => write!(f, "<Return {}>", value),
+            Stmt::Return { value: None, .. } => write!(f, "<Return>"),
+            Stmt::Expr(expr) => write!(f, "{}", expr),
+        }
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Value(expr) => write!(f, "{}", expr),
+            Pattern::Any => write!(f, "_"),
+        }
+    }
+}
+
+impl Display for Arm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let patterns = self.patterns.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" | ");
+        write!(f, "<Arm {} [{}]>", patterns, join(&self.body))
+    }
+}
+
+impl Display for Item {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Item::Fn(func) => {
+                let params = func.params.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", ");
+                match &func.doc {
+                    Some(doc) => write!(f, "<Fn \"{}\"({}) \"{}\" [{}]>", func.name, params, doc.replace('\n', "\\n"), join(&func.body)),
+                    None => write!(f, "<Fn \"{}\"({}) [{}]>", func.name, params, join(&func.body)),
+                }
+            }
+            Item::Test(test) => write!(f, "<Test \"{}\" [{}]>", test.name, join(&test.body)),
+            Item::Const(constant) => write!(f, "<Const \"{}\" {}>", constant.name, constant.value),
+            Item::Enum(enumeration) => {
+                let variants = enumeration.variants.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", ");
+                write!(f, "<Enum \"{}\" [{}]>", enumeration.name, variants)
+            }
+            Item::Struct(structure) => {
+                let fields = structure.fields.iter().map(|x| x.name.as_str()).collect::<Vec<_>>().join(", ");
+                write!(f, "<Struct \"{}\" [{}]>", structure.name, fields)
+            }
+            Item::Stmt(stmt) => write!(f, "{}", stmt),
+        }
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for item in &self.items {
+            writeln!(f, "{}", item)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ExitReason;
+    use crate::harness::{ error, parse };
+
+    fn tree(source: &str) -> String {
+        return parse(source).unwrap_or_else(|e| panic!("{:?}", e)).to_string();
+    }
+
+    #[test]
+    fn items_and_statements() {
+        let source = "## Doubles.\nfn double(x) { return x * 2; }\nconst N = 3;\nenum S { A, B }\nstruct P { x, y }\nvar g: Num;\nlet r: [Num; 4];\nlet p = P { x: 1, y };\np.x = S::B;\nr[1] += g;\nmatch g { 0 | 1 => { print(\"small\"); } _ => {} }\ntest \"t\" { assert!(g); }";
+        assert_eq!(tree(source), "<Fn \"double\"(x) \"Doubles.\" [<Return <Binary <Var \"x\"> \"*\" <Literal \"2\" (Num)>>>]>
+<Const \"N\" <Literal \"3\" (Num)>>
+<Enum \"S\" [A, B]>
+<Struct \"P\" [x, y]>
+<GlobalLet \"g\": Num>
+<Array \"r\": [Num; <Literal \"4\" (Num)>]>
+<Let \"p\" <Struct P {x: <Literal \"1\" (Num)>, y: <Var \"y\">}>>
+<SetField <Var \"p\"> \"x\" <Variant S::B>>
+<Store \"r\" <Literal \"1\" (Num)> <Binary <Index \"r\" <Literal \"1\" (Num)>> \"+\" <Var \"g\">>>
+<Match <Var \"g\"> [<Arm <Literal \"0\" (Num)> | <Literal \"1\" (Num)> [<Call print([<Literal \"small\" (Str)>])>]>, <Arm _ []>]>
+<Test \"t\" [<Macro assert!([<Var \"g\">])>]>
+");
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(tree("a = 1 - 2 - 3;"), "<Assign \"a\" <Binary <Binary <Literal \"1\" (Num)> \"-\" <Literal \"2\" (Num)>> \"-\" <Literal \"3\" (Num)>>>\n");
+        assert_eq!(tree("a = 2 ** 3 ** 2;"), "<Assign \"a\" <Binary <Literal \"2\" (Num)> \"**\" <Binary <Literal \"3\" (Num)> \"**\" <Literal \"2\" (Num)>>>>\n");
+        assert_eq!(tree("a = b || c && d == 1 + 2 * 3;"), "<Assign \"a\" <Binary <Var \"b\"> \"||\" <Binary <Var \"c\"> \"&&\" <Binary <Var \"d\"> \"==\" <Binary <Literal \"1\" (Num)> \"+\" <Binary <Literal \"2\" (Num)> \"*\" <Literal \"3\" (Num)>>>>>>>\n");
+        assert_eq!(tree("a = (1 + 2) * -b;"), "<Assign \"a\" <Binary <Binary <Literal \"1\" (Num)> \"+\" <Literal \"2\" (Num)>> \"*\" <Unary \"-\" <Var \"b\">>>>\n");
+        // negative literals stay literals
+        assert_eq!(tree("a = - -3;"), "<Assign \"a\" <Literal \"3\" (Num)>>\n");
+    }
+
+    #[test]
+    fn spans() {
+        let module = parse("let a = 1;\nlet bc = a +\n  22;").unwrap();
+        let spans = module.statements().map(|x| {
+            let span = x.span();
+            (span.line, span.col, span.end_line, span.end_col)
+        }).collect::<Vec<_>>();
+        assert_eq!(spans, vec![(1, 1, 1, 9), (2, 1, 3, 4)]);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let cases = [
+            ("let a = (1 + ;", "Expected ')' to close '('. (line 1, col 9)", ExitReason::CompileUnexpectedToken),
+            ("let a = (1 + 2) 3;", "Unexpected token '3' after a complete value, is a ';' missing? (line 1, col 17)", ExitReason::CompileUnexpectedToken),
+            ("let a = ();", "Expected a value between '(' and ')'. (line 1, col 9)", ExitReason::CompileExpectedValue),
+            ("print(1)\nprint(2);", "Unexpected token 'print' after a complete value, is a ';' missing? (line 2, col 1)", ExitReason::CompileUnexpectedToken),
+            ("let a = 1 +;", "Expected a value after operator '+'. (line 1, col 11)", ExitReason::CompileExpectedValue),
+            ("fn f() { fn g() {} }", "Functions, tests, constants, enums and structs can only be defined at the top level. (line 1, col 10)", ExitReason::CompileBadItemDef),
+            ("let a: Float = 1;", "Expected a type after \"a:\", one of Num, Str, Char, Content, Building, Unit, Team or Null. (line 1, col 6)", ExitReason::CompileUnknownType),
+        ];
+        for (source, message, reason) in cases {
+            assert_eq!(error(source), (message.to_string(), reason), "{}", source);
+        }
+    }
+}
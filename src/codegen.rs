@@ -0,0 +1,1391 @@
+use std::collections::HashMap;
+use crate::ast::{ Arm, Expr, Function, Module, Pattern, Span, Stmt, VarType };
+use crate::emulator::{ self, Value };
+use crate::ir::Ir;
+use crate::*;
+
+/// One line of generated mlog. Jumps refer to labels by name until the
+/// program is emitted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ins {
+    Label(String),
+    Code { op: String, args: Vec<String>, span: Span },
+}
+
+/// mlog instructions callable like functions: name, amount of arguments
+/// (`None` for any amount) and which argument the result is written to.
+const BUILTINS: [(&str, Option<usize>, Option<usize>); 19] = [
+    ("print", Some(1), None),
+    ("printchar", Some(1), None),
+    ("format", Some(1), None),
+    ("printflush", Some(1), None),
+    ("drawflush", Some(1), None),
+    ("wait", Some(1), None),
+    ("stop", Some(0), None),
+    ("end", Some(0), None),
+    ("noop", Some(0), None),
+    ("write", Some(3), None),
+    ("read", Some(2), Some(0)),
+    ("getlink", Some(1), Some(0)),
+    ("sensor", Some(2), Some(0)),
+    ("packcolor", Some(4), Some(0)),
+    ("lookup", Some(2), Some(1)),
+    ("draw", None, None),
+    ("control", None, None),
+    ("ubind", Some(1), None),
+    ("ucontrol", None, None),
+];
+
+/// Math functions lowered to `op`, with the amount of operands.
+pub const MATH: [(&str, usize); 22] = [
+    ("abs", 1),
+    ("sign", 1),
+    ("floor", 1),
+    ("ceil", 1),
+    ("round", 1),
+    ("sqrt", 1),
+    ("log", 1),
+    ("log10", 1),
+    ("sin", 1),
+    ("cos", 1),
+    ("tan", 1),
+    ("asin", 1),
+    ("acos", 1),
+    ("atan", 1),
+    ("rand", 1),
+    ("max", 2),
+    ("min", 2),
+    ("angle", 2),
+    ("angleDiff", 2),
+    ("len", 2),
+    ("noise", 2),
+    ("logn", 2),
+];
+
+/// mlog `op` name for a binary operator of the source language.
+pub fn binop_name(op: &str) -> Option<&'static str> {
+    Some(match op {
+        "||" => "or",
+        "&&" => "land",
+        "|" => "or",
+        "^" => "xor",
+        "&" => "and",
+        "==" => "equal",
+        "!=" => "notEqual",
+        "===" => "strictEqual",
+        "<" => "lessThan",
+        "<=" => "lessThanEq",
+        ">" => "greaterThan",
+        ">=" => "greaterThanEq",
+        "<<" => "shl",
+        ">>" => "shr",
+        ">>>" => "ushr",
+        "+" => "add",
+        "-" => "sub",
+        "*" => "mul",
+        "/" => "div",
+        "//" => "idiv",
+        "%" => "mod",
+        "%%" => "emod",
+        "**" => "pow",
+        _ => {
+            return None;
+        }
+    })
+}
+
+/// Reads the `--target` option, the Mindustry version the mlog is for.
+/// Version 8 added the `format` instruction.
+pub fn target_version(opts: &HashMap<String, String>) -> CgResult<u8> {
+    return match opts.get("target").map(|x| x.as_str()) {
+        None | Some("7") => Ok(7),
+        Some("8") => Ok(8),
+        Some(v) => Err((format!("Option \"--target\" expected 7 or 8, got \"{}\".", v), ExitReason::OptionBadValue)),
+    };
+}
+
+/// Part of a `print!` message: text known while compiling, or the operand
+/// of a value printed at run time.
+enum Piece {
+    Text(String),
+    Value(String),
+}
+
+/// `text` as an mlog string literal. mlog has no escapes besides `\n` for
+/// a newline, so a `"` or a `\` before an `n` cannot be written.
+fn mlog_string(text: &str) -> Option<String> {
+    if text.contains('"') || text.contains("\\n") {
+        return None;
+    }
+    return Some(format!("\"{}\"", text.replace('\n', "\\n")));
+}
+
+/// Whether `text` holds a placeholder the `format` instruction replaces.
+fn has_placeholder(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    return bytes.windows(3).any(|x| x[0] == b'{' && x[1].is_ascii_digit() && x[2] == b'}');
+}
+
+/// State of a function currently being inlined.
+struct Frame {
+    name: String,
+    renames: HashMap<String, String>,
+    ret: String,
+    end: String,
+}
+
+type CgResult<T> = Result<T, (String, ExitReason)>;
+
+macro_rules! pos {
+    ($span:expr) => {
+        format!(" (line {}, col {})", $span.line, $span.col)
+    };
+}
+
+/// An array declared with `let name: [Type; len]`.
+#[derive(Clone)]
+struct Array {
+    len: usize,
+    /// Memory cell or bank holding the elements, `None` when every element
+    /// is a variable of its own.
+    memory: Option<Expr>,
+}
+
+/// Variable holding an element of a register array.
+fn element(array: &str, idx: usize) -> String {
+    return format!("{}[{}]", array, idx);
+}
+
+/// Variable holding a field of the struct in `var`. The `.` keeps it apart
+/// from every name source code can spell.
+fn field_of(var: &str, name: &str) -> String {
+    return format!("{}.{}", var, name);
+}
+
+/// Collects the arrays declared in `body`, including nested blocks.
+fn collect_arrays<'b>(body: impl IntoIterator<Item = &'b Stmt>, arrays: &mut HashMap<String, Array>) -> CgResult<()> {
+    for stmt in body {
+        match stmt {
+            Stmt::Array { name, len, memory, span, .. } => {
+                let len = match len {
+                    Expr::Literal { vt: VarType::Num, value, .. } => match emulator::parse_literal(value) {
+                        Some(Value::Num(n)) if n.fract() == 0.0 && n >= 1.0 => n as usize,
+                        _ => 0,
+                    },
+                    _ => 0,
+                };
+                if len == 0 {
+                    return Err((format!("Length of array \"{}\" should be a positive integer.{}", name, pos!(span)), ExitReason::CompileBadArray));
+                }
+                let array = Array { len, memory: memory.clone() };
+                if let Some(old) = arrays.insert(name.clone(), array) {
+                    if old.len != len || old.memory != *memory {
+                        return Err((format!("Array \"{}\" is declared twice differently.{}", name, pos!(span)), ExitReason::CompileBadArray));
+                    }
+                }
+            }
+            Stmt::Block { body, .. } => collect_arrays(body, arrays)?,
+            Stmt::Match { arms, .. } => {
+                for arm in arms {
+                    collect_arrays(&arm.body, arrays)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    return Ok(());
+}
+
+pub struct Codegen<'a> {
+    funcs: HashMap<String, &'a Function>,
+    arrays: HashMap<String, Array>,
+    /// Fields of every struct.
+    structs: HashMap<String, Vec<String>>,
+    /// Struct held by every variable holding one, which is only a group of
+    /// variables, one per field.
+    struct_vars: HashMap<String, String>,
+    /// Mindustry version the mlog is for.
+    target: u8,
+    pub out: Vec<Ins>,
+    tmp_count: usize,
+    label_count: usize,
+    inline_count: usize,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Codegen<'a> {
+    /// Creates a generator knowing every function, struct and array defined
+    /// in `root`.
+    pub fn new(root: &'a Module, opts: &HashMap<String, String>) -> CgResult<Codegen<'a>> {
+        let mut funcs = HashMap::<String, &'a Function>::new();
+        for func in root.functions() {
+            if funcs.insert(func.name.clone(), func).is_some() {
+                return Err((format!("Function \"{}\" is defined twice.{}", func.name, pos!(func.span)), ExitReason::CompileDuplicateItem));
+            }
+        }
+        let mut structs = HashMap::<String, Vec<String>>::new();
+        for structure in root.structs() {
+            let fields = structure.fields.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+            if let Some((_, dup)) = structure.fields.iter().enumerate().find(|(i, x)| fields[..*i].contains(&x.name)) {
+                return Err((format!("Field \"{}\" is defined twice.{}", dup.name, pos!(dup.span)), ExitReason::CompileDuplicateItem));
+            }
+            if structs.insert(structure.name.clone(), fields).is_some() {
+                return Err((format!("Struct \"{}\" is defined twice.{}", structure.name, pos!(structure.span)), ExitReason::CompileDuplicateItem));
+            }
+        }
+        let mut arrays = HashMap::<String, Array>::new();
+        collect_arrays(root.statements(), &mut arrays)?;
+        for func in root.functions() {
+            collect_arrays(&func.body, &mut arrays)?;
+        }
+        for test in root.tests() {
+            collect_arrays(&test.body, &mut arrays)?;
+        }
+        return Ok(Codegen {
+            funcs,
+            arrays,
+            structs,
+            struct_vars: HashMap::<String, String>::new(),
+            target: target_version(opts)?,
+            out: Vec::<Ins>::new(),
+            tmp_count: 0,
+            label_count: 0,
+            inline_count: 0,
+            frames: Vec::<Frame>::new(),
+        });
+    }
+
+    fn tmp(&mut self) -> String {
+        self.tmp_count += 1;
+        return format!("__t{}", self.tmp_count - 1);
+    }
+
+    pub fn label(&mut self) -> String {
+        self.label_count += 1;
+        return format!("__L{}", self.label_count - 1);
+    }
+
+    pub fn emit(&mut self, at: Span, op: &str, args: &[&str]) {
+        self.out.push(Ins::Code {
+            op: op.to_string(),
+            args: args.iter().map(|x| x.to_string()).collect(),
+            span: at,
+        });
+    }
+
+    pub fn place(&mut self, label: &str) {
+        self.out.push(Ins::Label(label.to_string()));
+    }
+
+    /// Name of a variable as seen from the function being inlined.
+    fn var(&self, name: &str) -> String {
+        match self.frames.last().and_then(|x| x.renames.get(name)) {
+            Some(renamed) => renamed.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    fn array(&self, name: &str, span: Span) -> CgResult<Array> {
+        return match self.arrays.get(&self.var(name)) {
+            Some(array) => Ok(array.clone()),
+            None => Err((format!("\"{}\" is not an array.{}", name, pos!(span)), ExitReason::CompileBadArray)),
+        };
+    }
+
+    /// The index of an element if it is known at compile time, which must
+    /// then be within the array.
+    fn const_index(&self, name: &str, array: &Array, index: &Expr) -> CgResult<Option<usize>> {
+        let value = match index {
+            Expr::Literal { vt: VarType::Num, value, .. } => value,
+            _ => {
+                return Ok(None);
+            }
+        };
+        return match emulator::parse_literal(value) {
+            Some(Value::Num(n)) if n.fract() == 0.0 && n >= 0.0 && n < array.len as f64 => Ok(Some(n as usize)),
+            _ => Err((
+                format!("Index {} is out of bounds for \"{}\" of length {}.{}", value, name, array.len, pos!(index.span())),
+                ExitReason::CompileIndexOutOfBounds,
+            )),
+        };
+    }
+
+    /// Copies between `value` and the element of the register array `name`
+    /// at the dynamic `index`, into the element when `store` is set.
+    /// `op add @counter` jumps to one of `len` jumps leading to the element's
+    /// `set`, so every entry of the table is a single instruction whatever
+    /// the optimizer does to the code they lead to. An index that is not an
+    /// integer within the array stores nothing and reads `null`, checked the
+    /// way `match_stmt` checks values for its jump table.
+    fn element_table(&mut self, name: &str, index: &str, len: usize, value: &str, store: bool, span: Span) {
+        let end = self.label();
+        let outside = if store { end.clone() } else { self.label() };
+        let labels = (0..len).map(|_| self.label()).collect::<Vec<_>>();
+        let integer = self.tmp();
+        self.emit(span, "op", &["floor", &integer, index]);
+        self.emit(span, "op", &["strictEqual", &integer, &integer, index]);
+        self.emit(span, "jump", &[&outside, "equal", &integer, "false"]);
+        self.emit(span, "jump", &[&outside, "lessThan", index, "0"]);
+        self.emit(span, "jump", &[&outside, "greaterThan", index, &(len - 1).to_string()]);
+        self.emit(span, "op", &["add", "@counter", "@counter", index]);
+        for label in &labels {
+            self.emit(span, "jump", &[label, "always"]);
+        }
+        for (idx, label) in labels.iter().enumerate() {
+            self.place(label);
+            let elem = element(name, idx);
+            match store {
+                true => self.emit(span, "set", &[&elem, value]),
+                false => self.emit(span, "set", &[value, &elem]),
+            }
+            self.emit(span, "jump", &[&end, "always"]);
+        }
+        if !store {
+            self.place(&outside);
+            self.emit(span, "set", &[value, "null"]);
+        }
+        self.place(&end);
+    }
+
+    /// Lowers a read of `name[index]` into `dest`.
+    fn load(&mut self, name: &str, index: &Expr, span: Span, dest: &str) -> CgResult<()> {
+        let array = self.array(name, span)?;
+        let constant = self.const_index(name, &array, index)?;
+        let name = self.var(name);
+        match (&array.memory, constant) {
+            (Some(memory), _) => {
+                let idx = self.value(index)?;
+                let memory = self.value(memory)?;
+                self.emit(span, "read", &[dest, &memory, &idx]);
+            }
+            (None, Some(idx)) => self.emit(span, "set", &[dest, &element(&name, idx)]),
+            (None, None) => {
+                let idx = self.value(index)?;
+                self.element_table(&name, &idx, array.len, dest, false, span);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Lowers `name[index] = value`.
+    fn store(&mut self, name: &str, index: &Expr, value: &Expr, span: Span) -> CgResult<()> {
+        let array = self.array(name, span)?;
+        let constant = self.const_index(name, &array, index)?;
+        let name = self.var(name);
+        match (&array.memory, constant) {
+            (Some(memory), _) => {
+                let idx = self.value(index)?;
+                let v = self.value(value)?;
+                let memory = self.value(memory)?;
+                self.emit(span, "write", &[&v, &memory, &idx]);
+            }
+            (None, Some(idx)) => {
+                self.value_into(value, &element(&name, idx))?;
+            }
+            (None, None) => {
+                let idx = self.value(index)?;
+                let v = self.value(value)?;
+                self.element_table(&name, &idx, array.len, &v, true, span);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Variable holding `expr`, a variable or a field. The fields of a
+    /// struct in `p` are the variables `p.x`, `p.y`, nested ones `p.a.x`.
+    fn field_var(&self, expr: &Expr) -> CgResult<String> {
+        match expr {
+            Expr::Var { name, .. } => {
+                return Ok(self.var(name));
+            }
+            Expr::Field { base, field, span } => {
+                let base = self.field_var(base)?;
+                let fields = match self.struct_vars.get(&base).and_then(|x| self.structs.get(x).map(|y| (x, y))) {
+                    Some(found) => found,
+                    None => {
+                        return Err((format!("Field \"{}\" of a value that is not a struct.{}", field, pos!(span)), ExitReason::CompileBadStruct));
+                    }
+                };
+                if !fields.1.contains(field) {
+                    return Err((format!("Struct \"{}\" has no field \"{}\".{}", fields.0, field, pos!(span)), ExitReason::CompileBadStruct));
+                }
+                return Ok(field_of(&base, field));
+            }
+            _ => {
+                return Err((format!("Expected a variable before '.'.{}", pos!(expr.span())), ExitReason::CompileBadStruct));
+            }
+        }
+    }
+
+    /// Variable `expr` reads a struct from, if it does.
+    fn struct_source(&self, expr: &Expr) -> CgResult<Option<String>> {
+        if !matches!(expr, Expr::Var { .. } | Expr::Field { .. }) {
+            return Ok(None);
+        }
+        let var = self.field_var(expr)?;
+        return Ok(self.struct_vars.contains_key(&var).then_some(var));
+    }
+
+    /// Copies `src` into `dest`, field by field if it holds a struct.
+    fn copy_var(&mut self, src: &str, dest: &str, span: Span) {
+        match self.struct_vars.get(src).cloned() {
+            Some(name) => {
+                for field in self.structs.get(&name).cloned().unwrap_or_default() {
+                    self.copy_var(&field_of(src, &field), &field_of(dest, &field), span);
+                }
+                self.struct_vars.insert(dest.to_string(), name);
+            }
+            None => {
+                self.struct_vars.remove(dest);
+                if src != dest {
+                    self.emit(span, "set", &[dest, src]);
+                }
+            }
+        }
+    }
+
+    /// Lowers `Name { field: value, ... }` into the fields of `dest`.
+    fn build_struct(&mut self, name: &str, fields: &[(String, Expr)], span: Span, dest: &str) -> CgResult<()> {
+        let declared = match self.structs.get(name) {
+            Some(declared) => declared.clone(),
+            None => {
+                return Err((format!("Unknown struct \"{}\".{}", name, pos!(span)), ExitReason::CompileBadStruct));
+            }
+        };
+        for (n, (field, value)) in fields.iter().enumerate() {
+            if !declared.contains(field) {
+                return Err((format!("Struct \"{}\" has no field \"{}\".{}", name, field, pos!(value.span())), ExitReason::CompileBadStruct));
+            }
+            if fields[..n].iter().any(|x| x.0 == *field) {
+                return Err((format!("Field \"{}\" is given twice.{}", field, pos!(value.span())), ExitReason::CompileBadStruct));
+            }
+        }
+        if let Some(missing) = declared.iter().find(|x| !fields.iter().any(|y| y.0 == **x)) {
+            return Err((format!("Missing field \"{}\" of struct \"{}\".{}", missing, name, pos!(span)), ExitReason::CompileBadStruct));
+        }
+        // every value is read before a field is written, so fields can be
+        // built from the struct being replaced
+        let mut values = Vec::<(String, String)>::new();
+        let mut nested = Vec::<(String, &Expr)>::new();
+        for (field, value) in fields {
+            let target = field_of(dest, field);
+            if matches!(value, Expr::Struct { .. }) || self.struct_source(value)?.is_some() {
+                nested.push((target, value));
+                continue;
+            }
+            let mut operand = self.value(value)?;
+            if operand.starts_with(&format!("{}.", dest)) {
+                let copy = self.tmp();
+                self.emit(value.span(), "set", &[&copy, &operand]);
+                operand = copy;
+            }
+            values.push((target, operand));
+        }
+        for (target, operand) in values {
+            self.struct_vars.remove(&target);
+            self.emit(span, "set", &[&target, &operand]);
+        }
+        for (target, value) in nested {
+            self.value_into(value, &target)?;
+        }
+        self.struct_vars.insert(dest.to_string(), name.to_string());
+        return Ok(());
+    }
+
+    /// Lowers `expr` and returns the mlog operand holding its value.
+    pub fn value(&mut self, expr: &Expr) -> CgResult<String> {
+        match expr {
+            Expr::Var { name, span } => {
+                if self.arrays.contains_key(&self.var(name)) {
+                    return Err((format!("Array \"{}\" can only be used with an index.{}", name, pos!(span)), ExitReason::CompileBadArray));
+                }
+                if !self.struct_vars.contains_key(&self.var(name)) {
+                    return Ok(self.var(name));
+                }
+            }
+            Expr::Index { name, index, span } => {
+                let array = self.array(name, *span)?;
+                if let (None, Some(idx)) = (&array.memory, self.const_index(name, &array, index)?) {
+                    return Ok(element(&self.var(name), idx));
+                }
+            }
+            Expr::Field { .. } => {
+                let var = self.field_var(expr)?;
+                if !self.struct_vars.contains_key(&var) {
+                    return Ok(var);
+                }
+            }
+            Expr::Literal { vt: VarType::Num, value, .. } => {
+                return Ok(value.clone());
+            }
+            Expr::Literal { value, span, .. } => {
+                return match mlog_string(value) {
+                    Some(s) => Ok(s),
+                    None => Err((
+                        format!("mlog strings cannot hold '\"' or '\\' before 'n', they can only be printed.{}", pos!(span)),
+                        ExitReason::CompileBadEscape,
+                    )),
+                };
+            }
+            _ => {}
+        }
+        let dest = self.tmp();
+        let result = self.value_into(expr, &dest)?;
+        if self.struct_vars.contains_key(&result) {
+            return Err((
+                format!("A struct can only be assigned, passed and returned whole, or used through its fields.{}", pos!(expr.span())),
+                ExitReason::CompileBadStruct,
+            ));
+        }
+        return Ok(result);
+    }
+
+    /// Lowers `expr`, storing its value in `dest` where possible. Returns
+    /// the operand holding the value, which is `dest` unless nothing had to
+    /// be computed.
+    pub fn value_into(&mut self, expr: &Expr, dest: &str) -> CgResult<String> {
+        let span = expr.span();
+        if let Some(src) = self.struct_source(expr)? {
+            self.copy_var(&src, dest, span);
+            return Ok(dest.to_string());
+        }
+        match expr {
+            Expr::Var { .. } | Expr::Literal { .. } | Expr::Field { .. } => {
+                let v = self.value(expr)?;
+                self.emit(span, "set", &[dest, &v]);
+            }
+            Expr::Struct { name, fields, .. } => {
+                self.build_struct(name, fields, span, dest)?;
+                return Ok(dest.to_string());
+            }
+            Expr::Binary { op, left, right, .. } => {
+                let a = self.value(left)?;
+                let b = self.value(right)?;
+                if op == "!==" {
+                    self.emit(span, "op", &["strictEqual", dest, &a, &b]);
+                    self.emit(span, "op", &["equal", dest, dest, "false"]);
+                    return Ok(dest.to_string());
+                }
+                let name = match binop_name(op) {
+                    Some(n) => n,
+                    None => {
+                        return Err((format!("Unknown operator '{}'.{}", op, pos!(span)), ExitReason::CompileUnknownOperator));
+                    }
+                };
+                self.emit(span, "op", &[name, dest, &a, &b]);
+            }
+            Expr::Unary { op, operand, .. } => {
+                let a = self.value(operand)?;
+                match op.as_str() {
+                    "-" => self.emit(span, "op", &["sub", dest, "0", &a]),
+                    "!" => self.emit(span, "op", &["equal", dest, &a, "false"]),
+                    _ => self.emit(span, "op", &["not", dest, &a]),
+                }
+            }
+            Expr::Call { name, args, .. } => {
+                return self.call(name, args, span, Some(dest));
+            }
+            Expr::Macro { name, args, .. } => {
+                self.macro_call(name, args, span)?;
+            }
+            Expr::Index { name, index, .. } => {
+                self.load(name, index, span, dest)?;
+            }
+            Expr::Variant { enum_name, variant, .. } => {
+                return Err((format!("Unknown variant \"{}::{}\".{}", enum_name, variant, pos!(span)), ExitReason::CompileUnknownVariant));
+            }
+        }
+        // a variable that held a struct holds something else now
+        self.struct_vars.remove(dest);
+        return Ok(dest.to_string());
+    }
+
+    /// Lowers a call of a user function (inlined), a builtin instruction or
+    /// a math function. Returns the operand holding the result.
+    fn call(&mut self, name: &str, args: &[Expr], span: Span, dest: Option<&str>) -> CgResult<String> {
+        if let Some(func) = self.funcs.get(name).copied() {
+            if self.frames.iter().any(|x| x.name == name) {
+                return Err((format!("Function \"{}\" calls itself, which cannot be inlined.{}", name, pos!(span)), ExitReason::CompileRecursiveFunction));
+            }
+            if func.params.len() != args.len() {
+                return Err((
+                    format!("Function \"{}\" takes {} arguments, {} were provided.{}", name, func.params.len(), args.len(), pos!(span)),
+                    ExitReason::CompileWrongArgCount,
+                ));
+            }
+            let id = self.inline_count;
+            self.inline_count += 1;
+            let mut renames = HashMap::<String, String>::new();
+            for (param, arg) in func.params.iter().zip(args) {
+                let renamed = format!("__{}{}_{}", name, id, param.name);
+                self.value_into(arg, &renamed)?;
+                renames.insert(param.name.clone(), renamed);
+            }
+            let ret = match dest {
+                Some(d) => d.to_string(),
+                None => format!("__{}{}_ret", name, id),
+            };
+            let end = self.label();
+            self.frames.push(Frame { name: name.to_string(), renames, ret: ret.clone(), end: end.clone() });
+            self.block(&func.body)?;
+            self.frames.pop();
+            self.place(&end);
+            return Ok(ret);
+        }
+
+        if let ("print", [Expr::Literal { vt: VarType::Str, value, .. }]) = (name, args) {
+            self.print_text(value, span);
+            return Ok("null".to_string());
+        }
+
+        if let Some((_, count, result)) = BUILTINS.iter().find(|x| x.0 == name) {
+            if count.is_some_and(|x| x != args.len()) {
+                return Err((
+                    format!("Instruction \"{}\" takes {} arguments, {} were provided.{}", name, count.unwrap_or_default(), args.len(), pos!(span)),
+                    ExitReason::CompileWrongArgCount,
+                ));
+            }
+            let mut operands = Vec::<String>::new();
+            for arg in args {
+                operands.push(self.value(arg)?);
+            }
+            let mut ret = "null".to_string();
+            if let Some(result) = result {
+                ret = match dest {
+                    Some(d) => d.to_string(),
+                    None => self.tmp(),
+                };
+                operands.insert(*result, ret.clone());
+            }
+            self.emit(span, name, &operands.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+            return Ok(ret);
+        }
+
+        if let Some((_, count)) = MATH.iter().find(|x| x.0 == name) {
+            if *count != args.len() {
+                return Err((
+                    format!("Function \"{}\" takes {} arguments, {} were provided.{}", name, count, args.len(), pos!(span)),
+                    ExitReason::CompileWrongArgCount,
+                ));
+            }
+            let mut operands = vec![name.to_string(), match dest {
+                Some(d) => d.to_string(),
+                None => self.tmp(),
+            }];
+            for arg in args {
+                operands.push(self.value(arg)?);
+            }
+            self.emit(span, "op", &operands.iter().map(|x| x.as_str()).collect::<Vec<_>>());
+            return Ok(operands[1].clone());
+        }
+
+        return Err((format!("Unknown function \"{}\".{}", name, pos!(span)), ExitReason::CompileUnknownFunction));
+    }
+
+    /// Prints text known while compiling. What an mlog string cannot hold
+    /// is printed with `printchar`.
+    fn print_text(&mut self, text: &str, span: Span) {
+        let mut part = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            let code = match (c, chars.peek()) {
+                ('"', _) => 34,
+                ('\\', Some('n')) => 92,
+                _ => {
+                    part.push(c);
+                    continue;
+                }
+            };
+            if !part.is_empty() {
+                self.emit(span, "print", &[&mlog_string(&part).unwrap_or_default()]);
+                part.clear();
+            }
+            self.emit(span, "printchar", &[&code.to_string()]);
+        }
+        if !part.is_empty() {
+            self.emit(span, "print", &[&mlog_string(&part).unwrap_or_default()]);
+        }
+    }
+
+    /// Lowers hand-written mlog, `mlog!("...")` or a backtick literal. The
+    /// parser left a `{}` for every interpolated value, which is replaced
+    /// with the variable or literal holding it. Labels like `loop:` are
+    /// renamed so the code can be inlined more than once, and jumps may
+    /// only go to labels of the same code.
+    fn mlog_macro(&mut self, args: &[Expr], span: Span) -> CgResult<()> {
+        let template = match args.first() {
+            Some(Expr::Literal { vt: VarType::Str, value, .. }) => value,
+            _ => {
+                return Err((format!("Macro \"mlog!\" takes a string or backtick literal.{}", pos!(span)), ExitReason::CompileBadFormat));
+            }
+        };
+        let mut values = Vec::<String>::new();
+        for arg in &args[1..] {
+            values.push(self.value(arg)?);
+        }
+        let mut values = values.into_iter();
+        let mut code = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('}')) => {
+                    chars.next();
+                    code += &values.next().unwrap_or_default();
+                }
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    code.push(c);
+                }
+                _ => code.push(c),
+            }
+        }
+
+        let statements = code.lines().flat_map(emulator::split_mlog_line).collect::<Vec<_>>();
+        let mut labels = HashMap::<String, String>::new();
+        for statement in &statements {
+            if let [label] = statement.as_slice() {
+                if let Some(name) = label.strip_suffix(':') {
+                    let new = self.label();
+                    if labels.insert(name.to_string(), new).is_some() {
+                        return Err((format!("Label \"{}\" is defined twice in mlog code.{}", name, pos!(span)), ExitReason::CompileDuplicateItem));
+                    }
+                }
+            }
+        }
+        for statement in &statements {
+            if let Some(label) = statement[0].strip_suffix(':').and_then(|x| labels.get(x)) {
+                let label = label.clone();
+                self.place(&label);
+                continue;
+            }
+            let mut args = statement[1..].iter().map(|x| x.as_str()).collect::<Vec<_>>();
+            if statement[0] == "jump" {
+                match args.first().and_then(|x| labels.get(*x)) {
+                    Some(label) => args[0] = label,
+                    None => {
+                        return Err((
+                            format!("Jump in mlog code to \"{}\", which is not a label of the same code.{}", args.first().unwrap_or(&""), pos!(span)),
+                            ExitReason::CompileBadFormat,
+                        ));
+                    }
+                }
+            }
+            self.emit(span, &statement[0], &args);
+        }
+        return Ok(());
+    }
+
+    /// Whether the text buffer is empty whichever way control got here: a
+    /// `printflush` came last, with no print and no label in between. The
+    /// start of the program does not count, as `end` keeps the buffer.
+    fn buffer_empty(&self) -> bool {
+        for ins in self.out.iter().rev() {
+            match ins {
+                Ins::Label(_) => {
+                    return false;
+                }
+                Ins::Code { op, .. } if op == "printflush" => {
+                    return true;
+                }
+                Ins::Code { op, args, .. } if op == "print" || op == "format" || args.iter().any(|x| x == "@counter") => {
+                    return false;
+                }
+                Ins::Code { .. } => {}
+            }
+        }
+        return false;
+    }
+
+    /// Lowers `print!("HP: {} / {}", hp, max)`. Text and literal values are
+    /// joined while compiling, the other values printed one by one, or on
+    /// version 8 right after a `printflush` filled into `{0}`, `{1}`...
+    /// placeholders with `format`.
+    /// `{{` and `}}` print a brace.
+    fn print_macro(&mut self, args: &[Expr], span: Span) -> CgResult<()> {
+        let template = match args.first() {
+            Some(Expr::Literal { vt: VarType::Str, value, .. }) => value,
+            _ => {
+                return Err((format!("Macro \"print!\" takes a string literal first.{}", pos!(span)), ExitReason::CompileBadFormat));
+            }
+        };
+        let mut texts = vec![String::new()];
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('{', Some('{')) | ('}', Some('}')) => {
+                    chars.next();
+                    texts.last_mut().unwrap(/* never empty */).push(c);
+                }
+                ('{', Some('}')) => {
+                    chars.next();
+                    texts.push(String::new());
+                }
+                ('{', _) | ('}', _) => {
+                    return Err((
+                        format!("Unmatched '{}' in the message of \"print!\", write '{}{}' to print it.{}", c, c, c, pos!(args[0].span())),
+                        ExitReason::CompileBadFormat,
+                    ));
+                }
+                _ => texts.last_mut().unwrap(/* never empty */).push(c),
+            }
+        }
+        if texts.len() != args.len() {
+            let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+            return Err((
+                format!("The message of \"print!\" has {}, but got {}.{}", count(texts.len() - 1, "placeholder"), count(args.len() - 1, "value"), pos!(span)),
+                ExitReason::CompileBadFormat,
+            ));
+        }
+
+        let mut pieces = vec![Piece::Text(texts[0].clone())];
+        for (value, text) in args[1..].iter().zip(&texts[1..]) {
+            let known = match value {
+                Expr::Literal { vt: VarType::Num, value, .. } => match emulator::parse_literal(value) {
+                    Some(Value::Num(n)) => Some(emulator::fmt_num(n)),
+                    _ => None,
+                },
+                Expr::Literal { value, .. } => Some(value.clone()),
+                _ => None,
+            };
+            match (known, pieces.last_mut()) {
+                (Some(known), Some(Piece::Text(last))) => *last += &known,
+                (Some(known), _) => pieces.push(Piece::Text(known)),
+                (None, _) => pieces.push(Piece::Value(self.value(value)?)),
+            }
+            match pieces.last_mut() {
+                Some(Piece::Text(last)) => *last += text,
+                _ => pieces.push(Piece::Text(text.clone())),
+            }
+        }
+
+        let values = pieces.iter().filter(|x| matches!(x, Piece::Value(_))).count();
+        let text = pieces.iter().filter_map(|x| match x {
+            Piece::Text(t) => Some(t.as_str()),
+            Piece::Value(_) => None,
+        }).collect::<String>();
+        // `format` only knows `{0}` to `{9}` and would also fill placeholders
+        // written in the message itself, or printed before it
+        if self.target >= 8 && values > 0 && values <= 10 && !has_placeholder(&text) && mlog_string(&text).is_some() && self.buffer_empty() {
+            let mut message = String::new();
+            let mut operands = Vec::<&str>::new();
+            for piece in &pieces {
+                match piece {
+                    Piece::Text(t) => message += t,
+                    Piece::Value(v) => {
+                        message += &format!("{{{}}}", operands.len());
+                        operands.push(v);
+                    }
+                }
+            }
+            self.print_text(&message, span);
+            for operand in operands {
+                self.emit(span, "format", &[operand]);
+            }
+            return Ok(());
+        }
+        for piece in &pieces {
+            match piece {
+                Piece::Text(t) => self.print_text(t, span),
+                Piece::Value(v) => self.emit(span, "print", &[v]),
+            }
+        }
+        return Ok(());
+    }
+
+    /// Lowers the built-in macros `print!`, `assert!`, `assert_eq!` and
+    /// `assert_ne!`. A failed assertion prints where it failed and stops
+    /// the processor.
+    fn macro_call(&mut self, name: &str, args: &[Expr], span: Span) -> CgResult<()> {
+        if name == "print" {
+            return self.print_macro(args, span);
+        }
+        if name == "mlog" {
+            return self.mlog_macro(args, span);
+        }
+        let (needed, cond) = match name {
+            "assert" => (1, "notEqual"),
+            "assert_eq" => (2, "equal"),
+            "assert_ne" => (2, "notEqual"),
+            _ => {
+                return Err((format!("Unknown macro \"{}!\".{}", name, pos!(span)), ExitReason::CompileUnknownMacro));
+            }
+        };
+        if args.len() != needed && args.len() != needed + 1 {
+            return Err((
+                format!("Macro \"{}!\" takes {} or {} arguments, {} were provided.{}", name, needed, needed + 1, args.len(), pos!(span)),
+                ExitReason::CompileWrongArgCount,
+            ));
+        }
+        let a = self.value(&args[0])?;
+        let b = if needed == 2 { self.value(&args[1])? } else { "false".to_string() };
+        let ok = self.label();
+        self.emit(span, "jump", &[&ok, cond, &a, &b]);
+        let mut message = format!("assertion failed{}", pos!(span));
+        if let Some(Expr::Literal { value, .. }) = args.get(needed) {
+            message += ": ";
+            message += value;
+        }
+        self.print_text(&message, span);
+        if needed == 2 {
+            self.emit(span, "print", &["\"\\nleft: \""]);
+            self.emit(span, "print", &[&a]);
+            self.emit(span, "print", &["\"\\nright: \""]);
+            self.emit(span, "print", &[&b]);
+        }
+        self.emit(span, "stop", &[]);
+        self.place(&ok);
+        return Ok(());
+    }
+
+    /// Lowers a `match`. When there are at least four patterns, all of them
+    /// integers covering at least half of the range between the smallest
+    /// and the largest, the arm is picked with a jump table: the value is
+    /// added to `@counter`, landing on a `jump` to its arm. Otherwise the
+    /// value is compared with every pattern in turn, strings, content and
+    /// `null` by `strictEqual` as `equal` would take them for numbers.
+    fn match_stmt(&mut self, value: &Expr, arms: &[Arm], span: Span) -> CgResult<()> {
+        let v = self.value(value)?;
+        let end = self.label();
+        let labels = arms.iter().map(|_| self.label()).collect::<Vec<_>>();
+        let mut fallback = end.clone();
+        // patterns in order with the arm they lead to, later duplicates and
+        // everything after a `_` can never match
+        let mut cases = Vec::<(String, usize)>::new();
+        'arms: for (n, arm) in arms.iter().enumerate() {
+            for pattern in &arm.patterns {
+                let operand = match pattern {
+                    Pattern::Value(expr) => self.value(expr)?,
+                    Pattern::Any => {
+                        fallback = labels[n].clone();
+                        break 'arms;
+                    }
+                };
+                if !cases.iter().any(|x| x.0 == operand) {
+                    cases.push((operand, n));
+                }
+            }
+        }
+
+        let ints = cases.iter().map(|(operand, _)| match emulator::parse_literal(operand) {
+            Some(Value::Num(n)) if n.fract() == 0.0 && n.abs() < 1e9 => Some(n as i64),
+            _ => None,
+        }).collect::<Option<Vec<_>>>();
+        match ints {
+            Some(ints) if ints.len() >= 4 && ints.iter().max().unwrap_or(&0) - ints.iter().min().unwrap_or(&0) < 2 * ints.len() as i64 => {
+                let (min, max) = (*ints.iter().min().unwrap_or(&0), *ints.iter().max().unwrap_or(&0));
+                // strict, so strings and content do not pass as 1
+                let integer = self.tmp();
+                self.emit(span, "op", &["floor", &integer, &v]);
+                self.emit(span, "op", &["strictEqual", &integer, &integer, &v]);
+                self.emit(span, "jump", &[&fallback, "equal", &integer, "false"]);
+                self.emit(span, "jump", &[&fallback, "lessThan", &v, &min.to_string()]);
+                self.emit(span, "jump", &[&fallback, "greaterThan", &v, &max.to_string()]);
+                let mut idx = v.clone();
+                if min != 0 {
+                    idx = self.tmp();
+                    self.emit(span, "op", &["sub", &idx, &v, &min.to_string()]);
+                }
+                self.emit(span, "op", &["add", "@counter", "@counter", &idx]);
+                for n in min..=max {
+                    let target = match ints.iter().position(|x| *x == n) {
+                        Some(case) => labels[cases[case].1].clone(),
+                        None => fallback.clone(),
+                    };
+                    self.emit(span, "jump", &[&target, "always"]);
+                }
+            }
+            _ => {
+                for (operand, arm) in &cases {
+                    let cond = match emulator::parse_literal(operand) {
+                        Some(Value::Num(_)) => "equal",
+                        _ => "strictEqual",
+                    };
+                    self.emit(span, "jump", &[&labels[*arm], cond, &v, operand]);
+                }
+                self.emit(span, "jump", &[&fallback, "always"]);
+            }
+        }
+
+        for (arm, label) in arms.iter().zip(&labels) {
+            self.place(label);
+            self.block(&arm.body)?;
+            self.emit(arm.span, "jump", &[&end, "always"]);
+        }
+        self.place(&end);
+        return Ok(());
+    }
+
+    /// Lowers a statement.
+    pub fn statement(&mut self, stmt: &Stmt) -> CgResult<()> {
+        let span = stmt.span();
+        match stmt {
+            Stmt::Assign { target, value, .. } => {
+                let target = self.var(target);
+                if self.arrays.contains_key(&target) {
+                    return Err((format!("Array \"{}\" can only be assigned with an index.{}", target, pos!(span)), ExitReason::CompileBadArray));
+                }
+                self.value_into(value, &target)?;
+            }
+            Stmt::Array { .. } => {}
+            Stmt::SetField { base, field, value, .. } => {
+                let target = self.field_var(&Expr::Field { base: Box::new(base.clone()), field: field.clone(), span })?;
+                self.value_into(value, &target)?;
+            }
+            Stmt::Store { name, index, value, .. } => {
+                self.store(name, index, value, span)?;
+            }
+            Stmt::Let { name, value, .. } => {
+                if let Some(value) = value {
+                    let target = self.var(name);
+                    self.value_into(value, &target)?;
+                }
+            }
+            Stmt::Block { body, .. } => {
+                self.block(body)?;
+            }
+            Stmt::Match { value, arms, .. } => {
+                self.match_stmt(value, arms, span)?;
+            }
+            Stmt::Return { value, .. } => {
+                let frame = self.frames.last().map(|x| (x.ret.clone(), x.end.clone()));
+                match frame {
+                    Some((ret, end)) => {
+                        if let Some(v) = value {
+                            self.value_into(v, &ret)?;
+                        }
+                        self.emit(span, "jump", &[&end, "always"]);
+                    }
+                    None => {
+                        self.emit(span, "end", &[]);
+                    }
+                }
+            }
+            Stmt::Expr(Expr::Call { name, args, .. }) => {
+                self.call(name, args, span, None)?;
+            }
+            Stmt::Expr(Expr::Macro { name, args, .. }) => {
+                self.macro_call(name, args, span)?;
+            }
+            Stmt::Expr(_) => {
+                return Err((format!("Expression result is unused.{}", pos!(span)), ExitReason::CompileUnusedValue));
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn block(&mut self, body: &[Stmt]) -> CgResult<()> {
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        return Ok(());
+    }
+
+    /// Resolves labels and writes the program as mlog text. A jump to the
+    /// very end of the program gets an `end` to land on, as jumps past the
+    /// last instruction are ignored in game.
+    pub fn finish(&self) -> String {
+        return self.finish_mapped().0;
+    }
+
+    /// Like `finish`, also returning the source range every instruction was
+    /// generated from. The added `end` maps to an empty span.
+    pub fn finish_mapped(&self) -> (String, Vec<Span>) {
+        let mut labels = HashMap::<&str, usize>::new();
+        let mut count = 0;
+        for ins in &self.out {
+            match ins {
+                Ins::Label(name) => {
+                    labels.insert(name, count);
+                }
+                Ins::Code { .. } => {
+                    count += 1;
+                }
+            }
+        }
+        let mut lines = Vec::<String>::new();
+        let mut positions = Vec::<Span>::new();
+        let mut needs_end = false;
+        for ins in &self.out {
+            if let Ins::Code { op, args, span } = ins {
+                positions.push(*span);
+                let mut line = op.clone();
+                for (i, arg) in args.iter().enumerate() {
+                    line += " ";
+                    match labels.get(arg.as_str()) {
+                        Some(idx) if op == "jump" && i == 0 => {
+                            needs_end |= *idx == count;
+                            line += idx.to_string().as_str();
+                        }
+                        _ => {
+                            line += arg;
+                        }
+                    }
+                }
+                lines.push(line);
+            }
+        }
+        if needs_end {
+            lines.push("end".to_string());
+            positions.push(Span::default());
+        }
+        return (lines.join("\n") + "\n", positions);
+    }
+}
+
+/// Result of `generate`.
+pub struct Generated {
+    pub mlog: String,
+    /// Source range of every instruction.
+    pub positions: Vec<Span>,
+    /// New and original name of every variable `--minify-names` renamed.
+    pub names: Vec<(String, String)>,
+    /// The optimized program split into basic blocks, for `--emit ir`.
+    pub ir: Ir,
+}
+
+/// Runs the passes between parsing and generating code: name resolution,
+/// inlining of constants and the type check.
+pub fn prepare(root: &Module, opts: &HashMap<String, String>) -> CgResult<Module> {
+    let root = consts::inline(&scope::resolve(root, opts))?;
+    typecheck::check(&root, opts)?;
+    return Ok(root);
+}
+
+/// Generates mlog for the top level statements of `root`.
+pub fn generate(root: &Module, opts: &HashMap<String, String>) -> CgResult<Generated> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let level = optimize::opt_level(opts)?;
+    let root = &prepare(root, opts)?;
+    let mut cg = Codegen::new(root, opts)?;
+    for stmt in root.statements() {
+        cg.statement(stmt)?;
+    }
+    optimize::optimize(&mut cg.out, level, opts);
+    let mut names = Vec::<(String, String)>::new();
+    if argparse::flag_set(opts, "minify-names") {
+        names = names::minify(&mut cg.out);
+        info!("Renamed {} variables", names.len());
+    }
+    let ir = Ir::build(&cg.out);
+    debug!("------ IR:\n{}------", ir);
+    let (mlog, positions) = cg.finish_mapped();
+    debug!("------ Generated mlog:\n{}------", mlog);
+    return Ok(Generated { mlog, positions, names, ir });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ compile, error, opts, run, run_mlog };
+
+    const DENSE: &str = "fn name(x) { match x { 0 => { print(\"zero\"); } 1 | 2 => { print(\"small\"); } 3 => { print(\"three\"); } 5 => { print(\"five\"); } _ => { print(\"other\"); } } print(\",\"); }\n";
+
+    #[test]
+    fn dense_match_uses_a_jump_table() {
+        let calls = "name(@links - 1); name(@links); name(@links + 1); name(@links + 2); name(@links + 3); name(@links + 4);\nprintflush(message1);";
+        assert_eq!(run(&format!("{}{}", DENSE, calls), ""), "zero,small,small,three,other,five,");
+        assert!(compile(&format!("{}name(@links);", DENSE), &[]).unwrap().contains("op add @counter @counter"));
+    }
+
+    #[test]
+    fn jump_table_rejects_other_values() {
+        // out of range, fractions, strings and content all go to `_`
+        let calls = "name(@links - 2); name(@links + 0.5); name(\"a\"); name(@copper);\nprintflush(message1);";
+        assert_eq!(run(&format!("{}{}", DENSE, calls), ""), "other,other,other,other,");
+    }
+
+    #[test]
+    fn sparse_match_compares_in_turn() {
+        let source = "let x = @links * 100;\nmatch x { 1 => { print(\"a\"); } 100 => { print(\"b\"); } 1000 => { print(\"c\"); } }\nprintflush(message1);";
+        assert_eq!(run(source, ""), "b");
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(!mlog.contains("@counter"));
+        assert!(mlog.contains("jump 7 equal x 100"), "{}", mlog);
+    }
+
+    #[test]
+    fn strings_and_content_match_strictly() {
+        let source = "let i = @copper;\nlet s = \"hi\";\nmatch i { @lead => { print(\"lead\"); } @copper => { print(\"copper\"); } }\nmatch s { \"x\" => { print(\"x\"); } \"hi\" => { print(\"hi\"); } _ => { print(\"?\"); } }\nprintflush(message1);";
+        assert_eq!(run(source, ""), "copperhi");
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(mlog.contains("strictEqual i @lead") && mlog.contains("strictEqual s \"hi\""), "{}", mlog);
+    }
+
+    #[test]
+    fn first_matching_arm_wins() {
+        // a repeated pattern and the arms after `_` can never match
+        let source = "let x = @links;\nmatch x { 1 => { print(\"first\"); } 1 => { print(\"second\"); } _ => { print(\"any\"); } 2 => { print(\"two\"); } }\nprintflush(message1);";
+        assert_eq!(run(source, ""), "first");
+        let source = "let x = @links + 1;\nmatch x { 1 => { print(\"one\"); } _ => { print(\"any\"); } 2 => { print(\"two\"); } }\nprintflush(message1);";
+        assert_eq!(run(source, ""), "any");
+        // arms without a block hold one statement
+        assert_eq!(run("let x = @links;\nmatch x { 1 => print(\"one\"), _ => print(\"other\") }\nprintflush(message1);", ""), "one");
+    }
+
+    const CELL: &str = "message1 = message\ncell1 = memory-cell";
+
+    #[test]
+    fn memory_arrays() {
+        let source = "let a: [num; 4] in cell1;\nlet i = @links - 1;\na[i] = 5;\na[2] = a[i] + 1;\nprint(a[2]);\nprintflush(message1);";
+        assert_eq!(run(source, CELL), "6");
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(mlog.contains("write 5 cell1 i\nread __t1 cell1 i\n"), "{}", mlog);
+    }
+
+    #[test]
+    fn register_arrays() {
+        let source = "let r: [num; 3];\nlet i = @links;\nr[0] = 7;\nr[i] = 8;\nr[i + 1] = 9;\nprint(r[0] + r[i]);\nprint(r[2]);\nprintflush(message1);";
+        assert_eq!(run(source, ""), "159");
+        // dynamic indexes go through a table with one jump per element
+        let mlog = compile("let r: [num; 3];\nlet i = @links;\nr[i] = 8;\nprint(r[1]);", &[("opt-level", "0")]).unwrap();
+        assert!(mlog.starts_with("set i @links\nop floor __t0 i\nop strictEqual __t0 __t0 i\njump 16 equal __t0 false\njump 16 lessThan i 0\njump 16 greaterThan i 2\nop add @counter @counter i\njump 10 always\njump 12 always\njump 14 always\nset r[0] 8\n"), "{}", mlog);
+    }
+
+    #[test]
+    fn dynamic_indexes_outside_arrays() {
+        // stores outside the array do nothing and reads give null
+        for index in ["@links + 2", "@links - 2", "@links + 0.5"] {
+            let source = format!("let r: [num; 3];\nr[0] = 1;\nr[1] = 2;\nr[2] = 3;\nlet i = {};\nr[i] = 9;\nprint(r[i]);\nprint(r[0] + r[1] + r[2]);\nprintflush(message1);", index);
+            assert_eq!(run(&source, ""), "null6", "index {}", index);
+        }
+    }
+
+    #[test]
+    fn constant_indexes_are_checked() {
+        assert_eq!(error("let r: [num; 3];\nr[3] = 1;"), ("Index 3 is out of bounds for \"r\" of length 3. (line 2, col 3)".to_string(), ExitReason::CompileIndexOutOfBounds));
+        assert_eq!(error("let r: [num; 3];\nprint(r[-1]);").0, "Index -1 is out of bounds for \"r\" of length 3. (line 2, col 9)");
+        assert_eq!(error("let r: [num; 3];\nprint(r[1.5]);").1, ExitReason::CompileIndexOutOfBounds);
+        assert_eq!(error("let a: [num; 4] in cell1;\nprint(a[4]);").1, ExitReason::CompileIndexOutOfBounds);
+    }
+
+    #[test]
+    fn misused_arrays() {
+        let bad_array = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadArray, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_array("let r: [num; 0];"), "Length of array \"r\" should be a positive integer. (line 1, col 1)");
+        assert_eq!(bad_array("let r: [num; 2.5];"), "Length of array \"r\" should be a positive integer. (line 1, col 1)");
+        assert_eq!(bad_array("let r: [num; 3];\nlet x = r;"), "Array \"r\" can only be used with an index. (line 2, col 9)");
+        assert_eq!(bad_array("let r: [num; 3];\nr = 1;"), "Array \"r\" can only be assigned with an index. (line 2, col 1)");
+        assert_eq!(bad_array("let x = 1;\nprint(x[0]);"), "\"x\" is not an array. (line 2, col 7)");
+    }
+
+    #[test]
+    fn structs_are_flattened_and_copied() {
+        let source = "struct Point { x, y }\nlet p = Point { x: @links, y: 2 };\nlet q = p;\nq.x = 5;\nprint(p.x);\nprint(q.x + q.y);\nprintflush(message1);";
+        assert_eq!(run(source, ""), "17");
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(mlog.starts_with("set p.x @links\nset p.y 2\nset q.x p.x\nset q.y p.y\nset q.x 5\n"), "{}", mlog);
+        // nested structs, and a struct rebuilt from its own fields
+        let nested = "struct V { x, y }\nstruct Line { a, b }\nlet l = Line { a: V { x: @links, y: 2 }, b: V { x: 3, y: 4 } };\nl.b.y = l.a.x + 10;\nprint(l.b.y);\nprint(l.a.y);\nprintflush(message1);";
+        assert_eq!(run(nested, ""), "112");
+        assert!(compile(nested, &[("opt-level", "0")]).unwrap().contains("op add l.b.y l.a.x 10"));
+        assert_eq!(run("struct V { x, y }\nlet v = V { x: @links, y: 2 };\nv = V { x: v.y, y: v.x };\nprint(v.x);\nprint(v.y);\nprintflush(message1);", ""), "21");
+        // fields do not share names with variables
+        assert_eq!(run("struct P { x, y }\nlet p_x = 5;\nlet p = P { x: 1, y: 2 };\nprint(p_x);\nprintflush(message1);", ""), "5");
+    }
+
+    #[test]
+    fn structs_through_functions() {
+        assert_eq!(run("struct V { x, y }\nfn len2(v) { return v.x * v.x + v.y * v.y; }\nlet v = V { x: @links + 2, y: 4 };\nprint(len2(v));\nprintflush(message1);", ""), "25");
+        assert_eq!(run("struct P { x, y }\nfn swap(p) { return P { x: p.y, y: p.x }; }\nlet p = P { x: @links, y: 2 };\nlet s = swap(p);\nprint(s.x);\nprint(s.y);\nprintflush(message1);", ""), "21");
+    }
+
+    #[test]
+    fn struct_errors() {
+        let bad_struct = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadStruct, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_struct("struct Point { x, y }\nlet p = Point { x: 1, y: 2 };\nprint(p.z);"), "Struct \"Point\" has no field \"z\". (line 3, col 7)");
+        assert_eq!(bad_struct("struct Point { x, y }\nlet p = Point { x: 1 };"), "Missing field \"y\" of struct \"Point\". (line 2, col 9)");
+        assert_eq!(bad_struct("struct Point { x, y }\nlet p = Point { x: 1, y: 2, z: 3 };"), "Struct \"Point\" has no field \"z\". (line 2, col 32)");
+        assert_eq!(bad_struct("let p = Foo { x: 1 };"), "Unknown struct \"Foo\". (line 1, col 9)");
+        assert_eq!(bad_struct("let a = 1;\nprint(a.x);"), "Field \"x\" of a value that is not a struct. (line 2, col 7)");
+        assert_eq!(bad_struct("struct Point { x, y }\nlet p = Point { x: 1, y: 2 };\nprint(p);"), "A struct can only be assigned, passed and returned whole, or used through its fields. (line 3, col 7)");
+        assert_eq!(error("struct P { x, x }"), ("Field \"x\" is defined twice. (line 1, col 15)".to_string(), ExitReason::CompileDuplicateItem));
+        assert_eq!(error("struct P { x }\nstruct P { y }"), ("Struct \"P\" is defined twice. (line 2, col 1)".to_string(), ExitReason::CompileDuplicateItem));
+    }
+
+    #[test]
+    fn print_macro_lowering() {
+        let source = "let hp = @links * 30;\nlet max = @links + 99;\nprint!(\"HP: {} / {} {{ok}}\", hp, max);\nprintflush(message1);";
+        assert_eq!(run(source, ""), "HP: 30 / 100 {ok}");
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(mlog.contains("print \"HP: \"\nprint hp\nprint \" / \"\nprint max\nprint \" {ok}\"\n"), "{}", mlog);
+        // literal values are joined into the text while compiling
+        assert_eq!(compile("print!(\"{} and {}\", 1.5, \"s\");", &[]).unwrap(), "print \"1.5 and s\"\n");
+    }
+
+    #[test]
+    fn print_macro_uses_format_on_version_8() {
+        let source = "let hp = @links * 30;\nlet max = @links + 99;\nprintflush(message1);\nprint!(\"HP: {} / {} {{ok}}\", hp, max);\nprintflush(message1);";
+        let mlog = compile(source, &[("opt-level", "0"), ("target", "8")]).unwrap();
+        assert!(mlog.contains("print \"HP: {0} / {1} {ok}\"\nformat hp\nformat max\n"), "{}", mlog);
+        assert_eq!(run_mlog(&mlog, ""), "\nHP: 30 / 100 {ok}");
+        // the buffer may hold placeholders without a printflush right before,
+        // so the values are printed one by one
+        let source = "let x = @links;\nprint(\"cost {0}: \");\nprint!(\"got {}\", x);\nprintflush(message1);";
+        let mlog = compile(source, &[("target", "8")]).unwrap();
+        assert!(!mlog.contains("format"), "{}", mlog);
+        assert_eq!(run_mlog(&mlog, ""), "cost {0}: got 1");
+        let mlog = compile("let x = @links;\nprintflush(message1);\nmatch x { 1 => print(\"{0}\"), _ => {} }\nprint!(\"{}\", x);", &[("target", "8")]).unwrap();
+        assert!(!mlog.contains("format"), "{}", mlog);
+        assert!(!compile("let x = @links;\nprint!(\"{}\", x);", &[("target", "8")]).unwrap().contains("format"));
+        // a placeholder in the text itself would be filled too
+        let mlog = compile("let a = @links;\nprint!(\"{{0}} {}\", a);\nprintflush(message1);", &[("target", "8")]).unwrap();
+        assert!(!mlog.contains("format"), "{}", mlog);
+        assert_eq!(run_mlog(&mlog, ""), "{0} 1");
+        assert_eq!(target_version(&opts(&[("target", "9")])).unwrap_err().1, ExitReason::OptionBadValue);
+    }
+
+    #[test]
+    fn print_macro_errors() {
+        let bad_format = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadFormat, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_format("print!(\"{} {}\", 1);"), "The message of \"print!\" has 2 placeholders, but got 1 value. (line 1, col 1)");
+        assert_eq!(bad_format("print!(\"{}\", 1, 2);"), "The message of \"print!\" has 1 placeholder, but got 2 values. (line 1, col 1)");
+        assert_eq!(bad_format("print!(\"a { b\");"), "Unmatched '{' in the message of \"print!\", write '{{' to print it. (line 1, col 8)");
+        assert_eq!(bad_format("print!(\"a } b\");"), "Unmatched '}' in the message of \"print!\", write '}}' to print it. (line 1, col 8)");
+        assert_eq!(bad_format("let a = @links;\nprint!(\"{0} {}\", a);"), "Unmatched '{' in the message of \"print!\", write '{{' to print it. (line 2, col 8)");
+        assert_eq!(bad_format("let s = \"x\";\nprint!(s);"), "Macro \"print!\" takes a string literal first. (line 2, col 1)");
+    }
+
+    #[test]
+    fn mlog_passthrough() {
+        let source = "fn sum(n) { let total = 0; `loop:\nop add {total} {total} {n}\nop sub {n} {n} 1\njump loop greaterThan {n} 0`; return total; }\nprint(sum(@links + 2));\nprint(\",\");\nprint(sum(@links + 1));\nprintflush(message1);";
+        assert_eq!(run(source, ""), "6,3");
+        // every copy of the code gets its own labels
+        let mlog = compile(source, &[("opt-level", "0")]).unwrap();
+        assert!(mlog.contains("jump 2 greaterThan __sum0_n 0\n") && mlog.contains("jump 11 greaterThan __sum1_n 0\n"), "{}", mlog);
+        let source = "let x = @links;\nmlog!(\"op mul {x} {x} 3\");\nmlog!(\"print {x + 1}\");\nmlog!(\"print \\\"{{}}\\\"\");\nprintflush(message1);";
+        assert_eq!(run(source, ""), "4{}");
+    }
+
+    #[test]
+    fn mlog_errors() {
+        let bad_format = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadFormat, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_format("`jump nowhere always`;"), "Jump in mlog code to \"nowhere\", which is not a label of the same code. (line 1, col 1)");
+        assert_eq!(bad_format("`print {x`;"), "Unmatched '{' in mlog code, write '{{' for a brace. (line 1, col 8)");
+        assert_eq!(bad_format("`print }`;"), "Unmatched '}' in mlog code, write '}}' for a brace. (line 1, col 8)");
+        assert_eq!(bad_format("let s = 1;\nmlog!(s);"), "Macro \"mlog!\" takes a string or backtick literal. (line 2, col 1)");
+        assert_eq!(error("`a:\na:\nend`;"), ("Label \"a\" is defined twice in mlog code. (line 1, col 1)".to_string(), ExitReason::CompileDuplicateItem));
+        assert_eq!(error("`print {}`;"), ("Expected a value between '{' and '}' in mlog code. (line 1, col 8)".to_string(), ExitReason::CompileExpectedValue));
+        // values are parsed where they are written
+        assert_eq!(error("`print\n  {@links +}`;").0, "Expected a value after operator '+'. (line 2, col 11)");
+    }
+}
@@ -2,7 +2,7 @@ use std::{ collections::HashMap, ffi::OsStr, fs::exists, path::Path };
 use crate::*;
 
 pub fn run_command(
-    args: &Vec<String>,
+    args: &[String],
     opts: &HashMap<String, String>
 ) -> Result<(), (String, ExitReason)> {
     macro_rules! opts {
@@ -13,7 +13,7 @@ pub fn run_command(
 
     let filename = Path::new(&args[0])
         .file_name()
-        .unwrap_or(&OsStr::new("HOW-DID-YOU-EXECUTE-A-DIRECTORY"))
+        .unwrap_or(OsStr::new("HOW-DID-YOU-EXECUTE-A-DIRECTORY"))
         .to_str()
         .unwrap_or("INVALID-FILE-NAME");
     info!("Executing command [{}]", args[1].as_str());
@@ -23,6 +23,11 @@ pub fn run_command(
             Ok(())
         }
         "compile" => { compile::compile(args, opts) }
+        "run" => { emulator::run(args, opts) }
+        "simulate" => { world::simulate(args, opts) }
+        "render" => { render::render(args, opts) }
+        "test" => { unittest::test(args, opts) }
+        "debug" => { debugger::debug(args, opts) }
         _ => {
             if exists(&args[1]).unwrap_or(false) {
                 return Err((
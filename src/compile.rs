@@ -1,382 +1,1369 @@
-use std::fmt::Display;
 use std::io::ErrorKind;
 use std::{ collections::HashMap };
-use std::fs::read_to_string;
-use crate::tokenize::{StringType, Token};
+use std::fs::{ read_to_string, write };
+use std::path::Path;
+use crate::tokenize::{tokenize, StringType, Token};
+use crate::ast::{ Arm, Const, Enum, Expr, Field, Function, Item, Module, Param, Pattern, Span, Stmt, Struct, Test, VarType, Variant };
 use crate::*;
 
-#[derive(Debug)]
-enum ValType {
-    Nop,
-    Ident,
-    Const,
-    FuncCall,
-    MacroCall,
-    CodeBlock,
+/// Whether the tokenizer read `token` as a number literal.
+fn is_num(token: &Token) -> bool {
+    let mut chars = token.content.chars();
+    return token.strtype == StringType::Not && match (chars.next(), chars.next()) {
+        (Some('.'), Some(c)) => c.is_ascii_digit(),
+        (Some('%'), Some(c)) => c.is_ascii_hexdigit(),
+        (Some(c), _) => c.is_ascii_digit(),
+        _ => false,
+    };
 }
 
-#[derive(Debug)]
-#[derive(PartialEq, Eq)]
-enum VarType {
-    Nop,
-    Str,
-    Char,
-    Num,
+/// Checks a number literal and rewrites it the way the game's parser reads
+/// it: underscores are dropped, `0x` and `0b` integers and exponents are
+/// written out in decimal, and colors stay `%rrggbb` or `%rrggbbaa`.
+fn number(token: &Token) -> Result<String, (String, ExitReason)> {
+    let bad = |why: &str| Err((
+        format!("Invalid number \"{}\": {}. (line {}, col {})", token.content, why, token.line, token.col),
+        ExitReason::CompileBadNumber,
+    ));
+    if let Some(color) = token.content.strip_prefix('%') {
+        return Ok(format!("%{}", color.to_ascii_lowercase()));
+    }
+    let text = token.content.replace('_', "");
+    for (prefix, radix, name) in [("0x", 16, "hexadecimal"), ("0b", 2, "binary")] {
+        if let Some(digits) = text.strip_prefix(prefix) {
+            if digits.is_empty() || !digits.chars().all(|x| x.is_digit(radix)) {
+                return bad(&format!("expected {} digits after \"{}\"", name, prefix));
+            }
+            return match i64::from_str_radix(digits, radix) {
+                Ok(n) if n < 1 << 53 => Ok(n.to_string()),
+                _ => bad("it is too large to be stored exactly"),
+            };
+        }
+    }
+    let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+        Some((m, e)) => (m, Some(e)),
+        None => (text.as_str(), None),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, "0"));
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|x| x.is_ascii_digit());
+    if !(digits(whole) || whole.is_empty()) || !digits(fraction) {
+        return bad("expected only digits, a point and an exponent like 1.5e-3");
+    }
+    let exponent = match exponent {
+        None => {
+            return Ok(match whole.is_empty() {
+                true => format!("0{}", mantissa),
+                false => mantissa.to_string(),
+            });
+        }
+        Some(e) => e,
+    };
+    if !digits(exponent.trim_start_matches(['+', '-'])) {
+        return bad("expected digits after the exponent");
+    }
+    // plain digits for the usual range, else the shortest exponent form
+    return match text.parse::<f64>() {
+        Ok(n) if n == 0.0 || (1e-3..1e16).contains(&n.abs()) => Ok(format!("{}", n)),
+        Ok(n) if n.is_finite() => Ok(format!("{:e}", n)),
+        _ => bad("it is too large"),
+    };
 }
 
-#[derive(Debug)]
-struct Val {
-    pub t: ValType,
-    pub vt: VarType,
-    pub ident: Option<String>,
-    pub left: Option<Box<Val>>,
-    pub right: Option<Box<Val>>,
-    pub args: Option<Vec<Val>>,
-}
+/// Parses a single value: a literal, a variable, or a function or macro
+/// call with its arguments.
+fn parse_tokens(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Expr, (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    macro_rules! pos {
+        ($tok:expr) => {
+            format!(" (line {}, col {})", $tok.line, $tok.col)
+        };
+    }
+
+    debug!("Begin depth {}", depth);
 
-impl Default for Val {
-    fn default() -> Self {
-        Val { t: ValType::Nop, vt: VarType::Nop, ident: None, left: None, right: None, args: None, }
+    let span = token_span(tokens);
+    let first = tokens[0];
+    let mut used = 1;
+    let expr = if first.strtype != StringType::Not {
+        if first.strtype == StringType::Backtick {
+            return match tokens.get(1) {
+                None => mlog_macro(&first.content, span, opts, depth),
+                Some(token) => Err((
+                    format!("Unexpected token '{}' after mlog code.{}", token.content, pos!(token)),
+                    ExitReason::CompileUnexpectedToken,
+                )),
+            };
+        }
+        let value = unescape(first, opts)?;
+        if first.strtype == StringType::Char && value.chars().count() != 1 {
+            return Err((
+                format!(
+                    "Char '{}' should be 1 character long, but is {}.{}",
+                    first.content,
+                    value.chars().count(),
+                    pos!(first)
+                ),
+                ExitReason::CompileCharTooLong,
+            ));
+        }
+        match (&first.strtype, value.chars().next()) {
+            // printchar casts to a Java char, a single UTF-16 unit
+            (StringType::Char, Some(c)) if c as u32 > 0xffff => {
+                return Err((
+                    format!(
+                        "Char '{}' is U+{:X}, but only characters up to U+FFFF can be printed.{}",
+                        first.content,
+                        c as u32,
+                        pos!(first)
+                    ),
+                    ExitReason::CompileBadChar,
+                ));
+            }
+            (StringType::Char, Some(c)) => Expr::Literal { vt: VarType::Num, value: (c as u32).to_string(), span },
+            _ => Expr::Literal { vt: VarType::Str, value, span },
+        }
+    }
+    else if is_num(first) {
+        Expr::Literal { vt: VarType::Num, value: number(first)?, span }
+    }
+    else if let Some(digits) = tokens.get(1).filter(|x| first.content == "%" && x.line == first.line && x.col == first.col + 1) {
+        // the tokenizer only reads `%` followed by 6 or 8 hex digits as a color
+        return Err((
+            format!("Invalid color \"%{}\": expected 6 or 8 hexadecimal digits like %ff0000 or %ff000080.{}", digits.content, pos!(first)),
+            ExitReason::CompileBadNumber,
+        ));
+    }
+    else if !is_ident(first) {
+        return Err((format!("Unexpected token '{}'.{}", first.content, pos!(first)), ExitReason::CompileUnexpectedToken));
+    }
+    else if tokens.len() == 1 {
+        Expr::Var { name: first.content.clone(), span }
+    }
+    else if tokens[1].strtype == StringType::Not && tokens[1].content == "[" {
+        let close = match matching_close(tokens, 1) {
+            Some(c) => c,
+            None => {
+                return Err((format!("Expected ']' to close the index of \"{}\".{}", first.content, pos!(tokens[1])), ExitReason::CompileUnexpectedToken));
+            }
+        };
+        if close == 2 {
+            return Err((format!("Expected an index between '[' and ']'.{}", pos!(tokens[1])), ExitReason::CompileExpectedValue));
+        }
+        used = close + 1;
+        Expr::Index { name: first.content.clone(), index: Box::new(parse_expr(&tokens[2..close], opts, depth + 1)?), span }
+    }
+    else if tokens[1].strtype == StringType::Not && tokens[1].content == "." {
+        let mut base = Expr::Var { name: first.content.clone(), span: token_span(&tokens[..1]) };
+        while tokens.get(used).is_some_and(|x| x.strtype == StringType::Not && x.content == ".") {
+            let field = match tokens.get(used + 1) {
+                Some(field) if is_ident(field) => field,
+                _ => {
+                    return Err((format!("Expected a field name after '.'.{}", pos!(tokens[used])), ExitReason::CompileUnexpectedToken));
+                }
+            };
+            base = Expr::Field { base: Box::new(base), field: field.content.clone(), span: token_span(&tokens[..used + 2]) };
+            used += 2;
+        }
+        base
     }
+    else if tokens[1].strtype == StringType::Not && tokens[1].content == "{" {
+        let close = match matching_close(tokens, 1) {
+            Some(c) => c,
+            None => {
+                return Err((format!("Expected '}}' to close the fields of \"{}\".{}", first.content, pos!(tokens[1])), ExitReason::CompileUnexpectedToken));
+            }
+        };
+        // `field: value`, or just `field` for a variable of the same name
+        let mut fields = Vec::<(String, Expr)>::new();
+        for entry in split_commas(&tokens[2..close]) {
+            let field = match entry.first() {
+                Some(field) => *field,
+                None => {
+                    return Err((format!("Expected \"field: value\" in \"{}\".{}", first.content, pos!(tokens[1])), ExitReason::CompileUnexpectedToken));
+                }
+            };
+            if !is_ident(field) || (entry.len() > 1 && (entry[1].content != ":" || entry.len() == 2)) {
+                return Err((format!("Expected \"field: value\" in \"{}\".{}", first.content, pos!(field)), ExitReason::CompileUnexpectedToken));
+            }
+            let value = match entry.len() {
+                1 => Expr::Var { name: field.content.clone(), span: token_span(&entry[..1]) },
+                _ => parse_expr(&entry[2..], opts, depth + 1)?,
+            };
+            fields.push((field.content.clone(), value));
+        }
+        used = close + 1;
+        Expr::Struct { name: first.content.clone(), fields, span: token_span(&tokens[..used]) }
+    }
+    else {
+        let is_macro = tokens[1].content == "!";
+        let open = if is_macro { 2 } else { 1 };
+        if is_macro && tokens.get(2).is_none_or(|x| x.content != "(") {
+            return Err((format!("Expected '(' after macro call \"{}!\".{}", first.content, pos!(tokens[1])), ExitReason::CompileExpectedParenthesisAfterMacro));
+        }
+        let token = tokens[open];
+        if token.strtype != StringType::Not || token.content != "(" {
+            return Err((
+                format!(
+                    "Unpexpected token {}'{}' after '{}', expected one of ['(', '[', '!', '::', '=', '--', '++', '+=' , '-=', '*=', '/=', '//=', '^=', '=', '%=', '%%=', '<<=', '>>=', '>>>=', '&=', '|=', '&&=', '||='].{}",
+                    match token.strtype {
+                        StringType::Not => "",
+                        _ => "string ",
+                    },
+                    token.content,
+                    first.content,
+                    pos!(token)
+                ),
+                ExitReason::CompileBadTokenAfterIdentifier,
+            ));
+        }
+        let close = match matching_close(tokens, open) {
+            Some(c) => c,
+            None => {
+                return Err((format!("Expected ')' to close the arguments of \"{}\".{}", first.content, pos!(token)), ExitReason::CompileUnexpectedToken));
+            }
+        };
+        let mut args = Vec::<Expr>::new();
+        for arg in split_commas(&tokens[open + 1..close]) {
+            args.push(parse_expr(arg, opts, depth + 1)?);
+        }
+        used = close + 1;
+        let name = first.content.clone();
+        match (is_macro, name.as_str(), args.as_slice()) {
+            (true, "mlog", [Expr::Literal { vt: VarType::Str, value, span: at }]) => mlog_macro(value, *at, opts, depth)?,
+            (true, "mlog", [code @ Expr::Macro { name, .. }]) if name == "mlog" => code.clone(),
+            (true, ..) => Expr::Macro { name, args, span },
+            (false, ..) => Expr::Call { name, args, span },
+        }
+    };
+    if let Some(token) = tokens.get(used) {
+        return Err((
+            format!("Unexpected token '{}' after a complete value, is a ';' missing?{}", token.content, pos!(token)),
+            ExitReason::CompileUnexpectedToken,
+        ));
+    }
+
+    debug!("End depth {}", depth);
+    return Ok(expr);
 }
 
-impl Display for Val {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.t {
-            ValType::Nop => write!(f, "<Nop>"),
-            ValType::CodeBlock => {
-                if let Some(args) = &self.args {
-                    let mut outstr = "".to_string();
-                    for arg in args {
-                        outstr += format!("{}", arg).as_str();
-                        outstr += ", ";
+/// Colors Mindustry knows by name in tags like `[red]`.
+const COLORS: [&str; 41] = [
+    "clear", "black", "white", "lightgray", "light_gray", "gray", "darkgray", "dark_gray", "blue", "navy", "royal",
+    "slate", "sky", "cyan", "teal", "green", "acid", "lime", "forest", "olive", "yellow", "gold", "goldenrod", "orange",
+    "brown", "tan", "brick", "crimson", "red", "scarlet", "coral", "salmon", "pink", "magenta", "purple", "violet",
+    "maroon", "accent", "unlaunched", "highlight", "stat",
+];
+
+/// Text of a string or char literal with its escapes resolved: `\n`, `\t`,
+/// `\\`, `\"`, `\'` and `\u{XXXX}` (or `\uXXXX`). Color tags like `[red]`
+/// or `[#ff0000]` are kept for Mindustry to render, with a warning for
+/// names it does not know. `[[` prints a single `[`.
+fn unescape(token: &Token, opts: &HashMap<String, String>) -> Result<String, (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let chars = token.content.chars().collect::<Vec<_>>();
+    // position of every character, the literal starting after its quote
+    let mut positions = Vec::<(u64, u64)>::new();
+    let (mut line, mut col) = (token.line, token.col + 1);
+    for c in &chars {
+        positions.push((line, col));
+        (line, col) = if *c == '\n' { (line + 1, 1) } else { (line, col + 1) };
+    }
+    let at = |i: usize| format!(" (line {}, col {})", positions[i].0, positions[i].1);
+
+    let mut text = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let escape = match chars.get(i + 1) {
+                    Some(c) => *c,
+                    None => {
+                        return Err((format!("Expected an escape after '\\'.{}", at(i)), ExitReason::CompileBadEscape));
+                    }
+                };
+                i += 2;
+                match escape {
+                    'n' => text.push('\n'),
+                    't' => text.push('\t'),
+                    '\\' | '"' | '\'' => text.push(escape),
+                    'u' => {
+                        let braced = chars.get(i) == Some(&'{');
+                        let digits = match braced {
+                            true => chars[i + 1..].iter().take_while(|x| **x != '}').collect::<String>(),
+                            false => chars[i..].iter().take(4).collect::<String>(),
+                        };
+                        let valid = !digits.is_empty() && digits.len() <= 6 && digits.chars().all(|x| x.is_ascii_hexdigit());
+                        let closed = !braced || chars.get(i + 1 + digits.chars().count()) == Some(&'}');
+                        let c = u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32);
+                        match (valid && closed && (braced || digits.len() == 4), c) {
+                            (true, Some(c)) => text.push(c),
+                            _ => {
+                                return Err((format!("Expected a unicode escape like \\u{{41}} or \\u0041.{}", at(i - 2)), ExitReason::CompileBadEscape));
+                            }
+                        }
+                        i += digits.chars().count() + if braced { 2 } else { 0 };
+                    }
+                    _ => {
+                        return Err((
+                            format!("Unknown escape '\\{}', expected one of \\n, \\t, \\\\, \\\", \\' or \\u{{...}}.{}", escape, at(i - 2)),
+                            ExitReason::CompileBadEscape,
+                        ));
                     }
-                    outstr = outstr.strip_suffix(", ").unwrap_or_default().to_string();
-                    write!(f, "<CodeBlock [{}]>", outstr)
                 }
-                else {
-                    write!(f, "<CodeBlock [INVALID]>")
+                continue;
+            }
+            '[' if chars.get(i + 1) == Some(&'[') => {
+                text.push_str("[[");
+                i += 2;
+                continue;
+            }
+            '[' => {
+                let tag = chars[i + 1..].iter().take_while(|x| **x != ']' && **x != '[').collect::<String>();
+                let closed = chars.get(i + 1 + tag.chars().count()) == Some(&']');
+                let hex = tag.strip_prefix('#').is_some_and(|x| [3, 4, 6, 8].contains(&x.len()) && x.chars().all(|y| y.is_ascii_hexdigit()));
+                let name = !tag.is_empty() && tag.chars().all(|x| x.is_alphanumeric() || x == '_');
+                if closed && (tag.starts_with('#') && !hex || name && !COLORS.contains(&tag.to_lowercase().as_str())) {
+                    cwarn!("Unknown color tag \"[{}]\", Mindustry will print it as text.{}", tag, at(i));
                 }
             }
-            ValType::Ident => write!(f, "<Ident \"{}\">", self.ident.as_ref().unwrap_or(&"<UNKNOWN>".to_string())),
-            ValType::Const => write!(f, "<Const \"{}\" ({:?})>", self.ident.as_ref().unwrap_or(&"<UNKNOWN>".to_string()), self.vt),
-            ValType::MacroCall | ValType::FuncCall => {
-                if let Some(args) = &self.args {
-                    let mut outstr = "".to_string();
-                    for arg in args {
-                        outstr += format!("{}", arg).as_str();
-                        outstr += ", ";
+            _ => {}
+        }
+        text.push(chars[i]);
+        i += 1;
+    }
+    return Ok(text);
+}
+
+/// Turns the text of a backtick literal or `mlog!` into an `mlog` macro
+/// call: every `{value}` is parsed and replaced with a `{}` placeholder the
+/// code generator fills in with the variable holding the value. `{{` and
+/// `}}` are kept to stand for a brace.
+fn mlog_macro(code: &str, span: Span, opts: &HashMap<String, String>, depth: u64) -> Result<Expr, (String, ExitReason)> {
+    let mut template = String::new();
+    let mut args = Vec::<Expr>::new();
+    let (mut line, mut col) = (span.line, span.col + 1);
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        let (start_line, start_col) = (line, col);
+        let mut step = |c: char| {
+            if c == '\n' {
+                line += 1;
+                col = 0;
+            }
+            col += 1;
+        };
+        step(c);
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                step(chars.next().unwrap_or_default());
+                template.push(c);
+                template.push(c);
+            }
+            ('{', _) => {
+                let mut inner = String::new();
+                while let Some(c) = chars.next_if(|x| *x != '}') {
+                    step(c);
+                    inner.push(c);
+                }
+                if chars.next().is_none() {
+                    return Err((
+                        format!("Unmatched '{{' in mlog code, write '{{{{' for a brace. (line {}, col {})", start_line, start_col),
+                        ExitReason::CompileBadFormat,
+                    ));
+                }
+                step('}');
+                let mut tokens = tokenize(inner)?;
+                for token in tokens.iter_mut() {
+                    if token.line == 1 {
+                        token.col += start_col;
                     }
-                    outstr = outstr.strip_suffix(", ").unwrap_or_default().to_string();
-                    write!(f, "<{:?} {}([{}])>",
-                            self.t,
-                            self.ident.as_ref().unwrap_or(&"<UNKNOWN>".to_string()),
-                            outstr)
+                    token.line += start_line - 1;
                 }
-                else {
-                    write!(f, "<{:?} {}([INVALID])>",
-                            self.t,
-                            self.ident.as_ref().unwrap_or(&"<UNKNOWN>".to_string()))
+                check_reserved(&tokens)?;
+                if tokens.is_empty() {
+                    return Err((
+                        format!("Expected a value between '{{' and '}}' in mlog code. (line {}, col {})", start_line, start_col),
+                        ExitReason::CompileExpectedValue,
+                    ));
                 }
+                args.push(parse_expr(&tokens.iter().collect::<Vec<_>>(), opts, depth + 1)?);
+                template += "{}";
             }
+            ('}', _) => {
+                return Err((
+                    format!("Unmatched '}}' in mlog code, write '}}}}' for a brace. (line {}, col {})", start_line, start_col),
+                    ExitReason::CompileBadFormat,
+                ));
+            }
+            _ => template.push(c),
         }
     }
+    args.insert(0, Expr::Literal { vt: VarType::Str, value: template, span });
+    return Ok(Expr::Macro { name: "mlog".to_string(), args, span });
 }
 
-enum State {
-    None,
-    PrevIsIdentifier,
-    ParseRemainder,
-    ParseArgs,
+/// Splits `tokens` at the commas outside of nested brackets. A trailing
+/// comma is allowed.
+fn split_commas<'b>(tokens: &'b [&'b Token]) -> Vec<&'b [&'b Token]> {
+    let mut parts = Vec::<&[&Token]>::new();
+    let mut start = 0;
+    let mut nesting = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        if t.strtype != StringType::Not {
+            continue;
+        }
+        match t.content.as_str() {
+            "(" | "[" | "{" => nesting += 1,
+            ")" | "]" | "}" => nesting -= 1,
+            "," if nesting == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < tokens.len() {
+        parts.push(&tokens[start..]);
+    }
+    return parts;
 }
 
-fn is_num(s: &String) -> bool {
-    let numerics = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'e'];
-    if s.len() == 0 {
-        return false;
-    }
-    if !s.is_ascii() {
-        return false;
+/// Fails on a name starting with `__`, which the compiler keeps for the
+/// variables and labels it generates.
+fn check_reserved(tokens: &[Token]) -> Result<(), (String, ExitReason)> {
+    if let Some(token) = tokens.iter().find(|x| x.strtype == StringType::Not && x.content.starts_with("__")) {
+        return Err((
+            format!("Names starting with '__' are reserved: '{}'. (line {}, col {})", token.content, token.line, token.col),
+            ExitReason::CompileReservedName,
+        ));
     }
-    let mut ecount = 0;
-    return s.chars().all(|x| {
-        if x == 'e' {
-            ecount += 1;
+    return Ok(());
+}
+
+fn is_ident(token: &Token) -> bool {
+    let mut chars = token.content.chars();
+    return token.strtype == StringType::Not &&
+        chars.next().is_some_and(|x| x.is_alphabetic() || x == '_') &&
+        chars.all(|x| x.is_alphanumeric() || x == '_') &&
+        !tokenize::KEYWORDS.contains(&token.content.as_str());
+}
+
+fn binop_precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" | "===" | "!==" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" | ">>>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "//" | "%" | "%%" => 10,
+        "**" => 11,
+        _ => {
+            return None;
         }
-        return ecount <= 1 && numerics.contains(&x);
     })
 }
 
-fn parse_tokens(tokens:&Vec<&Token>, opts:&HashMap<String, String>, depth: u64) -> Result<Val, (String, ExitReason)> {
-    macro_rules! opts {
-        () => {
-            &opts
-        };
+/// Source range covered by `tokens`, from the first character of the first
+/// token to the last character of the last one.
+fn token_span(tokens: &[&Token]) -> Span {
+    let (first, last) = match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            return Span::default();
+        }
+    };
+    let quote = if last.strtype == StringType::Not { 0 } else { 1 };
+    let (end_line, end_col) = match last.content.rfind('\n') {
+        Some(idx) => (
+            last.line + last.content.matches('\n').count() as u64,
+            last.content[idx + 1..].chars().count() as u64 + quote,
+        ),
+        None => (last.line, last.col + last.content.chars().count() as u64 + quote - 1),
+    };
+    return Span { line: first.line, col: first.col, end_line, end_col };
+}
+
+const ASSIGN_OPS: [&str; 17] = [
+    "=", "+=", "-=", "*=", "/=", "//=", "%=", "%%=", "**=", "^=", "|=", "&=", "<<=", ">>=", ">>>=", "&&=", "||=",
+];
+
+/// Index of the token closing the bracket opened at `open`.
+fn matching_close(tokens: &[&Token], open: usize) -> Option<usize> {
+    let mut nesting = 0;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.strtype != StringType::Not {
+            continue;
+        }
+        match token.content.as_str() {
+            "(" | "[" | "{" => nesting += 1,
+            ")" | "]" | "}" => {
+                nesting -= 1;
+                if nesting == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
     }
+    return None;
+}
+
+
+/// Parses an expression. Binary operators are split at the one binding
+/// the loosest, everything else is handed to `parse_tokens`.
+pub fn parse_expr(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Expr, (String, ExitReason)> {
     macro_rules! pos {
         ($tok:expr) => {
             format!(" (line {}, col {})", $tok.line, $tok.col)
         };
     }
+    if tokens.is_empty() {
+        return Err(("Expected a value.".to_string(), ExitReason::CompileExpectedValue));
+    }
+    let span = token_span(tokens);
 
-    debug!("Begin depth {}", depth);
+    let mut best: Option<(usize, u8)> = None;
+    let mut nesting = 0;
+    let mut prev_operand = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.strtype != StringType::Not {
+            prev_operand = true;
+            continue;
+        }
+        match token.content.as_str() {
+            "(" | "[" | "{" => {
+                nesting += 1;
+                prev_operand = false;
+            }
+            ")" | "]" | "}" => {
+                nesting -= 1;
+                prev_operand = true;
+            }
+            op => {
+                if nesting != 0 {
+                    continue;
+                }
+                if let Some(prec) = binop_precedence(op) {
+                    if prev_operand {
+                        let right_assoc = op == "**";
+                        if best.is_none_or(|x| prec < x.1 || (prec == x.1 && !right_assoc)) {
+                            best = Some((i, prec));
+                        }
+                    }
+                    prev_operand = false;
+                }
+                else {
+                    prev_operand = !tokens[i].content.chars().all(|x| x.is_ascii_punctuation());
+                }
+            }
+        }
+    }
 
-    let nonestr = "None".to_string();
+    let first = tokens[0];
+    if let Some((i, _)) = best {
+        let op = tokens[i];
+        if i + 1 == tokens.len() {
+            return Err((format!("Expected a value after operator '{}'.{}", op.content, pos!(op)), ExitReason::CompileExpectedValue));
+        }
+        return Ok(Expr::Binary {
+            op: op.content.clone(),
+            left: Box::new(parse_expr(&tokens[..i], opts, depth + 1)?),
+            right: Box::new(parse_expr(&tokens[i + 1..], opts, depth + 1)?),
+            span,
+        });
+    }
+    if first.strtype == StringType::Not && ["-", "!", "~"].contains(&first.content.as_str()) {
+        if tokens.len() == 1 {
+            return Err((format!("Expected a value after operator '{}'.{}", first.content, pos!(first)), ExitReason::CompileExpectedValue));
+        }
+        let operand = parse_expr(&tokens[1..], opts, depth + 1)?;
+        if let (true, Expr::Literal { vt: VarType::Num, value, .. }) = (first.content == "-", &operand) {
+            let negated = "-".to_string() + value;
+            return Ok(Expr::Literal { vt: VarType::Num, value: negated.trim_start_matches("--").to_string(), span });
+        }
+        return Ok(Expr::Unary { op: first.content.clone(), operand: Box::new(operand), span });
+    }
+    if first.strtype == StringType::Not && first.content == "(" {
+        match matching_close(tokens, 0) {
+            None => {
+                return Err((format!("Expected ')' to close '('.{}", pos!(first)), ExitReason::CompileUnexpectedToken));
+            }
+            Some(1) => {
+                return Err((format!("Expected a value between '(' and ')'.{}", pos!(first)), ExitReason::CompileExpectedValue));
+            }
+            Some(close) if close == tokens.len() - 1 => {
+                return parse_expr(&tokens[1..close], opts, depth + 1);
+            }
+            Some(close) => {
+                return Err((
+                    format!("Unexpected token '{}' after a complete value, is a ';' missing?{}", tokens[close + 1].content, pos!(tokens[close + 1])),
+                    ExitReason::CompileUnexpectedToken,
+                ));
+            }
+        }
+    }
+    if tokens.len() == 3 && is_ident(first) && tokens[1].content == "::" && is_ident(tokens[2]) {
+        return Ok(Expr::Variant { enum_name: first.content.clone(), variant: tokens[2].content.clone(), span });
+    }
+    if first.strtype == StringType::Not && first.content == "@" {
+        if tokens.len() == 2 && is_ident(tokens[1]) {
+            return Ok(Expr::Var { name: "@".to_string() + tokens[1].content.as_str(), span });
+        }
+        return Err((format!("Expected a name after '@'.{}", pos!(first)), ExitReason::CompileUnexpectedToken));
+    }
+    return parse_tokens(tokens, opts, depth);
+}
 
-    let mut cblock = Val { t: ValType::CodeBlock, args: Some(Vec::<Val>::new()), ..Default::default() };
-    let mut state = State::None;
-    let mut val_wip = Val::default();
-    let mut val_isnew = true;
-    let mut parenthesis_depth = 0;
-    let mut buffer = Vec::<&Token>::new();
-    let mut should_return_codeblock = false;
+/// Parses one statement, the tokens up to a `;`.
+fn parse_statement(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Stmt, (String, ExitReason)> {
+    macro_rules! pos {
+        ($tok:expr) => {
+            format!(" (line {}, col {})", $tok.line, $tok.col)
+        };
+    }
+    let first = tokens[0];
+    let span = token_span(tokens);
+    let is = |i: usize, content: &str| tokens.get(i).is_some_and(|x| x.strtype == StringType::Not && x.content == content);
+    if is(0, "return") {
+        let value = match tokens.len() {
+            1 => None,
+            _ => Some(parse_expr(&tokens[1..], opts, depth + 1)?),
+        };
+        return Ok(Stmt::Return { value, span });
+    }
+    if (is(0, "let") || is(0, "var")) && tokens.get(1).is_some_and(|x| is_ident(x)) {
+        let name = tokens[1];
+        let mut next = 2;
+        let mut ty = None;
+        if is(next, ":") && is(next + 1, "[") {
+            return parse_array(tokens, opts, depth);
+        }
+        if is(next, ":") {
+            ty = match tokens.get(next + 1).filter(|x| x.strtype == StringType::Not).and_then(|x| VarType::from_name(&x.content)) {
+                Some(t) => Some(t),
+                None => {
+                    return Err((
+                        format!("Expected a type after \"{}:\", one of Num, Str, Char, Content, Building, Unit, Team or Null.{}", name.content, pos!(tokens[next])),
+                        ExitReason::CompileUnknownType,
+                    ));
+                }
+            };
+            next += 2;
+        }
+        let value = match tokens.get(next) {
+            None => None,
+            Some(t) if !is(next, "=") => {
+                return Err((format!("Unexpected token '{}' in declaration of \"{}\".{}", t.content, name.content, pos!(t)), ExitReason::CompileUnexpectedToken));
+            }
+            Some(t) if next + 1 == tokens.len() => {
+                return Err((format!("Expected a value after '='.{}", pos!(t)), ExitReason::CompileExpectedValue));
+            }
+            Some(_) => Some(parse_expr(&tokens[next + 1..], opts, depth + 1)?),
+        };
+        return Ok(Stmt::Let { name: name.content.clone(), ty, value, global: first.content == "var", span });
+    }
+    if is(0, "let") || is(0, "var") {
+        return Err((format!("Expected a name after '{}'.{}", first.content, pos!(first)), ExitReason::CompileUnexpectedToken));
+    }
+    // the target is a variable, an element like `a[i]` or a field like `p.x`
+    let mut target_len = 1;
+    if is(1, "[") {
+        target_len = matching_close(tokens, 1).map_or(0, |x| x + 1);
+    }
+    while is(target_len, ".") && tokens.get(target_len + 1).is_some_and(|x| is_ident(x)) {
+        target_len += 2;
+    }
+    if target_len > 0 && tokens.len() > target_len && is_ident(first) && tokens[target_len].strtype == StringType::Not {
+        let op = tokens[target_len].content.as_str();
+        let target_expr = parse_expr(&tokens[..target_len], opts, depth + 1)?;
+        let target = || Box::new(target_expr.clone());
+        let value = if tokens.len() == target_len + 1 && (op == "++" || op == "--") {
+            let one = Expr::Literal { vt: VarType::Num, value: "1".to_string(), span };
+            Some(Expr::Binary { op: op[..1].to_string(), left: target(), right: Box::new(one), span })
+        }
+        else if ASSIGN_OPS.contains(&op) {
+            if tokens.len() == target_len + 1 {
+                return Err((
+                    format!("Expected a value after '{}'.{}", op, pos!(tokens[target_len])),
+                    ExitReason::CompileExpectedValue,
+                ));
+            }
+            let rhs = parse_expr(&tokens[target_len + 1..], opts, depth + 1)?;
+            match op {
+                "=" => Some(rhs),
+                _ => Some(Expr::Binary { op: op.strip_suffix('=').unwrap_or(op).to_string(), left: target(), right: Box::new(rhs), span }),
+            }
+        }
+        else {
+            None
+        };
+        match (value, target_expr) {
+            (Some(value), Expr::Index { name, index, .. }) => {
+                return Ok(Stmt::Store { name, index: *index, value, span });
+            }
+            (Some(value), Expr::Field { base, field, .. }) => {
+                return Ok(Stmt::SetField { base: *base, field, value, span });
+            }
+            (Some(value), _) => {
+                return Ok(Stmt::Assign { target: first.content.clone(), value, span });
+            }
+            _ => {}
+        }
+    }
+    return Ok(Stmt::Expr(parse_expr(tokens, opts, depth)?));
+}
 
-    macro_rules! flush {
-        () => {
-            cblock.args.as_mut().unwrap(/* safe unwrap */).push(val_wip);
-            val_wip = Val::default();
-            val_isnew = true;
+/// Parses `let name: [Type; len]`, optionally followed by `in` and the
+/// memory cell or bank holding the elements.
+fn parse_array(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Stmt, (String, ExitReason)> {
+    macro_rules! pos {
+        ($tok:expr) => {
+            format!(" (line {}, col {})", $tok.line, $tok.col)
         };
     }
+    let span = token_span(tokens);
+    let is = |i: usize, content: &str| tokens.get(i).is_some_and(|x| x.strtype == StringType::Not && x.content == content);
+    let name = tokens[1];
+    let close = match matching_close(tokens, 3) {
+        Some(c) => c,
+        None => {
+            return Err((format!("Expected ']' to close the array type of \"{}\".{}", name.content, pos!(tokens[3])), ExitReason::CompileUnexpectedToken));
+        }
+    };
+    let ty = match tokens.get(4).filter(|x| x.strtype == StringType::Not).and_then(|x| VarType::from_name(&x.content)) {
+        Some(t) => t,
+        None => {
+            return Err((
+                format!("Expected an element type after \"{}: [\", one of Num, Str, Char, Content, Building, Unit, Team or Null.{}", name.content, pos!(tokens[3])),
+                ExitReason::CompileUnknownType,
+            ));
+        }
+    };
+    if !is(5, ";") || close <= 6 {
+        return Err((format!("Expected '; length' after the element type of \"{}\".{}", name.content, pos!(tokens[4])), ExitReason::CompileExpectedValue));
+    }
+    let len = parse_expr(&tokens[6..close], opts, depth + 1)?;
+    let memory = match tokens.get(close + 1) {
+        None => None,
+        Some(t) if is(close + 1, "in") => match tokens.len() > close + 2 {
+            true => Some(parse_expr(&tokens[close + 2..], opts, depth + 1)?),
+            false => {
+                return Err((format!("Expected a memory cell or bank after \"in\".{}", pos!(t)), ExitReason::CompileExpectedValue));
+            }
+        },
+        Some(t) => {
+            return Err((format!("Unexpected token '{}' in declaration of \"{}\".{}", t.content, name.content, pos!(t)), ExitReason::CompileUnexpectedToken));
+        }
+    };
+    return Ok(Stmt::Array { name: name.content.clone(), ty, len, memory, global: tokens[0].content == "var", span });
+}
+
+/// Parses the body of a function or test, which may only hold statements.
+fn parse_body(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Vec<Stmt>, (String, ExitReason)> {
+    let mut body = Vec::<Stmt>::new();
+    for item in parse_block(tokens, opts, depth)? {
+        match item {
+            Item::Stmt(stmt) => body.push(stmt),
+            Item::Fn(Function { span, .. }) |
+            Item::Test(Test { span, .. }) |
+            Item::Const(Const { span, .. }) |
+            Item::Enum(Enum { span, .. }) |
+            Item::Struct(Struct { span, .. }) => {
+                return Err((
+                    format!("Functions, tests, constants, enums and structs can only be defined at the top level. (line {}, col {})", span.line, span.col),
+                    ExitReason::CompileBadItemDef,
+                ));
+            }
+        }
+    }
+    return Ok(body);
+}
+
+/// Index of the `;` ending the statement starting at `start`, or the end
+/// of `tokens` if there is none.
+fn statement_end(tokens: &[&Token], start: usize) -> Result<usize, (String, ExitReason)> {
+    return find_outside_brackets(tokens, start, ";");
+}
 
-    let mut first = true;
-    let mut i:usize = 0;
-    loop {
-        if !first {
+/// Index of the first `separator` from `start` on that is not inside
+/// brackets, or the end of `tokens` if there is none.
+fn find_outside_brackets(tokens: &[&Token], start: usize, separator: &str) -> Result<usize, (String, ExitReason)> {
+    let mut nesting = 0;
+    let mut end = start;
+    while end < tokens.len() {
+        let t = tokens[end];
+        if t.strtype == StringType::Not {
+            match t.content.as_str() {
+                s if s == separator && nesting == 0 => break,
+                "(" | "[" | "{" => nesting += 1,
+                ")" | "]" | "}" => nesting -= 1,
+                _ => {}
+            }
+        }
+        if nesting < 0 {
+            return Err((format!("Unexpected token '{}'. (line {}, col {})", t.content, t.line, t.col), ExitReason::CompileUnexpectedToken));
+        }
+        end += 1;
+    }
+    return Ok(end);
+}
+
+/// Parses the arms of a `match` block, `patterns => { body }` or
+/// `patterns => statement`, separated by commas.
+fn parse_arms(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Vec<Arm>, (String, ExitReason)> {
+    macro_rules! pos {
+        ($tok:expr) => {
+            format!(" (line {}, col {})", $tok.line, $tok.col)
+        };
+    }
+    let mut arms = Vec::<Arm>::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        if tokens[i].content == "," {
             i += 1;
+            continue;
         }
-        else {
-            first = false;
+        let start = i;
+        let arrow = find_outside_brackets(tokens, i, "=>")?;
+        if arrow == tokens.len() {
+            return Err((format!("Expected '=>' after the patterns of a match arm.{}", pos!(tokens[i])), ExitReason::CompileBadMatch));
         }
-        if i >= tokens.len() {
-            break;
+        let mut patterns = Vec::<Pattern>::new();
+        while i < arrow {
+            let end = find_outside_brackets(&tokens[..arrow], i, "|")?;
+            patterns.push(match &tokens[i..end] {
+                [] => {
+                    return Err((format!("Expected a pattern.{}", pos!(tokens[i])), ExitReason::CompileBadMatch));
+                }
+                [any] if any.strtype == StringType::Not && any.content == "_" => Pattern::Any,
+                pattern => Pattern::Value(parse_expr(pattern, opts, depth + 1)?),
+            });
+            i = end + 1;
+        }
+        if patterns.is_empty() {
+            return Err((format!("Expected a pattern before '=>'.{}", pos!(tokens[arrow])), ExitReason::CompileBadMatch));
         }
+        let body = match tokens.get(arrow + 1) {
+            Some(open) if open.strtype == StringType::Not && open.content == "{" => {
+                let close = match matching_close(tokens, arrow + 1) {
+                    Some(c) => c,
+                    None => {
+                        return Err((format!("Expected '}}' to close the match arm.{}", pos!(open)), ExitReason::CompileBadMatch));
+                    }
+                };
+                i = close + 1;
+                parse_body(&tokens[arrow + 2..close], opts, depth + 1)?
+            }
+            Some(_) => {
+                let end = find_outside_brackets(tokens, arrow + 1, ",")?;
+                i = end + 1;
+                vec![parse_statement(&tokens[arrow + 1..end], opts, depth + 1)?]
+            }
+            None => {
+                return Err((format!("Expected a statement after '=>'.{}", pos!(tokens[arrow])), ExitReason::CompileBadMatch));
+            }
+        };
+        arms.push(Arm { patterns, body, span: token_span(&tokens[start..i.min(tokens.len())]) });
+    }
+    return Ok(arms);
+}
+
+/// Parses a sequence of statements, `{ }` blocks, `match` statements and
+/// items (`fn`, `test`, `const`, `enum` and `struct` definitions). Other
+/// statements are declarations, assignments to variables, array elements
+/// and struct fields, `return` and calls. `## doc comments` are attached to
+/// the function after them.
+pub fn parse_block(tokens: &[&Token], opts: &HashMap<String, String>, depth: u64) -> Result<Vec<Item>, (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    macro_rules! pos {
+        ($tok:expr) => {
+            format!(" (line {}, col {})", $tok.line, $tok.col)
+        };
+    }
+    debug!("Begin block depth {}", depth);
+
+    let mut block = Vec::<Item>::new();
+    let mut doc = Vec::<String>::new();
+    let mut i = 0;
+    while i < tokens.len() {
         let token = tokens[i];
-        debug!("[Depth {}] Processing {}.", depth, token);
-        match state {
-            State::None => {
-                match token.content.as_str() {
-                    ";" => {
-                        flush!();
-                        should_return_codeblock = true;
+        if token.strtype == StringType::Doc {
+            doc.push(token.content.clone());
+            i += 1;
+            continue;
+        }
+        let keyword = if token.strtype == StringType::Not { token.content.as_str() } else { "" };
+        match keyword {
+            ";" => {
+                i += 1;
+            }
+            "{" => {
+                let close = match matching_close(tokens, i) {
+                    Some(c) => c,
+                    None => {
+                        return Err((format!("Expected '}}' to close the block.{}", pos!(token)), ExitReason::CompileUnexpectedToken));
                     }
-                    _ => {
-                        val_isnew = false;
-                        val_wip = Val { ident: Some(token.content.clone()), ..Default::default() };
-                        if token.strtype != StringType::Not {
-                            val_wip.t = ValType::Const;
-                            val_wip.vt = match token.strtype {
-                                StringType::Char => VarType::Char,
-                                _ => VarType::Str
-                            };
-                            state = State::ParseRemainder;
+                };
+                let body = parse_body(&tokens[i + 1..close], opts, depth + 1)?;
+                block.push(Item::Stmt(Stmt::Block { body, span: token_span(&tokens[i..=close]) }));
+                i = close + 1;
+            }
+            "fn" | "test" => {
+                let name = tokens.get(i + 1);
+                let name_ok = match (keyword, name) {
+                    ("fn", Some(name)) => is_ident(name),
+                    ("test", Some(name)) => name.strtype == StringType::String,
+                    _ => false,
+                };
+                if !name_ok {
+                    return Err((
+                        match keyword {
+                            "fn" => format!("Expected a function name after 'fn'.{}", pos!(token)),
+                            _ => format!("Expected a test name string after 'test'.{}", pos!(token)),
+                        },
+                        ExitReason::CompileBadItemDef,
+                    ));
+                }
+                let name = name.unwrap(/* checked above */);
+                let mut params = Vec::<Param>::new();
+                let mut next = i + 2;
+                if keyword == "fn" {
+                    let open = tokens.get(next).filter(|x| x.content == "(");
+                    let close = open.and_then(|_| matching_close(tokens, next));
+                    let close = match close {
+                        Some(c) => c,
+                        None => {
+                            return Err((format!("Expected '(' with parameters after \"fn {}\".{}", name.content, pos!(name)), ExitReason::CompileBadItemDef));
                         }
-                        else if is_num(&token.content) {
-                            val_wip.t = ValType::Const;
-                            val_wip.vt = VarType::Num;
-                            let def = &&Default::default();
-                            let maybe_dot = tokens.get(i+1).unwrap_or(def);
-                            if maybe_dot.content == "." {
-                                if !token.content.contains("e") {
-                                    debug!("[Depth {}] Processing {} as decimal point.", depth, maybe_dot);
-                                    let def = &&Default::default();
-                                    let remainder = tokens.get(i+2).unwrap_or(def);
-                                    let nextcont = &remainder.content;
-                                    if is_num(&nextcont) {
-                                        debug!("[Depth {}] Processing {} as number remainder.", depth, remainder);
-                                        let mut _discard = "".to_string();
-                                        *val_wip.ident.as_mut().unwrap_or(&mut _discard) += ".";
-                                        *val_wip.ident.as_mut().unwrap_or(&mut _discard) += nextcont.as_str();
-                                        i += 2;
-                                    }
-                                    else {
-                                        debug!("[Depth {}] Skipped {} as number remainder.", depth, remainder);
-                                    }
-                                }
-                                else {
-                                    debug!("[Depth {}] Skipped {} as decimal point.", depth, maybe_dot);
-                                }
-                            }
-                            state = State::ParseRemainder;
+                    };
+                    for (n, param) in tokens[next + 1..close].iter().enumerate() {
+                        let ok = if n % 2 == 0 { is_ident(param) } else { param.content == "," };
+                        if !ok {
+                            return Err((format!("Unexpected token '{}' in parameters of \"{}\".{}", param.content, name.content, pos!(param)), ExitReason::CompileBadItemDef));
                         }
-                        else {
-                            val_wip.t = ValType::Ident;
-                            state = State::PrevIsIdentifier;
+                        if n % 2 == 0 {
+                            params.push(Param { name: param.content.clone(), span: token_span(&[param]) });
                         }
                     }
+                    next = close + 1;
                 }
-            }
-            State::PrevIsIdentifier => {
-                match token.content.as_str() {
-                    "(" => {
-                        val_wip.t = ValType::FuncCall;
-                        val_wip.args = Some(Vec::<Val>::new());
-                        state = State::ParseArgs;
-                        parenthesis_depth = 1;
+                let close = match tokens.get(next).filter(|x| x.content == "{") {
+                    Some(_) => matching_close(tokens, next),
+                    None => None,
+                };
+                let close = match close {
+                    Some(c) => c,
+                    None => {
+                        return Err((format!("Expected a '{{' block after \"{} {}\".{}", keyword, name.content, pos!(name)), ExitReason::CompileBadItemDef));
                     }
-                    "!" => {
-                        let success;
-                        if let Some(next) = tokens.get(i+1) {
-                            if next.content == "(" {
-                                success = true;
-                                val_wip.t = ValType::MacroCall;
-                                val_wip.args = Some(Vec::<Val>::new());
-                                state = State::ParseArgs;
-                                parenthesis_depth = 1;
-                                i += 1;
-                            } else {
-                                success = false;
-                            }
-                        } else {
-                            success = false;
-                        }
-                        if !success {
-                            return Err((format!("Expected '(' after macro call \"{}!\".{}", val_wip.ident.unwrap_or("None".to_string()), pos!(token)), ExitReason::CompileExpectedParenthesisAfterMacro));
-                        }
+                };
+                let body = parse_body(&tokens[next + 1..close], opts, depth + 1)?;
+                let span = token_span(&tokens[i..=close]);
+                block.push(match keyword {
+                    "fn" => Item::Fn(Function {
+                        name: name.content.clone(),
+                        params,
+                        body,
+                        doc: (!doc.is_empty()).then(|| doc.join("\n")),
+                        span,
+                    }),
+                    _ => Item::Test(Test { name: name.content.clone(), body, span }),
+                });
+                i = close + 1;
+            }
+            "match" => {
+                let open = find_outside_brackets(tokens, i + 1, "{")?;
+                let close = match matching_close(tokens, open) {
+                    Some(c) => c,
+                    None => {
+                        return Err((format!("Expected a '{{' block with arms after \"match\".{}", pos!(token)), ExitReason::CompileBadMatch));
                     }
+                };
+                if open == i + 1 {
+                    return Err((format!("Expected a value after \"match\".{}", pos!(token)), ExitReason::CompileBadMatch));
+                }
+                let value = parse_expr(&tokens[i + 1..open], opts, depth + 1)?;
+                let arms = parse_arms(&tokens[open + 1..close], opts, depth + 1)?;
+                block.push(Item::Stmt(Stmt::Match { value, arms, span: token_span(&tokens[i..=close]) }));
+                i = close + 1;
+            }
+            "const" => {
+                let end = statement_end(tokens, i)?;
+                let name = match tokens.get(i + 1) {
+                    Some(name) if is_ident(name) && i + 2 < end && tokens[i + 2].content == "=" => name,
                     _ => {
-                        return Err((
-                            format!(
-                                "Unpexpected token {}'{}' after '{}', expected one of ['(', '!', '::', '=', '--', '++', '+=' , '-=', '*=', '/=', '//=', '^=', '=', '%=', '%%=', '<<=', '>>=', '>>>=', '&=', '|=', '&&=', '||='].{}",
-                                match token.strtype {
-                                    StringType::Not => "",
-                                    _ => "string ",
-                                },
-                                token.content,
-                                match val_wip.left { Some(x) => x.ident.unwrap_or(nonestr), None => nonestr},
-                                pos!(token)
-                            ),
-                            ExitReason::CompileBadTokenAfterIdentifier,
-                        ));
+                        return Err((format!("Expected \"const NAME = value;\".{}", pos!(token)), ExitReason::CompileBadItemDef));
                     }
+                };
+                if i + 3 == end {
+                    return Err((format!("Expected a value for constant \"{}\".{}", name.content, pos!(name)), ExitReason::CompileBadItemDef));
                 }
+                let value = parse_expr(&tokens[i + 3..end], opts, depth + 1)?;
+                block.push(Item::Const(Const { name: name.content.clone(), value, span: token_span(&tokens[i..end]) }));
+                i = end + 1;
             }
-            State::ParseArgs => {
-                macro_rules! flush_funcarg {
-                    () => {
-                        match val_wip.args.as_mut(){
-                            Some(v) => v,
-                            None => {
-                                return Err(("Unwrapping val_wip.args.as_mut() failed.".to_string(), ExitReason::CompileWipArgsUnwrapFailed));
-                            }
-                        }.push(match parse_tokens(&buffer, opts, depth+1){
-                            Ok(val) => {
-                                match val.t {
-                                    ValType::CodeBlock => {
-                                        return Err((format!("Function argument should be a value, not executable code.{}", pos!(token)), ExitReason::CompileFuncArgNotValue));
-                                    },
-                                    _ => val
-                                }
-                            },
-                            e => {
-                                return e;
-                            }
-                        });
-                    };
-                }
-                match token.content.as_str() {
-                    "(" => {
-                        parenthesis_depth += 1;
-                    }
-                    ")" => {
-                        parenthesis_depth -= 1;
+            "enum" | "struct" => {
+                let what = if keyword == "enum" { "variants" } else { "fields" };
+                let name = match tokens.get(i + 1) {
+                    Some(name) if is_ident(name) => name,
+                    _ => {
+                        return Err((format!("Expected a name after '{}'.{}", keyword, pos!(token)), ExitReason::CompileBadItemDef));
                     }
-                    "," => {
-                        if parenthesis_depth == 1 {
-                            flush_funcarg!();
-                            buffer.clear();
-                        }
-                        else {
-                            buffer.push(token);
-                        }
+                };
+                let close = match tokens.get(i + 2).filter(|x| x.content == "{") {
+                    Some(_) => matching_close(tokens, i + 2),
+                    None => None,
+                };
+                let close = match close {
+                    Some(c) => c,
+                    None => {
+                        return Err((
+                            format!("Expected a '{{' block with {} after \"{} {}\".{}", what, keyword, name.content, pos!(name)),
+                            ExitReason::CompileBadItemDef,
+                        ));
                     }
-                    _ => {
-                        buffer.push(token);
+                };
+                let mut names = Vec::<(String, Span)>::new();
+                for (n, member) in tokens[i + 3..close].iter().enumerate() {
+                    let ok = if n % 2 == 0 { is_ident(member) } else { member.content == "," };
+                    if !ok {
+                        return Err((format!("Unexpected token '{}' in {} of \"{}\".{}", member.content, what, name.content, pos!(member)), ExitReason::CompileBadItemDef));
                     }
-                }
-                if parenthesis_depth == 0 {
-                    if buffer.len() > 0 {
-                        flush_funcarg!();
+                    if n % 2 == 0 {
+                        names.push((member.content.clone(), token_span(&[member])));
                     }
-                    state = State::None;
-                    buffer.clear();
                 }
+                let span = token_span(&tokens[i..=close]);
+                block.push(match keyword {
+                    "enum" => Item::Enum(Enum {
+                        name: name.content.clone(),
+                        variants: names.into_iter().map(|(name, span)| Variant { name, span }).collect(),
+                        span,
+                    }),
+                    _ => Item::Struct(Struct {
+                        name: name.content.clone(),
+                        fields: names.into_iter().map(|(name, span)| Field { name, span }).collect(),
+                        span,
+                    }),
+                });
+                i = close + 1;
             }
-            State::ParseRemainder => {
-                err!("not implemented");
+            _ => {
+                let end = statement_end(tokens, i)?;
+                block.push(Item::Stmt(parse_statement(&tokens[i..end], opts, depth + 1)?));
+                i = end + 1;
             }
         }
-        if
-            token.strtype == StringType::Char &&
-            !(
-                token.content.clone().char_indices().count() == 1 ||
-                token.content.starts_with("\\u")
-            )
-        {
-            return Err((
-                format!(
-                    "Char '{}' should be 1 character long, but is {}.{}",
-                    token.content,
-                    token.content.len(),
-                    pos!(token)
-                ),
-                ExitReason::CompileCharTooLong,
-            ));
-        }
-    }
-    if !val_isnew {
-        cblock.args.as_mut().unwrap().push(val_wip);
-    }
-    if (!should_return_codeblock) && cblock.args.as_ref().unwrap().len() == 1 {
-        cblock = cblock.args.as_mut().unwrap().remove(0);
-        debug!("[Depth {}] Returning Val of type {:?} instead of CodeBlock.", depth, cblock.t);
     }
 
-    debug!("End depth {}", depth);
-    return Ok(cblock);
+    debug!("End block depth {}", depth);
+    return Ok(block);
 }
 
-pub fn compile(
-    args: &Vec<String>,
-    opts: &HashMap<String, String>
-) -> Result<(), (String, ExitReason)> {
+/// Reads, tokenizes and parses a source file into its `Module`.
+pub fn parse_file(path: &str, opts: &HashMap<String, String>) -> Result<Module, (String, ExitReason)> {
     macro_rules! opts {
         () => {
             &opts
         };
     }
-    if args.len() < 3 {
-        return Err((
-            "Command \"compile\" expected 1 argument. 0 were provided.".to_string(),
-            ExitReason::CommandExpectedInputArgument,
-        ));
-    }
     info!("Reading file");
-    let file = match read_to_string(&args[2]) {
+    let file = match read_to_string(path) {
         Ok(f) => f,
         Err(e) => {
             if e.kind() == ErrorKind::NotFound {
-                return Err((format!("File \"{}\" not found.", &args[2]), ExitReason::CompileFileNotFound));
+                return Err((format!("File \"{}\" not found.", path), ExitReason::CompileFileNotFound));
             }
             else {
-                return Err((format!("Reading file \"{}\" failed. Error: {}", &args[2], e.kind()), ExitReason::CompileFileNotFound));
+                return Err((format!("Reading file \"{}\" failed. Error: {}", path, e.kind()), ExitReason::CompileFileNotFound));
             }
         }
     };
     info!("File read success");
+    return parse_source(file, opts);
+}
+
+/// Parses the source text of a program.
+pub fn parse_source(file: String, opts: &HashMap<String, String>) -> Result<Module, (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
     info!("Begin tokenize");
-    let tokens = tokenize::tokenize(file);
+    let mut tokens = tokenize::tokenize(file)?;
+    check_reserved(&tokens)?;
     info!("Tokenize success");
+    // doc comments are only kept for the function they come before
+    let mut keep = Vec::<bool>::new();
+    let mut next_is_fn = false;
+    for token in tokens.iter().rev() {
+        if token.strtype != StringType::Doc {
+            next_is_fn = token.strtype == StringType::Not && token.content == "fn";
+        }
+        keep.push(token.strtype != StringType::Doc || next_is_fn);
+    }
+    tokens.retain(|token| {
+        let kept = keep.pop().unwrap_or(true);
+        if !kept {
+            cwarn!("Doc comment is not followed by a function and is ignored. (line {}, col {})", token.line, token.col);
+        }
+        return kept;
+    });
     debug!("------ All tokens:");
     for token in &tokens {
         debug!("{}", token);
     }
     debug!("------");
     info!("Parse tokens (first pass)");
-    let root = match parse_tokens(&tokens.iter().collect(), opts, 0) {
-        Ok(t) => t,
-        Err(e) => { return Err(e); }
-    };
+    let root = Module { items: parse_block(&tokens.iter().collect::<Vec<_>>(), opts, 0)? };
     debug!("{}", root);
+    return Ok(root);
+}
+
+/// Writes the source map of a compiled program as JSON: for every mlog
+/// instruction the range of source text it was generated from. Generated
+/// instructions without a source have every position set to 0.
+fn source_map(source: &str, mlog: &str, positions: &[Span]) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut out = format!("{{\n  \"version\": 1,\n  \"source\": {},\n  \"mlog\": {},\n  \"instructions\": [", quote(source), quote(mlog));
+    for (i, span) in positions.iter().enumerate() {
+        out += if i == 0 { "\n" } else { ",\n" };
+        out += format!(
+            "    {{ \"index\": {}, \"line\": {}, \"col\": {}, \"end_line\": {}, \"end_col\": {} }}",
+            i, span.line, span.col, span.end_line, span.end_col
+        ).as_str();
+    }
+    out += "\n  ]\n}\n";
+    return out;
+}
+
+pub fn compile(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"compile\" expected 1 argument. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let emit = opts.get("emit").map(|x| x.as_str()).unwrap_or("mlog");
+    if !["ast", "ir", "mlog"].contains(&emit) {
+        return Err((format!("Option \"--emit\" expected ast, ir or mlog, got \"{}\".", emit), ExitReason::OptionBadValue));
+    }
+    let root = parse_file(&args[2], opts)?;
+    let out_path = match opts.get("out-file") {
+        Some(path) => path.clone(),
+        None => Path::new(&args[2]).with_extension(emit).to_string_lossy().to_string(),
+    };
+    if Path::new(&out_path) == Path::new(&args[2]) {
+        return Err((
+            format!("Output file \"{}\" is the same as the input file. Use --out-file to pick another.", out_path),
+            ExitReason::CompileOutputConflict,
+        ));
+    }
+    if emit == "ast" {
+        if let Err(e) = write(&out_path, root.to_string()) {
+            return Err((format!("Writing file \"{}\" failed. Error: {}", out_path, e.kind()), ExitReason::CompileWriteFailed));
+        }
+        ok!("Wrote the syntax tree of \"{}\" to \"{}\"", args[2], out_path);
+        return Ok(());
+    }
+    info!("Generate code");
+    let generated = codegen::generate(&root, opts)?;
+    if emit == "ir" {
+        if let Err(e) = write(&out_path, generated.ir.to_string()) {
+            return Err((format!("Writing file \"{}\" failed. Error: {}", out_path, e.kind()), ExitReason::CompileWriteFailed));
+        }
+        ok!("Wrote the IR of \"{}\" to \"{}\"", args[2], out_path);
+        return Ok(());
+    }
+    if let Err(e) = write(&out_path, &generated.mlog) {
+        return Err((format!("Writing file \"{}\" failed. Error: {}", out_path, e.kind()), ExitReason::CompileWriteFailed));
+    }
+    if argparse::flag_set(opts, "source-map") {
+        let map_path = out_path.clone() + ".map";
+        if let Err(e) = write(&map_path, source_map(&args[2], &out_path, &generated.positions)) {
+            return Err((format!("Writing file \"{}\" failed. Error: {}", map_path, e.kind()), ExitReason::CompileWriteFailed));
+        }
+        info!("Wrote source map to \"{}\"", map_path);
+    }
+    if argparse::flag_set(opts, "minify-names") {
+        let names_path = out_path.clone() + ".names";
+        let text = generated.names.iter().map(|(short, name)| format!("{} = {}\n", short, name)).collect::<String>();
+        if let Err(e) = write(&names_path, text) {
+            return Err((format!("Writing file \"{}\" failed. Error: {}", names_path, e.kind()), ExitReason::CompileWriteFailed));
+        }
+        info!("Wrote name map to \"{}\"", names_path);
+    }
+    ok!("Compiled \"{}\" to \"{}\"", args[2], out_path);
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ compile, error, opts, run };
+
+    fn positions(source: &str) -> Vec<(u64, u64, u64, u64)> {
+        let opts = opts(&[("opt-level", "0")]);
+        let generated = codegen::generate(&parse_source(source.to_string(), &opts).unwrap(), &opts).unwrap();
+        return generated.positions.iter().map(|x| (x.line, x.col, x.end_line, x.end_col)).collect();
+    }
+
+    #[test]
+    fn instructions_map_to_their_expression() {
+        assert_eq!(positions("let a = @links;\n  let b = a * 2 + 1;"), vec![(1, 9, 1, 14), (2, 11, 2, 15), (2, 11, 2, 19)]);
+    }
+
+    #[test]
+    fn added_end_has_no_position() {
+        let map = positions("fn f(x) { match x { 0 => { return 1; } _ => {} } return 2; }\nlet a = f(@links);\nmatch a { 1 => { print(a); } _ => {} }");
+        assert_eq!(map.last(), Some(&(0, 0, 0, 0)));
+        assert!(map[..map.len() - 1].iter().all(|x| x.0 > 0));
+    }
+
+    #[test]
+    fn source_map_json() {
+        let spans = [Span { line: 1, col: 2, end_line: 3, end_col: 4 }, Span::default()];
+        let json = source_map("dir\\a \"b\".src", "a.mlog", &spans);
+        assert_eq!(json, "{\n  \"version\": 1,\n  \"source\": \"dir\\\\a \\\"b\\\".src\",\n  \"mlog\": \"a.mlog\",\n  \"instructions\": [
+    { \"index\": 0, \"line\": 1, \"col\": 2, \"end_line\": 3, \"end_col\": 4 },
+    { \"index\": 1, \"line\": 0, \"col\": 0, \"end_line\": 0, \"end_col\": 0 }\n  ]\n}\n");
+        assert_eq!(source_map("a.src", "a.mlog", &[]), "{\n  \"version\": 1,\n  \"source\": \"a.src\",\n  \"mlog\": \"a.mlog\",\n  \"instructions\": [\n  ]\n}\n");
+    }
+
+    #[test]
+    fn match_syntax_errors() {
+        let bad_match = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadMatch, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_match("let x = 1;\nmatch x { 1 print(x); }"), "Expected '=>' after the patterns of a match arm. (line 2, col 11)");
+        assert_eq!(bad_match("let x = 1;\nmatch x { | 1 => {} }"), "Expected a pattern. (line 2, col 11)");
+        assert_eq!(bad_match("let x = 1;\nmatch x { 1 => }"), "Expected a statement after '=>'. (line 2, col 13)");
+        assert_eq!(bad_match("let x = 1;\nmatch x { 1 => { print(x); }"), "Expected a '{' block with arms after \"match\". (line 2, col 1)");
+    }
+
+    #[test]
+    fn array_syntax_errors() {
+        assert_eq!(error("let r: [foo; 3];"), (
+            "Expected an element type after \"r: [\", one of Num, Str, Char, Content, Building, Unit, Team or Null. (line 1, col 8)".to_string(),
+            ExitReason::CompileUnknownType,
+        ));
+        assert_eq!(error("let r: [num 3];").0, "Expected '; length' after the element type of \"r\". (line 1, col 9)");
+        assert_eq!(error("let r: [num; 3] in;").0, "Expected a memory cell or bank after \"in\". (line 1, col 17)");
+        assert_eq!(error("let r: [num; 3] x;").0, "Unexpected token 'x' in declaration of \"r\". (line 1, col 17)");
+        assert_eq!(error("let r: [num; 3;").0, "Expected ']' to close the array type of \"r\". (line 1, col 8)");
+    }
+
+    #[test]
+    fn string_escapes() {
+        let source = "print(\"a\\tb\\\\c \\\"q\\\" \\u{41}\\u0042 [red]x[] [[y\");\nprintflush(message1);";
+        assert_eq!(run(source, ""), "a\tb\\c \"q\" AB [red]x[] [[y");
+        assert_eq!(run("print(\"line1\\nline2\");\nprintflush(message1);", ""), "line1\nline2");
+        // unknown color tags are only warned about
+        assert_eq!(compile("print(\"[blu]x[#ggg]\");", &[]).unwrap(), "print \"[blu]x[#ggg]\"\n");
+    }
+
+    #[test]
+    fn strings_mlog_cannot_hold() {
+        // quotes and a backslash before an `n` are printed by character code
+        let mlog = compile("print(\"say \\\"hi\\\"\");\nprint(\"a \\\\n b\");", &[]).unwrap();
+        assert_eq!(mlog, "print \"say \"\nprintchar 34\nprint \"hi\"\nprintchar 34\nprint \"a \"\nprintchar 92\nprint \"n b\"\n");
+        assert_eq!(run("print(\"a \\\\n b\");\nprintflush(message1);", ""), "a \\n b");
+        assert_eq!(error("let s = \"say \\\"hi\\\"\";\nprint(s);").0, "mlog strings cannot hold '\"' or '\\' before 'n', they can only be printed. (line 1, col 9)");
+    }
+
+    #[test]
+    fn bad_escapes() {
+        let bad_escape = |source: &str| {
+            let (message, reason) = error(source);
+            assert_eq!(reason, ExitReason::CompileBadEscape, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_escape("print(\"a\\qb\");"), "Unknown escape '\\q', expected one of \\n, \\t, \\\\, \\\", \\' or \\u{...}. (line 1, col 9)");
+        assert_eq!(bad_escape("print(\"x\n  \\z\");"), "Unknown escape '\\z', expected one of \\n, \\t, \\\\, \\\", \\' or \\u{...}. (line 2, col 3)");
+        for escape in ["\\u{110000}", "\\u12", "\\u{41", "\\u{}", "\\u{12345678}"] {
+            assert_eq!(bad_escape(&format!("print(\"ab{}\");", escape)), "Expected a unicode escape like \\u{41} or \\u0041. (line 1, col 10)");
+        }
+    }
+
+    #[test]
+    fn number_literals() {
+        let source = "let a = 0xff_ff; let b = 0b101; let c = 1_000_000; let d = 1e-3; let e = .5; let f = 1.5e3;\nlet g = %FF0000; let h = %ff000080; let i = 2E+2; let j = 1e20; let k = -5; let l = 1e-5;\nprint(a); print(b); print(c); print(d); print(e); print(f); print(g); print(h); print(i); print(j); print(k); print(l);";
+        let mlog = compile(source, &[("opt-level", "2")]).unwrap();
+        assert_eq!(mlog, "print 65535\nprint 5\nprint 1000000\nprint 0.001\nprint 0.5\nprint 1500\nprint %ff0000\nprint %ff000080\nprint 200\nprint 1e20\nprint -5\nprint 1e-5\n");
+        // `%` after a value is the remainder, after a keyword a color
+        assert_eq!(compile("let x = @links;\nprint(x %2);", &[("opt-level", "0")]).unwrap(), "set x @links\nop mod __t0 x 2\nprint __t0\n");
+        assert_eq!(compile("fn f() { return %ff0000; }\nprint(f());", &[]).unwrap(), "print %ff0000\n");
+    }
+
+    #[test]
+    fn bad_numbers() {
+        let bad_number = |number: &str| {
+            let (message, reason) = error(&format!("let a = {};\nprint(a);", number));
+            assert_eq!(reason, ExitReason::CompileBadNumber, "{}", message);
+            return message;
+        };
+        assert_eq!(bad_number("0x"), "Invalid number \"0x\": expected hexadecimal digits after \"0x\". (line 1, col 9)");
+        assert_eq!(bad_number("0xfg"), "Invalid number \"0xfg\": expected hexadecimal digits after \"0x\". (line 1, col 9)");
+        assert_eq!(bad_number("0b102"), "Invalid number \"0b102\": expected binary digits after \"0b\". (line 1, col 9)");
+        assert_eq!(bad_number("1e"), "Invalid number \"1e\": expected digits after the exponent. (line 1, col 9)");
+        assert_eq!(bad_number("1e999"), "Invalid number \"1e999\": it is too large. (line 1, col 9)");
+        assert_eq!(bad_number("0x20000000000000"), "Invalid number \"0x20000000000000\": it is too large to be stored exactly. (line 1, col 9)");
+        assert_eq!(bad_number("%ff00"), "Invalid color \"%ff00\": expected 6 or 8 hexadecimal digits like %ff0000 or %ff000080. (line 1, col 9)");
+        assert_eq!(bad_number("%gg0000"), "Invalid color \"%gg0000\": expected 6 or 8 hexadecimal digits like %ff0000 or %ff000080. (line 1, col 9)");
+        assert_eq!(bad_number("%ff0000ff00"), "Invalid color \"%ff0000ff00\": expected 6 or 8 hexadecimal digits like %ff0000 or %ff000080. (line 1, col 9)");
+    }
+
+    #[test]
+    fn chars_are_codepoints() {
+        let mlog = compile("print('A');\nprintchar('\\n');\nprintchar('\\u{e800}');\nprint('\\'');", &[]).unwrap();
+        assert_eq!(mlog, "print 65\nprintchar 10\nprintchar 59392\nprint 39\n");
+        assert_eq!(compile("let c = 'A';\nlet x = c + 1;\nprint(x);", &[("opt-level", "2")]).unwrap(), "print 66\n");
+        assert!(compile("match @links { 'A' => { print(1); } _ => {} }", &[]).unwrap().starts_with("jump 2 equal @links 65\n"));
+    }
+
+    #[test]
+    fn bad_chars() {
+        assert_eq!(error("let c = 'ab';"), ("Char 'ab' should be 1 character long, but is 2. (line 1, col 9)".to_string(), ExitReason::CompileCharTooLong));
+        assert_eq!(error("let c = '';"), ("Char '' should be 1 character long, but is 0. (line 1, col 9)".to_string(), ExitReason::CompileCharTooLong));
+        assert_eq!(error("let c = '\\u{1F600}';"), ("Char '\\u{1F600}' is U+1F600, but only characters up to U+FFFF can be printed. (line 1, col 9)".to_string(), ExitReason::CompileBadChar));
+        assert_eq!(error("let c = '😀';").0, "Char '😀' is U+1F600, but only characters up to U+FFFF can be printed. (line 1, col 9)");
+        // empty strings are fine
+        assert_eq!(compile("let s = \"\";\nprint(s);", &[("opt-level", "0")]).unwrap(), "set s \"\"\nprint s\n");
+    }
+
+    #[test]
+    fn reserved_names() {
+        // generated names could otherwise be read or overwritten
+        let source = "fn f(a) { return a; }\nlet __f0_a = 7;\nprint(f(1));\nprint(__f0_a);";
+        assert_eq!(error(source), ("Names starting with '__' are reserved: '__f0_a'. (line 2, col 5)".to_string(), ExitReason::CompileReservedName));
+        assert_eq!(error("let x = 1;\nmlog!(`print {__t0}`);").0, "Names starting with '__' are reserved: '__t0'. (line 2, col 15)");
+        assert_eq!(run("let _a = 1;\nprint(_a);\nprintflush(message1);", ""), "1");
+    }
+}
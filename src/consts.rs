@@ -0,0 +1,327 @@
+//! Compile-time constants and enums. `const NAME = value;` is computed
+//! while compiling, following the emulator's rules, and every use of the
+//! name is replaced with the result, so constants never take a `set`.
+//! Enum variants are numbered from 0 and replaced the same way.
+
+use std::collections::HashMap;
+use crate::ast::{ Arm, Expr, Item, Module, Pattern, Span, Stmt, VarType };
+use crate::codegen;
+use crate::emulator::{ self, OpKind, Value };
+use crate::optimize;
+use crate::*;
+
+type ConstResult<T> = Result<T, (String, ExitReason)>;
+
+macro_rules! pos {
+    ($span:expr) => {
+        format!(" (line {}, col {})", $span.line, $span.col)
+    };
+}
+
+struct Consts {
+    values: HashMap<String, Value>,
+    enums: HashMap<String, Vec<String>>,
+}
+
+impl Consts {
+    fn variant(&self, enum_name: &str, variant: &str, span: Span) -> ConstResult<Value> {
+        let variants = match self.enums.get(enum_name) {
+            Some(v) => v,
+            None => {
+                return Err((format!("Unknown enum \"{}\".{}", enum_name, pos!(span)), ExitReason::CompileUnknownVariant));
+            }
+        };
+        return match variants.iter().position(|x| x == variant) {
+            Some(idx) => Ok(Value::Num(idx as f64)),
+            None => Err((format!("Enum \"{}\" has no variant \"{}\".{}", enum_name, variant, pos!(span)), ExitReason::CompileUnknownVariant)),
+        };
+    }
+
+    /// Computes the value of a constant's expression.
+    fn eval(&self, expr: &Expr) -> ConstResult<Value> {
+        let not_constant = |what: String| (
+            format!("{} is not known at compile time.{}", what, pos!(expr.span())),
+            ExitReason::CompileNotConstant,
+        );
+        let kind = |name: &str| OpKind::parse(name).ok_or((
+            format!("Unknown operator '{}'.{}", name, pos!(expr.span())),
+            ExitReason::CompileUnknownOperator,
+        ));
+        match expr {
+            Expr::Literal { vt: VarType::Num, value, .. } => {
+                return match emulator::parse_literal(value) {
+                    Some(v) => Ok(v),
+                    None => Err(not_constant(format!("\"{}\"", value))),
+                };
+            }
+            Expr::Literal { value, .. } => {
+                return Ok(Value::Str(value.clone()));
+            }
+            Expr::Var { name, .. } => {
+                if let Some(value) = self.values.get(name) {
+                    return Ok(value.clone());
+                }
+                return match emulator::parse_literal(name) {
+                    Some(v) if name.starts_with('@') || ["true", "false", "null"].contains(&name.as_str()) => Ok(v),
+                    _ => Err(not_constant(format!("\"{}\"", name))),
+                };
+            }
+            Expr::Variant { enum_name, variant, span } => {
+                return self.variant(enum_name, variant, *span);
+            }
+            Expr::Binary { op, left, right, .. } => {
+                let (a, b) = (self.eval(left)?, self.eval(right)?);
+                if op == "!==" {
+                    return Ok(Value::from_bool(emulator::eval_op(OpKind::StrictEqual, &a, &b).num() == 0.0));
+                }
+                let name = codegen::binop_name(op).unwrap_or(op);
+                return Ok(emulator::eval_op(kind(name)?, &a, &b));
+            }
+            Expr::Unary { op, operand, .. } => {
+                let a = self.eval(operand)?;
+                return Ok(match op.as_str() {
+                    "-" => emulator::eval_op(OpKind::Sub, &Value::Num(0.0), &a),
+                    "!" => emulator::eval_op(OpKind::Equal, &a, &Value::Num(0.0)),
+                    _ => emulator::eval_op(OpKind::Not, &a, &Value::Null),
+                });
+            }
+            Expr::Call { name, args, .. } if name != "rand" && name != "noise" && codegen::MATH.iter().any(|x| x.0 == name && x.1 == args.len()) => {
+                let a = self.eval(&args[0])?;
+                let b = match args.get(1) {
+                    Some(arg) => self.eval(arg)?,
+                    None => Value::Null,
+                };
+                return Ok(emulator::eval_op(kind(name)?, &a, &b));
+            }
+            Expr::Call { name, args, .. } if name == "packcolor" && args.len() == 4 => {
+                let mut c = Vec::<f64>::new();
+                for arg in args {
+                    c.push(self.eval(arg)?.num());
+                }
+                return Ok(Value::Num(emulator::pack_color(c[0], c[1], c[2], c[3])));
+            }
+            Expr::Call { name, .. } => {
+                return Err(not_constant(format!("The result of \"{}\"", name)));
+            }
+            Expr::Macro { name, .. } => {
+                return Err(not_constant(format!("The result of \"{}!\"", name)));
+            }
+            Expr::Index { name, .. } => {
+                return Err(not_constant(format!("An element of \"{}\"", name)));
+            }
+            Expr::Struct { name, .. } => {
+                return Err(not_constant(format!("A value of struct \"{}\"", name)));
+            }
+            Expr::Field { field, .. } => {
+                return Err(not_constant(format!("Field \"{}\"", field)));
+            }
+        }
+    }
+
+    /// Writes a computed value back as an expression.
+    fn to_expr(&self, value: &Value, span: Span) -> ConstResult<Expr> {
+        return Ok(match value {
+            Value::Null => Expr::Var { name: "null".to_string(), span },
+            Value::Str(s) => Expr::Literal { vt: VarType::Str, value: s.clone(), span },
+            Value::Content(c) => Expr::Var { name: format!("@{}", c), span },
+            Value::Num(_) => match optimize::to_literal(value) {
+                Some(v) => Expr::Literal { vt: VarType::Num, value: v, span },
+                None => {
+                    return Err((format!("Constant value {:?} cannot be written in mlog.{}", value, pos!(span)), ExitReason::CompileNotConstant));
+                }
+            },
+            Value::Building(_) => {
+                return Err((format!("A building is not known at compile time.{}", pos!(span)), ExitReason::CompileNotConstant));
+            }
+        });
+    }
+
+    fn expr(&self, expr: &Expr) -> ConstResult<Expr> {
+        return Ok(match expr {
+            Expr::Literal { .. } => expr.clone(),
+            Expr::Var { name, span } => match self.values.get(name) {
+                Some(value) => self.to_expr(value, *span)?,
+                None => expr.clone(),
+            },
+            Expr::Variant { enum_name, variant, span } => self.to_expr(&self.variant(enum_name, variant, *span)?, *span)?,
+            Expr::Binary { op, left, right, span } => Expr::Binary {
+                op: op.clone(),
+                left: Box::new(self.expr(left)?),
+                right: Box::new(self.expr(right)?),
+                span: *span,
+            },
+            Expr::Unary { op, operand, span } => Expr::Unary { op: op.clone(), operand: Box::new(self.expr(operand)?), span: *span },
+            Expr::Call { name, args, span } => Expr::Call { name: name.clone(), args: self.exprs(args)?, span: *span },
+            Expr::Macro { name, args, span } => Expr::Macro { name: name.clone(), args: self.exprs(args)?, span: *span },
+            Expr::Index { name, index, span } => Expr::Index { name: name.clone(), index: Box::new(self.expr(index)?), span: *span },
+            Expr::Struct { name, fields, span } => {
+                let mut values = Vec::<(String, Expr)>::new();
+                for (field, value) in fields {
+                    values.push((field.clone(), self.expr(value)?));
+                }
+                Expr::Struct { name: name.clone(), fields: values, span: *span }
+            }
+            Expr::Field { base, field, span } => Expr::Field { base: Box::new(self.expr(base)?), field: field.clone(), span: *span },
+        });
+    }
+
+    fn exprs(&self, exprs: &[Expr]) -> ConstResult<Vec<Expr>> {
+        return exprs.iter().map(|x| self.expr(x)).collect();
+    }
+
+    fn target(&self, name: &str, span: Span) -> ConstResult<()> {
+        if self.values.contains_key(name) {
+            return Err((format!("Cannot assign to constant \"{}\".{}", name, pos!(span)), ExitReason::CompileAssignToConst));
+        }
+        return Ok(());
+    }
+
+    /// Rejects a `let` named like a constant. Scoped variables were renamed
+    /// to `name:N` by the resolver, so the suffix is ignored: declaring the
+    /// name in a block is an error just like at the top level.
+    fn declare(&self, name: &str, span: Span) -> ConstResult<()> {
+        let source = name.split(':').next().unwrap_or(name);
+        if self.values.contains_key(source) {
+            return Err((format!("Cannot declare \"{}\", a constant has that name.{}", source, pos!(span)), ExitReason::CompileAssignToConst));
+        }
+        return Ok(());
+    }
+
+    fn block(&self, body: &[Stmt]) -> ConstResult<Vec<Stmt>> {
+        let mut out = Vec::<Stmt>::new();
+        for stmt in body {
+            out.push(match stmt {
+                Stmt::Assign { target, value, span } => {
+                    self.target(target, *span)?;
+                    Stmt::Assign { target: target.clone(), value: self.expr(value)?, span: *span }
+                }
+                Stmt::Let { name, ty, value, global, span } => {
+                    self.declare(name, *span)?;
+                    let value = match value {
+                        Some(v) => Some(self.expr(v)?),
+                        None => None,
+                    };
+                    Stmt::Let { name: name.clone(), ty: *ty, value, global: *global, span: *span }
+                }
+                Stmt::Array { name, ty, len, memory, global, span } => {
+                    self.declare(name, *span)?;
+                    let memory = match memory {
+                        Some(m) => Some(self.expr(m)?),
+                        None => None,
+                    };
+                    let len = self.to_expr(&self.eval(len)?, len.span())?;
+                    Stmt::Array { name: name.clone(), ty: *ty, len, memory, global: *global, span: *span }
+                }
+                Stmt::Store { name, index, value, span } => {
+                    self.target(name, *span)?;
+                    Stmt::Store { name: name.clone(), index: self.expr(index)?, value: self.expr(value)?, span: *span }
+                }
+                Stmt::SetField { base, field, value, span } => {
+                    Stmt::SetField { base: self.expr(base)?, field: field.clone(), value: self.expr(value)?, span: *span }
+                }
+                Stmt::Block { body, span } => Stmt::Block { body: self.block(body)?, span: *span },
+                Stmt::Match { value, arms, span } => {
+                    let mut resolved = Vec::<Arm>::new();
+                    for arm in arms {
+                        let mut patterns = Vec::<Pattern>::new();
+                        for pattern in &arm.patterns {
+                            patterns.push(match pattern {
+                                // patterns are compared with the value, so they must be known
+                                Pattern::Value(expr) => Pattern::Value(self.to_expr(&self.eval(expr)?, expr.span())?),
+                                Pattern::Any => Pattern::Any,
+                            });
+                        }
+                        resolved.push(Arm { patterns, body: self.block(&arm.body)?, span: arm.span });
+                    }
+                    Stmt::Match { value: self.expr(value)?, arms: resolved, span: *span }
+                }
+                Stmt::Return { value: Some(value), span } => Stmt::Return { value: Some(self.expr(value)?), span: *span },
+                Stmt::Return { value: None, .. } => stmt.clone(),
+                Stmt::Expr(expr) => Stmt::Expr(self.expr(expr)?),
+            });
+        }
+        return Ok(out);
+    }
+}
+
+/// Computes every constant of `root` in the order they are defined and
+/// replaces their uses, and those of enum variants, with the values.
+pub fn inline(root: &Module) -> ConstResult<Module> {
+    let mut consts = Consts { values: HashMap::<String, Value>::new(), enums: HashMap::<String, Vec<String>>::new() };
+    for enumeration in root.enums() {
+        let variants = enumeration.variants.iter().map(|x| x.name.clone()).collect::<Vec<_>>();
+        if let Some(dup) = enumeration.variants.iter().enumerate().find(|(i, x)| variants[..*i].contains(&x.name)) {
+            return Err((format!("Variant \"{}\" is defined twice.{}", dup.1.name, pos!(dup.1.span)), ExitReason::CompileDuplicateItem));
+        }
+        if consts.enums.insert(enumeration.name.clone(), variants).is_some() {
+            return Err((format!("Enum \"{}\" is defined twice.{}", enumeration.name, pos!(enumeration.span)), ExitReason::CompileDuplicateItem));
+        }
+    }
+    for constant in root.consts() {
+        let value = consts.eval(&constant.value)?;
+        if consts.values.insert(constant.name.clone(), value).is_some() {
+            return Err((format!("Constant \"{}\" is defined twice.{}", constant.name, pos!(constant.span)), ExitReason::CompileDuplicateItem));
+        }
+    }
+
+    let mut items = Vec::<Item>::new();
+    for item in &root.items {
+        items.push(match item {
+            Item::Stmt(stmt) => Item::Stmt(consts.block(std::slice::from_ref(stmt))?.remove(0)),
+            Item::Fn(func) => {
+                let mut func = func.clone();
+                func.body = consts.block(&func.body)?;
+                Item::Fn(func)
+            }
+            Item::Test(test) => {
+                let mut test = test.clone();
+                test.body = consts.block(&test.body)?;
+                Item::Test(test)
+            }
+            Item::Const(_) | Item::Enum(_) | Item::Struct(_) => item.clone(),
+        });
+    }
+    return Ok(Module { items });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ compile, error };
+
+    #[test]
+    fn values_are_computed_and_inlined() {
+        assert_eq!(compile("const W = 176;\nconst H = W / 2 + 1;\nprint(H);", &[]).unwrap(), "print 89\n");
+        assert_eq!(compile("const F = floor(2.5) + abs(-1);\nprint(F);", &[]).unwrap(), "print 3\n");
+        assert_eq!(compile("const P = packcolor(1, 0, 0, 1);\nprint(P);", &[]).unwrap(), "print %ff0000ff\n");
+        assert_eq!(compile("const Q = 1 / 0;\nprint(Q);", &[]).unwrap(), "print null\n");
+        assert_eq!(compile("const N = 1e300 * 10;\nprint(N);", &[]).unwrap(), "print 1e301\n");
+        assert_eq!(compile("const T = @copper;\nconst S = \"hi\";\nprint(T);\nprint(S);", &[]).unwrap(), "print @copper\nprint \"hi\"\n");
+        // constants never take a `set`, even without optimizations
+        assert_eq!(compile("const C = 2;\nlet x = C;\nprint(x);", &[("opt-level", "0")]).unwrap(), "set x 2\nprint x\n");
+    }
+
+    #[test]
+    fn runtime_values_are_rejected() {
+        assert_eq!(error("const X = @mapw;"), ("\"@mapw\" is not known at compile time. (line 1, col 11)".to_string(), ExitReason::CompileNotConstant));
+        assert_eq!(error("const R = rand(1);"), ("The result of \"rand\" is not known at compile time. (line 1, col 11)".to_string(), ExitReason::CompileNotConstant));
+        // constants are computed in the order they are defined
+        assert_eq!(error("const A = B + 1;\nconst B = 2;").1, ExitReason::CompileNotConstant);
+    }
+
+    #[test]
+    fn enums() {
+        assert_eq!(compile("enum State { Idle, Mining, Returning }\nlet s = State::Returning;\nprint(s);", &[]).unwrap(), "set s 2\nprint 2\n");
+        assert_eq!(error("enum State { Idle }\nprint(State::Flying);"), ("Enum \"State\" has no variant \"Flying\". (line 2, col 7)".to_string(), ExitReason::CompileUnknownVariant));
+        assert_eq!(error("print(Nope::A);"), ("Unknown enum \"Nope\". (line 1, col 7)".to_string(), ExitReason::CompileUnknownVariant));
+    }
+
+    #[test]
+    fn duplicates_and_assignments() {
+        assert_eq!(error("enum E { A, A }"), ("Variant \"A\" is defined twice. (line 1, col 13)".to_string(), ExitReason::CompileDuplicateItem));
+        assert_eq!(error("enum E { A }\nenum E { B }").1, ExitReason::CompileDuplicateItem);
+        assert_eq!(error("const C = 1;\nconst C = 2;"), ("Constant \"C\" is defined twice. (line 2, col 1)".to_string(), ExitReason::CompileDuplicateItem));
+        assert_eq!(error("const C = 1;\nC = 2;").0, "Cannot assign to constant \"C\". (line 2, col 1)");
+        assert_eq!(error("const C = 2;\nfn f() { let C = 3; return C; }\nprint(f());").0, "Cannot declare \"C\", a constant has that name. (line 2, col 10)");
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{ stdin, stdout, Write };
+use crate::emulator::{ BlockKind, Building, Emulator, Value };
+use crate::*;
+
+const DEBUGGER_HELP: &str = "Commands:
+    s, step [n]         Execute the next n mlog instructions (default 1).
+    n, next             Execute until the source line changes.
+    c, continue         Run until a breakpoint, stop or the tick limit.
+    b, break <line>     Set a breakpoint on a source line.
+    d, delete <line>    Remove the breakpoint on a source line.
+    w, watch <var>      Show a variable every time execution pauses.
+    u, unwatch <var>    Stop showing a variable.
+    p, print <var>      Show the value of a variable.
+    v, vars             Show all variables.
+    l, list             Show the source around the current line.
+    i, info             Show breakpoints, watches, tick and time.
+    h, help             Show this message.
+    q, quit             Leave the debugger.";
+
+struct Session {
+    emu: Emulator,
+    /// Source line every instruction was generated from, 0 if none.
+    lines: Vec<u64>,
+    source: Vec<String>,
+    breakpoints: Vec<u64>,
+    watches: Vec<String>,
+    ticks: u64,
+    /// Whether the source was compiled with optimizations, which can fold
+    /// or inline the code of a line away.
+    optimized: bool,
+}
+
+impl Session {
+    fn show_value(&self, value: &Value) -> String {
+        match value {
+            Value::Str(s) => format!("\"{}\"", s.replace('\n', "\\n")),
+            Value::Content(c) => format!("@{}", c),
+            Value::Building(idx) => self.emu.buildings[*idx].name.clone(),
+            _ => self.emu.to_string(value),
+        }
+    }
+
+    fn var(&self, name: &str) -> Option<Value> {
+        match name {
+            "@counter" => Some(Value::Num(self.emu.counter)),
+            "@tick" => Some(Value::Num(self.emu.tick as f64)),
+            "@time" => Some(Value::Num(self.emu.time())),
+            _ => self.emu.program.var_names.iter().position(|x| x == name).map(|idx| self.emu.vars[idx].clone()),
+        }
+    }
+
+    fn current_line(&self) -> u64 {
+        self.lines.get(self.emu.next_index()).copied().unwrap_or(0)
+    }
+
+    /// Prints the next instruction next to the source line it came from,
+    /// followed by the watched variables.
+    fn show_position(&self) {
+        if self.emu.stopped {
+            println!("Processor stopped after {} ticks.", self.emu.tick);
+            return;
+        }
+        let idx = self.emu.next_index();
+        let ins = match self.emu.program.instructions.get(idx) {
+            Some(ins) => ins,
+            None => {
+                println!("Program has no instructions.");
+                return;
+            }
+        };
+        let line = self.current_line();
+        let source = match self.source.get((line as usize).wrapping_sub(1)) {
+            Some(src) if line > 0 => format!("{:>4} | {}", line, src.trim()),
+            _ => "     | <generated>".to_string(),
+        };
+        println!("[{:>4}] {:<36} {}", idx, ins.source, source);
+        for name in &self.watches {
+            match self.var(name) {
+                Some(v) => println!("       {} = {}", name, self.show_value(&v)),
+                None => println!("       {} = <unknown variable>", name),
+            }
+        }
+    }
+
+    fn step(&mut self) -> bool {
+        if self.emu.tick >= self.ticks {
+            println!("Tick limit of {} reached.", self.ticks);
+            return false;
+        }
+        return self.emu.advance();
+    }
+
+    /// Steps until the source line changes.
+    fn next(&mut self) {
+        let start = self.current_line();
+        while self.step() && self.current_line() == start {}
+    }
+
+    /// Runs until the next instruction starts a line with a breakpoint.
+    fn cont(&mut self) {
+        loop {
+            let before = self.current_line();
+            if !self.step() {
+                return;
+            }
+            let line = self.current_line();
+            if line != before && self.breakpoints.contains(&line) {
+                println!("Breakpoint on line {}.", line);
+                return;
+            }
+        }
+    }
+
+    fn list(&self) {
+        let line = self.current_line() as usize;
+        let from = line.saturating_sub(4).max(1);
+        for n in from..(line + 4).min(self.source.len() + 1) {
+            let marker = if n == line { ">" } else if self.breakpoints.contains(&(n as u64)) { "*" } else { " " };
+            println!("{} {:>4} | {}", marker, n, self.source[n - 1]);
+        }
+    }
+
+    fn flush_output(&mut self) {
+        for (name, text) in self.emu.output.drain(..) {
+            for line in text.lines() {
+                println!("{}: {}", name, line);
+            }
+        }
+        self.emu.draw_output.clear();
+    }
+
+    /// Handles one command line. Returns false when the debugger should quit.
+    fn command(&mut self, input: &str) -> bool {
+        let mut parts = input.split_whitespace();
+        let cmd = parts.next().unwrap_or("step");
+        let arg = parts.next();
+        let line_arg = || arg.and_then(|x| x.parse::<u64>().ok());
+        match cmd {
+            "s" | "step" => {
+                for _ in 0..arg.and_then(|x| x.parse::<u64>().ok()).unwrap_or(1) {
+                    if !self.step() {
+                        break;
+                    }
+                }
+                self.flush_output();
+                self.show_position();
+            }
+            "n" | "next" => {
+                self.next();
+                self.flush_output();
+                self.show_position();
+            }
+            "c" | "continue" => {
+                self.cont();
+                self.flush_output();
+                self.show_position();
+            }
+            "b" | "break" => match line_arg() {
+                Some(line) if self.lines.contains(&line) => {
+                    if !self.breakpoints.contains(&line) {
+                        self.breakpoints.push(line);
+                    }
+                    println!("Breakpoint set on line {}.", line);
+                }
+                Some(line) if self.optimized => println!(
+                    "No code was generated from line {}, optimization may have removed it. Debug with \"--opt-level 0\" to keep every line.",
+                    line
+                ),
+                Some(line) => println!("No code was generated from line {}.", line),
+                None => println!("Expected a line number."),
+            },
+            "d" | "delete" => match line_arg() {
+                Some(line) => {
+                    self.breakpoints.retain(|x| *x != line);
+                    println!("Breakpoint on line {} removed.", line);
+                }
+                None => println!("Expected a line number."),
+            },
+            "w" | "watch" => match arg {
+                Some(name) => {
+                    if !self.watches.iter().any(|x| x == name) {
+                        self.watches.push(name.to_string());
+                    }
+                    self.show_position();
+                }
+                None => println!("Expected a variable name."),
+            },
+            "u" | "unwatch" => match arg {
+                Some(name) => self.watches.retain(|x| x != name),
+                None => println!("Expected a variable name."),
+            },
+            "p" | "print" => match arg.map(|x| (x, self.var(x))) {
+                Some((name, Some(v))) => println!("{} = {}", name, self.show_value(&v)),
+                Some((name, None)) => println!("Unknown variable \"{}\".", name),
+                None => println!("Expected a variable name."),
+            },
+            "v" | "vars" => {
+                for (name, value) in self.emu.program.var_names.iter().zip(&self.emu.vars) {
+                    println!("{} = {}", name, self.show_value(value));
+                }
+            }
+            "l" | "list" => self.list(),
+            "i" | "info" => {
+                println!("Breakpoints: {:?}", self.breakpoints);
+                println!("Watching: {:?}", self.watches);
+                println!("Tick {}, @time {}, @counter {}", self.emu.tick, self.emu.time(), self.emu.counter);
+            }
+            "h" | "help" => println!("{}", DEBUGGER_HELP),
+            "q" | "quit" => {
+                return false;
+            }
+            _ => println!("Unknown command \"{}\", type \"help\" for a list.", cmd),
+        }
+        return true;
+    }
+}
+
+pub fn debug(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"debug\" expected 1 or 2 arguments. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let (ipt, ticks) = emulator::emulator_opts(opts)?;
+    let path = &args[2];
+    let source = match read_to_string(path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err((format!("Reading file \"{}\" failed. Error: {}", path, e.kind()), ExitReason::CompileFileNotFound));
+        }
+    };
+    // source lines are only kept apart without optimizations
+    let mut opts = opts.clone();
+    let optimized = match opts.contains_key("opt-level") {
+        true => optimize::opt_level(&opts)? > 0,
+        false => {
+            opts.insert("opt-level".to_string(), "0".to_string());
+            false
+        }
+    };
+    let opts = &opts;
+    let (program, lines) = if path.ends_with(".mlog") {
+        let program = emulator::parse_mlog(&source)?;
+        let lines = program.instructions.iter().map(|x| x.line).collect();
+        (program, lines)
+    }
+    else {
+        let root = compile::parse_file(path, opts)?;
+        let generated = codegen::generate(&root, opts)?;
+        (emulator::parse_mlog(&generated.mlog)?, generated.positions.iter().map(|x| x.line).collect())
+    };
+    let buildings = match args.get(3) {
+        Some(world) => world::read_world(world)?,
+        None => vec![Building::new("message1", BlockKind::Message { text: String::new() })],
+    };
+    info!("Loaded {} instructions", program.instructions.len());
+
+    let mut session = Session {
+        emu: Emulator::new(program, buildings, ipt),
+        lines,
+        source: source.lines().map(|x| x.to_string()).collect(),
+        breakpoints: Vec::<u64>::new(),
+        watches: Vec::<String>::new(),
+        ticks,
+        optimized: optimized && !path.ends_with(".mlog"),
+    };
+    println!("Debugging \"{}\", type \"help\" for a list of commands.", path);
+    session.show_position();
+    loop {
+        print!("(mdb) ");
+        _ = stdout().flush();
+        let mut input = String::new();
+        match stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+        }
+        if !session.command(input.trim()) {
+            break;
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::opts;
+
+    fn session(source: &str, level: &str) -> Session {
+        let opts = opts(&[("opt-level", level)]);
+        let generated = codegen::generate(&compile::parse_source(source.to_string(), &opts).unwrap(), &opts).unwrap();
+        let buildings = vec![Building::new("message1", BlockKind::Message { text: String::new() })];
+        return Session {
+            emu: Emulator::new(emulator::parse_mlog(&generated.mlog).unwrap(), buildings, 8),
+            lines: generated.positions.iter().map(|x| x.line).collect(),
+            source: source.lines().map(|x| x.to_string()).collect(),
+            breakpoints: Vec::<u64>::new(),
+            watches: Vec::<String>::new(),
+            ticks: 100,
+            optimized: level != "0",
+        };
+    }
+
+    const SOURCE: &str = "let a = @links;\nlet b = a + 1;\n\nlet c = b * 2;\nprint(c);";
+
+    #[test]
+    fn next_stops_on_every_line_with_code() {
+        let mut session = session(SOURCE, "0");
+        let mut lines = vec![session.current_line()];
+        while !session.emu.stopped && !session.emu.wrapped {
+            session.next();
+            lines.push(session.current_line());
+        }
+        assert_eq!(lines[..4], [1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn continue_runs_to_breakpoints() {
+        let mut session = session(SOURCE, "0");
+        assert!(session.command("break 4"));
+        assert!(session.command("break 3"));
+        assert_eq!(session.breakpoints, vec![4]);
+        session.cont();
+        assert_eq!(session.current_line(), 4);
+        assert_eq!(session.var("b"), Some(Value::Num(2.0)));
+        assert_eq!(session.var("c"), Some(Value::Null));
+        assert!(session.command("delete 4"));
+        assert!(session.breakpoints.is_empty());
+        assert!(!session.command("quit"));
+    }
+
+    #[test]
+    fn step_counts_and_tick_limit() {
+        let mut session = session("let i = 0;\n`loop:\nop add {i} {i} 1\njump loop always`;", "0");
+        session.command("step 2");
+        assert_eq!(session.var("@counter"), Some(Value::Num(2.0)));
+        session.command("step 2");
+        assert_eq!(session.var("@counter"), Some(Value::Num(2.0)));
+        assert_eq!(session.var("i"), Some(Value::Num(2.0)));
+        session.ticks = 2;
+        session.cont();
+        assert_eq!(session.emu.tick, 2);
+    }
+
+    #[test]
+    fn optimized_builds_lose_lines() {
+        let source = "let a = 2;\nlet b = a + 1;\nprint(b);";
+        let optimized = session(source, "2");
+        assert!(optimized.optimized);
+        assert_eq!(optimized.lines, vec![3]);
+        assert_eq!(session(source, "0").lines, vec![1, 2, 3]);
+    }
+}
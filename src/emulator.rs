@@ -0,0 +1,1435 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use crate::*;
+
+/// Maximum amount of instructions a processor accepts.
+pub const MAX_INSTRUCTIONS: usize = 1000;
+/// Maximum length of the print buffer, further prints are dropped.
+pub const MAX_TEXT_BUFFER: usize = 400;
+/// Ticks per second of simulated game time.
+pub const TICKS_PER_SECOND: f64 = 60.0;
+/// Maximum amount of draw commands buffered before `drawflush`.
+pub const MAX_GRAPHICS_BUFFER: usize = 256;
+/// Maximum amount of draw commands a display accepts per flush.
+pub const MAX_DISPLAY_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Num(f64),
+    Str(String),
+    Content(String),
+    Building(usize),
+}
+
+impl Value {
+    pub fn num(&self) -> f64 {
+        match self {
+            Value::Num(n) => *n,
+            Value::Null => 0.0,
+            _ => 1.0,
+        }
+    }
+
+    pub fn is_obj(&self) -> bool {
+        !matches!(self, Value::Num(_))
+    }
+
+    /// Mindustry turns NaN and infinities into null when storing numbers.
+    pub fn from_num(n: f64) -> Value {
+        if n.is_nan() || n.is_infinite() {
+            Value::Null
+        }
+        else {
+            Value::Num(n)
+        }
+    }
+
+    pub fn from_bool(b: bool) -> Value {
+        Value::Num(if b { 1.0 } else { 0.0 })
+    }
+}
+
+/// Formats a number the way Java's `Double.toString` does.
+pub fn java_double(n: f64) -> String {
+    let a = n.abs();
+    if a == 0.0 || (1e-3..1e7).contains(&a) {
+        let s = format!("{}", n);
+        if s.contains('.') {
+            return s;
+        }
+        return s + ".0";
+    }
+    let s = format!("{:e}", n);
+    let (mantissa, exponent) = s.split_once('e').unwrap_or((s.as_str(), "0"));
+    if mantissa.contains('.') {
+        return format!("{}E{}", mantissa, exponent);
+    }
+    return format!("{}.0E{}", mantissa, exponent);
+}
+
+/// Formats a number the way the `print` instruction does.
+pub fn fmt_num(n: f64) -> String {
+    if (n - (n as i64) as f64).abs() < 0.00001 {
+        return format!("{}", n as i64);
+    }
+    return java_double(n);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpKind {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Idiv,
+    Mod,
+    Emod,
+    Pow,
+    Equal,
+    NotEqual,
+    Land,
+    LessThan,
+    LessThanEq,
+    GreaterThan,
+    GreaterThanEq,
+    StrictEqual,
+    Shl,
+    Shr,
+    Ushr,
+    Or,
+    And,
+    Xor,
+    Not,
+    Max,
+    Min,
+    Angle,
+    AngleDiff,
+    Len,
+    Noise,
+    Abs,
+    Sign,
+    Log,
+    Logn,
+    Log10,
+    Floor,
+    Ceil,
+    Round,
+    Sqrt,
+    Rand,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+}
+
+impl OpKind {
+    pub fn parse(s: &str) -> Option<OpKind> {
+        Some(match s {
+            "add" => OpKind::Add,
+            "sub" => OpKind::Sub,
+            "mul" => OpKind::Mul,
+            "div" => OpKind::Div,
+            "idiv" => OpKind::Idiv,
+            "mod" => OpKind::Mod,
+            "emod" => OpKind::Emod,
+            "pow" => OpKind::Pow,
+            "equal" => OpKind::Equal,
+            "notEqual" => OpKind::NotEqual,
+            "land" => OpKind::Land,
+            "lessThan" => OpKind::LessThan,
+            "lessThanEq" => OpKind::LessThanEq,
+            "greaterThan" => OpKind::GreaterThan,
+            "greaterThanEq" => OpKind::GreaterThanEq,
+            "strictEqual" => OpKind::StrictEqual,
+            "shl" => OpKind::Shl,
+            "shr" => OpKind::Shr,
+            "ushr" => OpKind::Ushr,
+            "or" => OpKind::Or,
+            "and" => OpKind::And,
+            "xor" => OpKind::Xor,
+            "not" => OpKind::Not,
+            "max" => OpKind::Max,
+            "min" => OpKind::Min,
+            "angle" => OpKind::Angle,
+            "angleDiff" => OpKind::AngleDiff,
+            "len" => OpKind::Len,
+            "noise" => OpKind::Noise,
+            "abs" => OpKind::Abs,
+            "sign" => OpKind::Sign,
+            "log" => OpKind::Log,
+            "logn" => OpKind::Logn,
+            "log10" => OpKind::Log10,
+            "floor" => OpKind::Floor,
+            "ceil" => OpKind::Ceil,
+            "round" => OpKind::Round,
+            "sqrt" => OpKind::Sqrt,
+            "rand" => OpKind::Rand,
+            "sin" => OpKind::Sin,
+            "cos" => OpKind::Cos,
+            "tan" => OpKind::Tan,
+            "asin" => OpKind::Asin,
+            "acos" => OpKind::Acos,
+            "atan" => OpKind::Atan,
+            _ => {
+                return None;
+            }
+        })
+    }
+
+    pub fn is_unary(&self) -> bool {
+        matches!(
+            self,
+            OpKind::Not |
+            OpKind::Abs |
+            OpKind::Sign |
+            OpKind::Log |
+            OpKind::Log10 |
+            OpKind::Floor |
+            OpKind::Ceil |
+            OpKind::Round |
+            OpKind::Sqrt |
+            OpKind::Rand |
+            OpKind::Sin |
+            OpKind::Cos |
+            OpKind::Tan |
+            OpKind::Asin |
+            OpKind::Acos |
+            OpKind::Atan
+        )
+    }
+
+    /// Applies the operation to plain numbers. `rand` is handled by the
+    /// caller as it needs emulator state.
+    pub fn eval_num(&self, a: f64, b: f64) -> f64 {
+        match self {
+            OpKind::Add => a + b,
+            OpKind::Sub => a - b,
+            OpKind::Mul => a * b,
+            OpKind::Div => a / b,
+            OpKind::Idiv => (a / b).floor(),
+            OpKind::Mod => a % b,
+            OpKind::Emod => ((a % b) + b) % b,
+            OpKind::Pow => a.powf(b),
+            OpKind::Equal => bool_num((a - b).abs() < 0.000001),
+            OpKind::NotEqual => bool_num((a - b).abs() >= 0.000001),
+            OpKind::Land => bool_num(a != 0.0 && b != 0.0),
+            OpKind::LessThan => bool_num(a < b),
+            OpKind::LessThanEq => bool_num(a <= b),
+            OpKind::GreaterThan => bool_num(a > b),
+            OpKind::GreaterThanEq => bool_num(a >= b),
+            OpKind::StrictEqual => bool_num(a == b),
+            OpKind::Shl => (a as i64).wrapping_shl(b as i64 as u32) as f64,
+            OpKind::Shr => (a as i64).wrapping_shr(b as i64 as u32) as f64,
+            OpKind::Ushr => ((a as i64 as u64).wrapping_shr(b as i64 as u32)) as i64 as f64,
+            OpKind::Or => ((a as i64) | (b as i64)) as f64,
+            OpKind::And => ((a as i64) & (b as i64)) as f64,
+            OpKind::Xor => ((a as i64) ^ (b as i64)) as f64,
+            OpKind::Not => !(a as i64) as f64,
+            OpKind::Max => a.max(b),
+            OpKind::Min => a.min(b),
+            OpKind::Angle => {
+                let angle = b.atan2(a).to_degrees();
+                if angle < 0.0 { angle + 360.0 } else { angle }
+            }
+            OpKind::AngleDiff => {
+                let a = a.rem_euclid(360.0);
+                let b = b.rem_euclid(360.0);
+                (a - b).abs().min(360.0 - (a - b).abs())
+            }
+            OpKind::Len => a.hypot(b),
+            OpKind::Noise => simplex_2d(a, b),
+            OpKind::Abs => a.abs(),
+            OpKind::Sign => if a == 0.0 { 0.0 } else { a.signum() },
+            OpKind::Log => a.ln(),
+            OpKind::Logn => a.ln() / b.ln(),
+            OpKind::Log10 => a.log10(),
+            OpKind::Floor => a.floor(),
+            OpKind::Ceil => a.ceil(),
+            OpKind::Round => (a + 0.5).floor(),
+            OpKind::Sqrt => a.sqrt(),
+            OpKind::Rand => 0.0,
+            OpKind::Sin => a.to_radians().sin(),
+            OpKind::Cos => a.to_radians().cos(),
+            OpKind::Tan => a.to_radians().tan(),
+            OpKind::Asin => a.asin().to_degrees(),
+            OpKind::Acos => a.acos().to_degrees(),
+            OpKind::Atan => a.atan().to_degrees(),
+        }
+    }
+}
+
+fn bool_num(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
+
+/// Applies `op` to two values following Mindustry's rules: `strictEqual`
+/// compares kind and value, `equal`/`notEqual` compare objects by value
+/// when both sides are objects, everything else works on numbers.
+pub fn eval_op(op: OpKind, a: &Value, b: &Value) -> Value {
+    match op {
+        OpKind::StrictEqual => {
+            return Value::from_bool(match (a, b) {
+                (Value::Num(x), Value::Num(y)) => x == y,
+                _ => a == b,
+            });
+        }
+        OpKind::Equal | OpKind::NotEqual if a.is_obj() && b.is_obj() => {
+            return Value::from_bool((a == b) == (op == OpKind::Equal));
+        }
+        _ => {}
+    }
+    return Value::from_num(op.eval_num(a.num(), b.num()));
+}
+
+/// 2D simplex noise, used by `op noise`.
+fn simplex_2d(x: f64, y: f64) -> f64 {
+    const GRAD: [(f64, f64); 8] = [
+        (1.0, 1.0), (-1.0, 1.0), (1.0, -1.0), (-1.0, -1.0),
+        (1.0, 0.0), (-1.0, 0.0), (0.0, 1.0), (0.0, -1.0),
+    ];
+    fn hash(i: i64, j: i64) -> usize {
+        let mut h = (i.wrapping_mul(374761393) ^ j.wrapping_mul(668265263)) as u64;
+        h = (h ^ (h >> 13)).wrapping_mul(1274126177);
+        return ((h ^ (h >> 16)) & 7) as usize;
+    }
+    let f2 = 0.5 * (3f64.sqrt() - 1.0);
+    let g2 = (3.0 - 3f64.sqrt()) / 6.0;
+    let s = (x + y) * f2;
+    let i = (x + s).floor() as i64;
+    let j = (y + s).floor() as i64;
+    let t = (i + j) as f64 * g2;
+    let x0 = x - (i as f64 - t);
+    let y0 = y - (j as f64 - t);
+    let (i1, j1) = if x0 > y0 { (1, 0) } else { (0, 1) };
+    let x1 = x0 - i1 as f64 + g2;
+    let y1 = y0 - j1 as f64 + g2;
+    let x2 = x0 - 1.0 + 2.0 * g2;
+    let y2 = y0 - 1.0 + 2.0 * g2;
+    let mut total = 0.0;
+    for (dx, dy, gi) in [(x0, y0, hash(i, j)), (x1, y1, hash(i + i1, j + j1)), (x2, y2, hash(i + 1, j + 1))] {
+        let t = 0.5 - dx * dx - dy * dy;
+        if t > 0.0 {
+            let t = t * t;
+            total += t * t * (GRAD[gi].0 * dx + GRAD[gi].1 * dy);
+        }
+    }
+    return 70.0 * total;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Global {
+    Counter,
+    Time,
+    Tick,
+    Second,
+    Minute,
+    Links,
+    Ipt,
+    This,
+    Unit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Var(usize),
+    Const(Value),
+    Global(Global),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanEq,
+    GreaterThan,
+    GreaterThanEq,
+    StrictEqual,
+    Always,
+}
+
+impl Cond {
+    pub fn parse(s: &str) -> Option<Cond> {
+        Some(match s {
+            "equal" => Cond::Equal,
+            "notEqual" => Cond::NotEqual,
+            "lessThan" => Cond::LessThan,
+            "lessThanEq" => Cond::LessThanEq,
+            "greaterThan" => Cond::GreaterThan,
+            "greaterThanEq" => Cond::GreaterThanEq,
+            "strictEqual" => Cond::StrictEqual,
+            "always" => Cond::Always,
+            _ => {
+                return None;
+            }
+        })
+    }
+
+    pub fn op(&self) -> Option<OpKind> {
+        match self {
+            Cond::Equal => Some(OpKind::Equal),
+            Cond::NotEqual => Some(OpKind::NotEqual),
+            Cond::LessThan => Some(OpKind::LessThan),
+            Cond::LessThanEq => Some(OpKind::LessThanEq),
+            Cond::GreaterThan => Some(OpKind::GreaterThan),
+            Cond::GreaterThanEq => Some(OpKind::GreaterThanEq),
+            Cond::StrictEqual => Some(OpKind::StrictEqual),
+            Cond::Always => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrawKind {
+    Clear,
+    Color,
+    Col,
+    Stroke,
+    Line,
+    Rect,
+    LineRect,
+    Poly,
+    LinePoly,
+    Triangle,
+    Image,
+}
+
+impl DrawKind {
+    pub fn parse(s: &str) -> Option<DrawKind> {
+        Some(match s {
+            "clear" => DrawKind::Clear,
+            "color" => DrawKind::Color,
+            "col" => DrawKind::Col,
+            "stroke" => DrawKind::Stroke,
+            "line" => DrawKind::Line,
+            "rect" => DrawKind::Rect,
+            "lineRect" => DrawKind::LineRect,
+            "poly" => DrawKind::Poly,
+            "linePoly" => DrawKind::LinePoly,
+            "triangle" => DrawKind::Triangle,
+            "image" => DrawKind::Image,
+            _ => {
+                return None;
+            }
+        })
+    }
+}
+
+/// A draw command as stored in the graphics buffer. Arguments are packed
+/// into 10 bit sign-magnitude integers like in game, `image` keeps the
+/// content name separately.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawCmd {
+    pub kind: DrawKind,
+    pub args: [i32; 6],
+    pub image: Option<String>,
+}
+
+fn pack_sign(v: f64) -> i32 {
+    let v = v as i32;
+    let magnitude = v.abs() & 0b0111111111;
+    return if v < 0 { -magnitude } else { magnitude };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Noop,
+    Set(Operand, Operand),
+    Op(OpKind, Operand, Operand, Operand),
+    Select(Operand, Cond, Operand, Operand, Operand, Operand),
+    Jump(Option<usize>, Cond, Operand, Operand),
+    End,
+    Stop,
+    Wait(Operand),
+    Print(Operand),
+    PrintChar(Operand),
+    Format(Operand),
+    PrintFlush(Operand),
+    PackColor(Operand, Operand, Operand, Operand, Operand),
+    UnpackColor(Operand, Operand, Operand, Operand, Operand),
+    Lookup(String, Operand, Operand),
+    Read(Operand, Operand, Operand),
+    Write(Operand, Operand, Operand),
+    GetLink(Operand, Operand),
+    Sensor(Operand, Operand, Operand),
+    Control(String, Operand, Operand),
+    /// `ubind` and `ucontrol` with its command. The emulator has no units,
+    /// so `@unit` stays null and both do nothing, like in game when no unit
+    /// of the type exists.
+    UBind(Operand),
+    UControl(String),
+    Draw(DrawKind, [Operand; 6]),
+    DrawFlush(Operand),
+    Unsupported(String),
+}
+
+pub struct Instruction {
+    pub kind: Instr,
+    /// Line in the mlog source, starting at 1.
+    pub line: u64,
+    pub source: String,
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (line {})", self.source, self.line)
+    }
+}
+
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub var_names: Vec<String>,
+}
+
+/// Splits one line of mlog into statements of tokens, keeping quoted strings
+/// (including their quotes) as single tokens and dropping `#` comments.
+pub fn split_mlog_line(line: &str) -> Vec<Vec<String>> {
+    let mut statements = Vec::<Vec<String>>::new();
+    let mut current = Vec::<String>::new();
+    let mut token = String::new();
+    let mut instr = false;
+    for ch in line.chars() {
+        if instr {
+            token.push(ch);
+            if ch == '"' {
+                instr = false;
+            }
+            continue;
+        }
+        match ch {
+            '"' => {
+                token.push(ch);
+                instr = true;
+            }
+            '#' => {
+                break;
+            }
+            ';' => {
+                if !token.is_empty() {
+                    current.push(token);
+                    token = String::new();
+                }
+                statements.push(current);
+                current = Vec::<String>::new();
+            }
+            ' ' | '\t' | '\r' => {
+                if !token.is_empty() {
+                    current.push(token);
+                    token = String::new();
+                }
+            }
+            _ => {
+                token.push(ch);
+            }
+        }
+    }
+    if !token.is_empty() {
+        current.push(token);
+    }
+    statements.push(current);
+    return statements.into_iter().filter(|x| !x.is_empty()).collect();
+}
+
+/// Parses a numeric literal the way the game's assembler does.
+pub fn parse_mlog_num(s: &str) -> Option<f64> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return i64::from_str_radix(hex, 16).ok().map(|x| x as f64);
+    }
+    if let Some(bin) = s.strip_prefix("0b") {
+        return i64::from_str_radix(bin, 2).ok().map(|x| x as f64);
+    }
+    if let Some(color) = s.strip_prefix('%') {
+        if color.len() != 6 && color.len() != 8 {
+            return None;
+        }
+        let rgba = u32::from_str_radix(color, 16).ok()?;
+        let rgba = if color.len() == 6 { (rgba << 8) | 0xff } else { rgba };
+        return Some(f64::from_bits(rgba as u64));
+    }
+    if s.is_empty() || !s.chars().all(|x| x.is_ascii_digit() || ['-', '+', '.', 'e', 'E'].contains(&x)) {
+        return None;
+    }
+    return s.parse::<f64>().ok();
+}
+
+/// Packs color components from 0 to 1 into a double, like `packcolor`.
+pub fn pack_color(r: f64, g: f64, b: f64, a: f64) -> f64 {
+    let c = |x: f64| ((x.clamp(0.0, 1.0) * 255.0) as u32) & 0xff;
+    let rgba = (c(r) << 24) | (c(g) << 16) | (c(b) << 8) | c(a);
+    return f64::from_bits(rgba as u64);
+}
+
+/// Reverses `pack_color`.
+pub fn unpack_color(v: f64) -> (f64, f64, f64, f64) {
+    let rgba = v.to_bits() as u32;
+    let c = |shift: u32| ((rgba >> shift) & 0xff) as f64 / 255.0;
+    return (c(24), c(16), c(8), c(0));
+}
+
+struct ProgramBuilder {
+    var_names: Vec<String>,
+}
+
+/// Globals whose value depends on the processor, the map or the time, so
+/// they can never be known before the program runs.
+pub const RUNTIME_GLOBALS: [&str; 25] = [
+    "@counter", "@time", "@tick", "@second", "@minute", "@links", "@ipt", "@this", "@unit", "@thisx", "@thisy",
+    "@mapw", "@maph", "@waveNumber", "@waveTime", "@server", "@client", "@wait", "@blockCount", "@unitCount",
+    "@itemCount", "@liquidCount", "@sectorId", "@selected", "@clientUnit",
+];
+
+/// Value of a literal operand: numbers, strings, `true`, `false`, `null`,
+/// the math and control constants and content like `@copper`. `None` for
+/// variables and the `RUNTIME_GLOBALS` such as `@time`.
+pub fn parse_literal(s: &str) -> Option<Value> {
+    if s.is_empty() {
+        return Some(Value::Null);
+    }
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return Some(Value::Str(s[1..s.len() - 1].replace("\\n", "\n")));
+    }
+    match s {
+        "true" => {
+            return Some(Value::Num(1.0));
+        }
+        "false" => {
+            return Some(Value::Num(0.0));
+        }
+        "null" => {
+            return Some(Value::Null);
+        }
+        "@pi" => {
+            return Some(Value::Num(std::f64::consts::PI));
+        }
+        "@e" => {
+            return Some(Value::Num(std::f64::consts::E));
+        }
+        "@degToRad" => {
+            return Some(Value::Num(std::f64::consts::PI / 180.0));
+        }
+        "@radToDeg" => {
+            return Some(Value::Num(180.0 / std::f64::consts::PI));
+        }
+        "@ctrlProcessor" => {
+            return Some(Value::Num(1.0));
+        }
+        "@ctrlPlayer" => {
+            return Some(Value::Num(2.0));
+        }
+        "@ctrlCommand" => {
+            return Some(Value::Num(3.0));
+        }
+        _ if RUNTIME_GLOBALS.contains(&s) => {
+            return None;
+        }
+        _ => {}
+    }
+    if let Some(content) = s.strip_prefix('@') {
+        return Some(Value::Content(content.to_string()));
+    }
+    return parse_mlog_num(s).map(Value::from_num);
+}
+
+impl ProgramBuilder {
+    fn operand(&mut self, s: &str) -> Operand {
+        match s {
+            "@counter" => {
+                return Operand::Global(Global::Counter);
+            }
+            "@time" => {
+                return Operand::Global(Global::Time);
+            }
+            "@tick" => {
+                return Operand::Global(Global::Tick);
+            }
+            "@second" => {
+                return Operand::Global(Global::Second);
+            }
+            "@minute" => {
+                return Operand::Global(Global::Minute);
+            }
+            "@links" => {
+                return Operand::Global(Global::Links);
+            }
+            "@ipt" => {
+                return Operand::Global(Global::Ipt);
+            }
+            "@this" => {
+                return Operand::Global(Global::This);
+            }
+            "@unit" => {
+                return Operand::Global(Global::Unit);
+            }
+            _ => {}
+        }
+        if let Some(value) = parse_literal(s) {
+            return Operand::Const(value);
+        }
+        return Operand::Var(match self.var_names.iter().position(|x| x == s) {
+            Some(idx) => idx,
+            None => {
+                self.var_names.push(s.to_string());
+                self.var_names.len() - 1
+            }
+        });
+    }
+}
+
+/// Parses mlog source into a `Program`. Unknown instructions are kept as
+/// `Instr::Unsupported` and behave like `noop`, like in game.
+pub fn parse_mlog(source: &str) -> Result<Program, (String, ExitReason)> {
+    let mut builder = ProgramBuilder { var_names: Vec::<String>::new() };
+    let mut labels = HashMap::<String, usize>::new();
+    let mut statements = Vec::<(u64, Vec<String>, String)>::new();
+
+    for (lineidx, line) in source.lines().enumerate() {
+        for statement in split_mlog_line(line) {
+            if statement.len() == 1 && statement[0].ends_with(':') && !statement[0].starts_with('"') {
+                let label = statement[0].strip_suffix(':').unwrap_or_default().to_string();
+                labels.insert(label, statements.len());
+                continue;
+            }
+            statements.push(((lineidx + 1) as u64, statement, line.trim().to_string()));
+        }
+    }
+
+    let mut instructions = Vec::<Instruction>::new();
+    for (line, statement, source) in statements {
+        let arg = |idx: usize| statement.get(idx + 1).map(|x| x.as_str()).unwrap_or_default();
+        // operands are only created when used, so keywords like the `op`
+        // kind or jump condition are not mistaken for variables
+        macro_rules! op {
+            ($idx:expr) => {
+                builder.operand(arg($idx))
+            };
+        }
+        let kind = match statement[0].as_str() {
+            "noop" => Instr::Noop,
+            "set" => Instr::Set(op!(0), op!(1)),
+            "op" => match OpKind::parse(arg(0)) {
+                Some(op) => Instr::Op(op, op!(1), op!(2), op!(3)),
+                None => Instr::Unsupported(source.clone()),
+            },
+            "select" => match Cond::parse(arg(1)) {
+                Some(cond) => Instr::Select(op!(0), cond, op!(2), op!(3), op!(4), op!(5)),
+                None => Instr::Unsupported(source.clone()),
+            },
+            "jump" => {
+                let target = match labels.get(arg(0)) {
+                    Some(idx) => Some(*idx),
+                    None => arg(0).parse::<usize>().ok(),
+                };
+                match Cond::parse(arg(1)) {
+                    Some(cond) => Instr::Jump(target, cond, op!(2), op!(3)),
+                    None => Instr::Unsupported(source.clone()),
+                }
+            }
+            "end" => Instr::End,
+            "stop" => Instr::Stop,
+            "wait" => Instr::Wait(op!(0)),
+            "print" => Instr::Print(op!(0)),
+            "printchar" => Instr::PrintChar(op!(0)),
+            "format" => Instr::Format(op!(0)),
+            "printflush" => Instr::PrintFlush(op!(0)),
+            "packcolor" => Instr::PackColor(op!(0), op!(1), op!(2), op!(3), op!(4)),
+            "unpackcolor" => Instr::UnpackColor(op!(0), op!(1), op!(2), op!(3), op!(4)),
+            "lookup" => Instr::Lookup(arg(0).to_string(), op!(1), op!(2)),
+            "read" => Instr::Read(op!(0), op!(1), op!(2)),
+            "write" => Instr::Write(op!(0), op!(1), op!(2)),
+            "getlink" => Instr::GetLink(op!(0), op!(1)),
+            "sensor" => Instr::Sensor(op!(0), op!(1), op!(2)),
+            "control" => Instr::Control(arg(0).to_string(), op!(1), op!(2)),
+            "ubind" => Instr::UBind(op!(0)),
+            "ucontrol" => Instr::UControl(arg(0).to_string()),
+            "draw" => match DrawKind::parse(arg(0)) {
+                Some(kind) => Instr::Draw(kind, [op!(1), op!(2), op!(3), op!(4), op!(5), op!(6)]),
+                None => Instr::Unsupported(source.clone()),
+            },
+            "drawflush" => Instr::DrawFlush(op!(0)),
+            _ => Instr::Unsupported(source.clone()),
+        };
+        instructions.push(Instruction { kind, line, source });
+    }
+
+    return Ok(Program { instructions, var_names: builder.var_names });
+}
+
+const ITEMS: [&str; 16] = [
+    "copper", "lead", "metaglass", "graphite", "sand", "coal", "titanium", "thorium",
+    "scrap", "silicon", "plastanium", "phase-fabric", "surge-alloy", "spore-pod",
+    "blast-compound", "pyratite",
+];
+const LIQUIDS: [&str; 11] = [
+    "water", "slag", "oil", "cryofluid", "neoplasm", "arkycite", "gallium", "ozone",
+    "hydrogen", "nitrogen", "cyanogen",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockKind {
+    Message { text: String },
+    Memory { memory: Vec<f64>, bank: bool },
+    Display { size: usize, large: bool },
+    Switch,
+    Sorter { item: Option<String>, inverted: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Building {
+    pub name: String,
+    pub kind: BlockKind,
+    pub enabled: bool,
+}
+
+impl Building {
+    pub fn new(name: &str, kind: BlockKind) -> Building {
+        Building { name: name.to_string(), kind, enabled: true }
+    }
+
+    /// Name of the block type, what `print` shows for a building.
+    pub fn block_name(&self) -> &'static str {
+        match self.kind {
+            BlockKind::Message { .. } => "message",
+            BlockKind::Memory { bank: false, .. } => "memory-cell",
+            BlockKind::Memory { bank: true, .. } => "memory-bank",
+            BlockKind::Display { large: false, .. } => "logic-display",
+            BlockKind::Display { large: true, .. } => "large-logic-display",
+            BlockKind::Switch => "switch",
+            BlockKind::Sorter { inverted: false, .. } => "sorter",
+            BlockKind::Sorter { inverted: true, .. } => "inverted-sorter",
+        }
+    }
+
+    /// Size of the block in tiles, as reported by `sensor @size`.
+    pub fn size(&self) -> f64 {
+        match self.kind {
+            BlockKind::Memory { bank: true, .. } => 2.0,
+            BlockKind::Display { large: false, .. } => 3.0,
+            BlockKind::Display { large: true, .. } => 6.0,
+            _ => 1.0,
+        }
+    }
+
+    /// Reads a property for `sensor`, null if the block does not have it.
+    pub fn sensor(&self, prop: &str) -> Value {
+        match (prop, &self.kind) {
+            ("enabled", _) => Value::from_bool(self.enabled),
+            ("type", _) => Value::Content(self.block_name().to_string()),
+            ("size", _) => Value::Num(self.size()),
+            ("memoryCapacity", BlockKind::Memory { memory, .. }) => Value::Num(memory.len() as f64),
+            ("displayWidth" | "displayHeight", BlockKind::Display { size, .. }) => Value::Num(*size as f64),
+            ("bufferSize", BlockKind::Message { text }) => Value::Num(text.chars().count() as f64),
+            ("config", BlockKind::Sorter { item: Some(item), .. }) => Value::Content(item.clone()),
+            _ => Value::Null,
+        }
+    }
+}
+
+pub struct Emulator {
+    pub program: Program,
+    pub vars: Vec<Value>,
+    pub constant: Vec<bool>,
+    pub counter: f64,
+    pub tick: u64,
+    pub ipt: u64,
+    pub text_buffer: String,
+    pub buildings: Vec<Building>,
+    pub stopped: bool,
+    /// Set when the counter wrapped back to the first instruction.
+    pub wrapped: bool,
+    /// Stop instead of wrapping back to the first instruction.
+    pub once: bool,
+    /// Messages flushed to message blocks, in order, with the block name.
+    pub output: Vec<(String, String)>,
+    pub graphics_buffer: Vec<DrawCmd>,
+    /// Draw commands flushed to displays, in order, with the building index.
+    pub draw_output: Vec<(usize, Vec<DrawCmd>)>,
+    /// Instructions that were reported as unsupported.
+    pub unsupported: Vec<usize>,
+    wait_time: HashMap<usize, f64>,
+    yielded: bool,
+    tick_steps: u64,
+    rng: u64,
+}
+
+impl Emulator {
+    pub fn new(program: Program, buildings: Vec<Building>, ipt: u64) -> Emulator {
+        let mut vars = vec![Value::Null; program.var_names.len()];
+        let mut constant = vec![false; program.var_names.len()];
+        for (idx, name) in program.var_names.iter().enumerate() {
+            if let Some(b) = buildings.iter().position(|x| &x.name == name) {
+                vars[idx] = Value::Building(b);
+                constant[idx] = true;
+            }
+        }
+        Emulator {
+            program,
+            vars,
+            constant,
+            counter: 0.0,
+            tick: 0,
+            ipt,
+            text_buffer: String::new(),
+            buildings,
+            stopped: false,
+            wrapped: false,
+            once: false,
+            output: Vec::<(String, String)>::new(),
+            graphics_buffer: Vec::<DrawCmd>::new(),
+            draw_output: Vec::<(usize, Vec<DrawCmd>)>::new(),
+            unsupported: Vec::<usize>::new(),
+            wait_time: HashMap::<usize, f64>::new(),
+            yielded: false,
+            tick_steps: 0,
+            rng: 0x2545f4914f6cdd1d,
+        }
+    }
+
+    /// Simulated game time in milliseconds.
+    pub fn time(&self) -> f64 {
+        self.tick as f64 * 1000.0 / TICKS_PER_SECOND
+    }
+
+    pub fn get(&self, op: &Operand) -> Value {
+        match op {
+            Operand::Var(idx) => self.vars[*idx].clone(),
+            Operand::Const(v) => v.clone(),
+            Operand::Global(g) => match g {
+                Global::Counter => Value::Num(self.counter),
+                Global::Time => Value::Num(self.time()),
+                Global::Tick => Value::Num(self.tick as f64),
+                Global::Second => Value::Num(self.time() / 1000.0),
+                Global::Minute => Value::Num(self.time() / 60000.0),
+                Global::Links => Value::Num(self.buildings.len() as f64),
+                Global::Ipt => Value::Num(self.ipt as f64),
+                Global::This | Global::Unit => Value::Null,
+            },
+        }
+    }
+
+    pub fn set(&mut self, op: &Operand, value: Value) {
+        match op {
+            Operand::Var(idx) if !self.constant[*idx] => {
+                self.vars[*idx] = value;
+            }
+            Operand::Global(Global::Counter) => {
+                self.counter = value.num();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn to_string(&self, value: &Value) -> String {
+        match value {
+            Value::Null => "null".to_string(),
+            Value::Num(n) => fmt_num(*n),
+            Value::Str(s) => s.clone(),
+            Value::Content(s) => s.clone(),
+            Value::Building(idx) => self.buildings[*idx].block_name().to_string(),
+        }
+    }
+
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        return (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    fn append_text(&mut self, text: &str) {
+        if self.text_buffer.chars().count() < MAX_TEXT_BUFFER {
+            self.text_buffer += text;
+        }
+    }
+
+    /// Index of the instruction that will be executed next.
+    pub fn next_index(&self) -> usize {
+        let len = self.program.instructions.len();
+        if self.counter < 0.0 || self.counter as usize >= len {
+            return 0;
+        }
+        return self.counter as usize;
+    }
+
+    /// Executes a single instruction. Returns false if nothing was executed
+    /// because the processor has stopped or has no instructions.
+    pub fn step(&mut self) -> bool {
+        let len = self.program.instructions.len();
+        if self.stopped || len == 0 {
+            return false;
+        }
+        let idx = self.next_index();
+        if idx == 0 && self.counter != 0.0 {
+            self.wrapped = true;
+            if self.once {
+                self.stopped = true;
+                return false;
+            }
+        }
+        self.counter = (idx + 1) as f64;
+        let kind = self.program.instructions[idx].kind.clone();
+        match kind {
+            Instr::Noop | Instr::UBind(_) | Instr::UControl(_) => {}
+            Instr::Unsupported(_) => {
+                if !self.unsupported.contains(&idx) {
+                    self.unsupported.push(idx);
+                }
+            }
+            Instr::Set(dest, val) => {
+                let v = self.get(&val);
+                self.set(&dest, v);
+            }
+            Instr::Op(op, dest, a, b) => {
+                let v = match op {
+                    OpKind::Rand => Value::from_num(self.random() * self.get(&a).num()),
+                    _ if op.is_unary() => Value::from_num(op.eval_num(self.get(&a).num(), 0.0)),
+                    _ => eval_op(op, &self.get(&a), &self.get(&b)),
+                };
+                self.set(&dest, v);
+            }
+            Instr::Select(dest, cond, a, b, t, f) => {
+                let v = if self.check(cond, &a, &b) { self.get(&t) } else { self.get(&f) };
+                self.set(&dest, v);
+            }
+            Instr::Jump(target, cond, a, b) => {
+                if let Some(target) = target {
+                    if target < len && self.check(cond, &a, &b) {
+                        self.counter = target as f64;
+                    }
+                }
+            }
+            Instr::End => {
+                self.counter = len as f64;
+            }
+            Instr::Stop => {
+                self.counter = idx as f64;
+                self.stopped = true;
+                self.yielded = true;
+            }
+            Instr::Wait(secs) => {
+                let cur = *self.wait_time.get(&idx).unwrap_or(&0.0);
+                if cur >= self.get(&secs).num() {
+                    self.wait_time.insert(idx, 0.0);
+                }
+                else {
+                    self.counter = idx as f64;
+                    self.yielded = true;
+                    self.wait_time.insert(idx, cur + 1.0 / TICKS_PER_SECOND);
+                }
+            }
+            Instr::Print(val) => {
+                let text = self.to_string(&self.get(&val));
+                self.append_text(&text);
+            }
+            Instr::PrintChar(val) => {
+                let ch = match self.get(&val) {
+                    Value::Num(n) => char::from_u32(n.floor() as u32),
+                    _ => None,
+                };
+                if let Some(ch) = ch {
+                    self.append_text(&ch.to_string());
+                }
+            }
+            Instr::Format(val) => {
+                let text = self.to_string(&self.get(&val));
+                let bytes = self.text_buffer.as_bytes();
+                let mut best: Option<(usize, u8)> = None;
+                for i in 0..bytes.len().saturating_sub(2) {
+                    if
+                        bytes[i] == b'{' &&
+                        bytes[i + 1].is_ascii_digit() &&
+                        bytes[i + 2] == b'}' &&
+                        best.is_none_or(|x| bytes[i + 1] < x.1)
+                    {
+                        best = Some((i, bytes[i + 1]));
+                    }
+                }
+                if let Some((i, _)) = best {
+                    self.text_buffer.replace_range(i..i + 3, &text);
+                }
+            }
+            Instr::PrintFlush(target) => {
+                if let Value::Building(b) = self.get(&target) {
+                    let text = self.text_buffer.clone();
+                    let building = &mut self.buildings[b];
+                    if let BlockKind::Message { text: message } = &mut building.kind {
+                        *message = text.clone();
+                        self.output.push((building.name.clone(), text));
+                    }
+                }
+                self.text_buffer.clear();
+            }
+            Instr::PackColor(dest, r, g, b, a) => {
+                let v = pack_color(self.get(&r).num(), self.get(&g).num(), self.get(&b).num(), self.get(&a).num());
+                self.set(&dest, Value::Num(v));
+            }
+            Instr::UnpackColor(r, g, b, a, val) => {
+                let (vr, vg, vb, va) = unpack_color(self.get(&val).num());
+                self.set(&r, Value::Num(vr));
+                self.set(&g, Value::Num(vg));
+                self.set(&b, Value::Num(vb));
+                self.set(&a, Value::Num(va));
+            }
+            Instr::Lookup(kind, dest, index) => {
+                let i = self.get(&index).num();
+                let table: &[&str] = match kind.as_str() {
+                    "item" => &ITEMS,
+                    "liquid" => &LIQUIDS,
+                    _ => &[],
+                };
+                let v = if i >= 0.0 && (i as usize) < table.len() {
+                    Value::Content(table[i as usize].to_string())
+                }
+                else {
+                    Value::Null
+                };
+                self.set(&dest, v);
+            }
+            Instr::Draw(kind, ops) => {
+                if self.graphics_buffer.len() < MAX_GRAPHICS_BUFFER {
+                    let nums = ops.clone().map(|x| self.get(&x).num());
+                    let cmd = match kind {
+                        DrawKind::Col => {
+                            let (r, g, b, a) = unpack_color(nums[0]);
+                            DrawCmd {
+                                kind: DrawKind::Color,
+                                args: [r, g, b, a, 0.0, 0.0].map(|x| (x * 255.0) as i32),
+                                image: None,
+                            }
+                        }
+                        DrawKind::Clear | DrawKind::Color => DrawCmd {
+                            kind,
+                            args: nums.map(|x| (x as i32).clamp(0, 255)),
+                            image: None,
+                        },
+                        DrawKind::Image => DrawCmd {
+                            kind,
+                            args: nums.map(pack_sign),
+                            image: match self.get(&ops[2]) {
+                                Value::Content(c) => Some(c),
+                                _ => None,
+                            },
+                        },
+                        _ => DrawCmd { kind, args: nums.map(pack_sign), image: None },
+                    };
+                    self.graphics_buffer.push(cmd);
+                }
+            }
+            Instr::DrawFlush(target) => {
+                if let Value::Building(b) = self.get(&target) {
+                    if let BlockKind::Display { .. } = self.buildings[b].kind {
+                        let mut cmds = std::mem::take(&mut self.graphics_buffer);
+                        cmds.truncate(MAX_DISPLAY_BUFFER);
+                        self.draw_output.push((b, cmds));
+                    }
+                }
+                self.graphics_buffer.clear();
+            }
+            Instr::Read(dest, target, addr) => {
+                let addr = self.get(&addr).num();
+                let v = match self.get(&target) {
+                    Value::Building(b) => match &self.buildings[b].kind {
+                        BlockKind::Memory { memory, .. } => Value::Num(
+                            if addr < 0.0 || addr as usize >= memory.len() { 0.0 } else { memory[addr as usize] }
+                        ),
+                        BlockKind::Message { text } => match text.chars().nth(addr as usize) {
+                            Some(ch) if addr >= 0.0 => Value::Num(ch as u32 as f64),
+                            _ => Value::Null,
+                        },
+                        _ => {
+                            return true;
+                        }
+                    },
+                    Value::Str(text) => match text.chars().nth(addr as usize) {
+                        Some(ch) if addr >= 0.0 => Value::Num(ch as u32 as f64),
+                        _ => Value::Null,
+                    },
+                    _ => {
+                        return true;
+                    }
+                };
+                self.set(&dest, v);
+            }
+            Instr::Write(val, target, addr) => {
+                let addr = self.get(&addr).num();
+                let v = self.get(&val).num();
+                if let Value::Building(b) = self.get(&target) {
+                    if let BlockKind::Memory { memory, .. } = &mut self.buildings[b].kind {
+                        if addr >= 0.0 && (addr as usize) < memory.len() {
+                            memory[addr as usize] = v;
+                        }
+                    }
+                }
+            }
+            Instr::GetLink(dest, index) => {
+                let i = self.get(&index).num();
+                let v = if i >= 0.0 && (i as usize) < self.buildings.len() {
+                    Value::Building(i as usize)
+                }
+                else {
+                    Value::Null
+                };
+                self.set(&dest, v);
+            }
+            Instr::Sensor(dest, target, prop) => {
+                let v = match (self.get(&target), self.get(&prop)) {
+                    (Value::Building(b), Value::Content(prop)) => self.buildings[b].sensor(&prop),
+                    (Value::Str(text), Value::Content(prop)) if prop == "size" => Value::Num(text.chars().count() as f64),
+                    _ => Value::Null,
+                };
+                self.set(&dest, v);
+            }
+            Instr::Control(kind, target, val) => {
+                let v = self.get(&val);
+                if let Value::Building(b) = self.get(&target) {
+                    let building = &mut self.buildings[b];
+                    match (kind.as_str(), &mut building.kind) {
+                        ("enabled", _) => {
+                            building.enabled = v.num() != 0.0;
+                        }
+                        ("config", BlockKind::Sorter { item, .. }) => {
+                            *item = match v {
+                                Value::Content(c) => Some(c),
+                                _ => None,
+                            };
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        return true;
+    }
+
+    fn check(&self, cond: Cond, a: &Operand, b: &Operand) -> bool {
+        match cond.op() {
+            Some(op) => eval_op(op, &self.get(a), &self.get(b)).num() != 0.0,
+            None => true,
+        }
+    }
+
+    /// Executes the next instruction, first moving on to the next tick if
+    /// the current one used up its instructions or yielded. Used to step
+    /// through a program one instruction at a time.
+    pub fn advance(&mut self) -> bool {
+        if self.yielded || self.tick_steps >= self.ipt {
+            self.tick += 1;
+            self.tick_steps = 0;
+            self.yielded = false;
+        }
+        self.tick_steps += 1;
+        return self.step();
+    }
+
+    /// Runs one game tick, executing up to `ipt` instructions.
+    pub fn run_tick(&mut self) {
+        self.yielded = false;
+        for _ in 0..self.ipt {
+            if self.yielded || !self.step() {
+                break;
+            }
+        }
+        self.tick += 1;
+    }
+}
+
+/// Reads the `--ipt` and `--ticks` options shared by the emulator commands.
+pub fn emulator_opts(opts: &HashMap<String, String>) -> Result<(u64, u64), (String, ExitReason)> {
+    let mut parsed = [8, 600];
+    for (i, name) in ["ipt", "ticks"].iter().enumerate() {
+        if let Some(v) = opts.get(*name) {
+            parsed[i] = match v.parse::<u64>() {
+                Ok(x) if x > 0 => x,
+                _ => {
+                    return Err((
+                        format!("Option \"--{}\" expected a positive whole number, got \"{}\".", name, v),
+                        ExitReason::OptionBadValue,
+                    ));
+                }
+            };
+        }
+    }
+    return Ok((parsed[0], parsed[1]));
+}
+
+pub fn read_mlog(path: &str) -> Result<Program, (String, ExitReason)> {
+    let file = match read_to_string(path) {
+        Ok(f) => f,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                return Err((format!("File \"{}\" not found.", path), ExitReason::RunFileNotFound));
+            }
+            else {
+                return Err((format!("Reading file \"{}\" failed. Error: {}", path, e.kind()), ExitReason::RunFileNotFound));
+            }
+        }
+    };
+    return parse_mlog(&file);
+}
+
+pub fn run(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"run\" expected 1 argument. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let (ipt, ticks) = emulator_opts(opts)?;
+    info!("Reading file");
+    let program = read_mlog(&args[2])?;
+    info!("Parsed {} instructions", program.instructions.len());
+    if program.instructions.len() > MAX_INSTRUCTIONS {
+        warn!("Program has {} instructions, processors only accept {}.", program.instructions.len(), MAX_INSTRUCTIONS);
+    }
+    let buildings = vec![Building::new("message1", BlockKind::Message { text: String::new() })];
+    let mut emu = Emulator::new(program, buildings, ipt);
+    emu.once = flag_set(opts, "once");
+    drive(&mut emu, ticks, opts, |emu| {
+        for (_, text) in &emu.output {
+            println!("{}", text);
+        }
+    });
+    return Ok(());
+}
+
+/// Runs the emulator until it stops or `ticks` ticks passed, calling
+/// `on_tick` after every tick. Flushed messages and draw commands not taken
+/// out by `on_tick` are dropped afterwards.
+pub fn drive<F: FnMut(&mut Emulator)>(
+    emu: &mut Emulator,
+    ticks: u64,
+    opts: &HashMap<String, String>,
+    mut on_tick: F
+) {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let mut reported = 0;
+    while emu.tick < ticks && !emu.stopped {
+        emu.run_tick();
+        on_tick(emu);
+        emu.output.clear();
+        emu.draw_output.clear();
+        while reported < emu.unsupported.len() {
+            let idx = emu.unsupported[reported];
+            cwarn!("Unsupported instruction treated as noop: {}", emu.program.instructions[idx]);
+            reported += 1;
+        }
+    }
+    info!(
+        "Stopped after {} ticks ({}), counter at {}",
+        emu.tick,
+        if emu.wrapped && emu.once { "program ended" } else if emu.stopped { "stop instruction" } else { "tick limit" },
+        emu.counter
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::run_mlog;
+
+    fn op(op: &str, a: &str, b: &str) -> Value {
+        return eval_op(OpKind::parse(op).unwrap(), &parse_literal(a).unwrap(), &parse_literal(b).unwrap());
+    }
+
+    #[test]
+    fn equal_has_a_tolerance_and_strict_equal_does_not() {
+        assert_eq!(op("equal", "0.3", "0.30000000001"), Value::Num(1.0));
+        assert_eq!(op("notEqual", "0.3", "0.30000000001"), Value::Num(0.0));
+        assert_eq!(op("strictEqual", "0.3", "0.30000000001"), Value::Num(0.0));
+        assert_eq!(op("equal", "1", "1.001"), Value::Num(0.0));
+    }
+
+    #[test]
+    fn null_and_objects() {
+        assert_eq!(op("equal", "null", "0"), Value::Num(1.0));
+        assert_eq!(op("strictEqual", "null", "0"), Value::Num(0.0));
+        assert_eq!(op("strictEqual", "null", "null"), Value::Num(1.0));
+        assert_eq!(op("equal", "\"a\"", "\"a\""), Value::Num(1.0));
+        assert_eq!(op("equal", "@copper", "@lead"), Value::Num(0.0));
+        // objects are 1 in arithmetic, null is 0
+        assert_eq!(op("add", "\"a\"", "1"), Value::Num(2.0));
+        assert_eq!(op("add", "null", "1"), Value::Num(1.0));
+    }
+
+    #[test]
+    fn invalid_numbers_become_null() {
+        assert_eq!(op("div", "1", "0"), Value::Null);
+        assert_eq!(op("log", "-1", "0"), Value::Null);
+        assert_eq!(op("idiv", "-7", "2"), Value::Num(-4.0));
+        assert_eq!(op("mod", "-7", "2"), Value::Num(-1.0));
+        assert_eq!(op("emod", "-7", "2"), Value::Num(1.0));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(parse_literal("0x1F"), Some(Value::Num(31.0)));
+        assert_eq!(parse_literal("%ff0000"), Some(Value::Num(f64::from_bits(0xff0000ff))));
+        assert_eq!(parse_literal("@copper"), Some(Value::Content("copper".to_string())));
+        assert_eq!(parse_literal("@ctrlPlayer"), Some(Value::Num(2.0)));
+        assert_eq!(parse_literal("\"a\\nb\""), Some(Value::Str("a\nb".to_string())));
+        assert_eq!(parse_literal("x"), None);
+        for global in RUNTIME_GLOBALS {
+            assert_eq!(parse_literal(global), None, "{}", global);
+        }
+        assert_eq!(run_mlog("print @pi\nprint \" \"\nprint x\nprintflush message1", ""), "3.141592653589793 null");
+    }
+
+    #[test]
+    fn number_formatting() {
+        assert_eq!(fmt_num(3.0), "3");
+        assert_eq!(fmt_num(0.5), "0.5");
+        assert_eq!(fmt_num(2.000000001), "2");
+        assert_eq!(java_double(1.0), "1.0");
+        assert_eq!(java_double(1.5e20), "1.5E20");
+        assert_eq!(java_double(-1e-5), "-1.0E-5");
+    }
+
+    #[test]
+    fn pack_color_round_trips() {
+        let (r, g, b, a) = unpack_color(pack_color(1.0, 0.0, 0.2, 2.0));
+        assert_eq!((r, g, b, a), (1.0, 0.0, 0.2, 1.0));
+        assert_eq!(run_mlog("packcolor c 1 0 0 1\nunpackcolor r g b a c\nprint r\nprint g\nprint a\nprintflush message1", ""), "101");
+    }
+
+    #[test]
+    fn jumps_and_counter() {
+        let mlog = "set i 0\nop add i i 1\njump 1 lessThan i 5\nop add @counter @counter 1\nprint \"skipped\"\nprint i\nprintflush message1";
+        assert_eq!(run_mlog(mlog, ""), "5");
+    }
+
+    #[test]
+    fn wait_takes_simulated_time() {
+        let printed = run_mlog("wait 0.5\nprint @tick\nprint \" \"\nprint @time\nprintflush message1", "");
+        let (tick, time) = printed.split_once(' ').unwrap();
+        let tick = tick.parse::<f64>().unwrap();
+        assert!((30.0..=31.0).contains(&tick), "{}", printed);
+        assert_eq!(time.parse::<f64>().unwrap(), tick * 1000.0 / TICKS_PER_SECOND);
+    }
+
+    #[test]
+    fn ipt_limits_instructions_per_tick() {
+        let program = parse_mlog("op add x x 1\njump 0 always").unwrap();
+        let mut emu = Emulator::new(program, Vec::new(), 8);
+        emu.run_tick();
+        emu.run_tick();
+        let x = emu.program.var_names.iter().position(|x| x == "x").unwrap();
+        assert_eq!(emu.vars[x], Value::Num(8.0));
+        assert_eq!(emu.tick, 2);
+    }
+}
@@ -0,0 +1,101 @@
+//! Helpers shared by the tests of every module.
+
+use std::collections::HashMap;
+use crate::codegen::Ins;
+use crate::ast::{ Module, Span };
+use crate::emulator::{ self, Emulator };
+use crate::*;
+
+/// Options for a silent run, with the given ones set.
+pub fn opts(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    let mut opts = HashMap::<String, String>::new();
+    opts.insert("silent".to_string(), String::new());
+    for (key, value) in pairs {
+        opts.insert(key.to_string(), value.to_string());
+    }
+    return opts;
+}
+
+/// Parses a program without checking it.
+pub fn parse(source: &str) -> Result<Module, (String, ExitReason)> {
+    return compile::parse_source(source.to_string(), &opts(&[]));
+}
+
+/// Compiles a program to mlog.
+pub fn compile(source: &str, pairs: &[(&str, &str)]) -> Result<String, (String, ExitReason)> {
+    let opts = opts(pairs);
+    let root = compile::parse_source(source.to_string(), &opts)?;
+    return Ok(codegen::generate(&root, &opts)?.mlog);
+}
+
+/// The error compiling `source` fails with.
+pub fn error(source: &str) -> (String, ExitReason) {
+    return match compile(source, &[]) {
+        Ok(mlog) => panic!("expected an error, compiled to:\n{}", mlog),
+        Err(e) => e,
+    };
+}
+
+/// Instructions from mlog-like text, where `name:` lines are labels.
+pub fn code(text: &str) -> Vec<Ins> {
+    return text.lines().map(|line| match line.strip_suffix(':') {
+        Some(label) => Ins::Label(label.to_string()),
+        None => {
+            let mut words = line.split_whitespace().map(|x| x.to_string());
+            Ins::Code { op: words.next().unwrap(), args: words.collect(), span: Span::default() }
+        }
+    }).collect();
+}
+
+/// Writes instructions back in the form `code` reads.
+pub fn text(out: &[Ins]) -> String {
+    return out.iter().map(|ins| match ins {
+        Ins::Label(name) => format!("{}:", name),
+        Ins::Code { op, args, .. } => [op.clone()].into_iter().chain(args.iter().cloned()).collect::<Vec<_>>().join(" "),
+    }).collect::<Vec<_>>().join("\n");
+}
+
+/// Runs mlog once through with the blocks of a world file, or only
+/// `message1` if it is empty. Returns the text of every `printflush`,
+/// one per line.
+pub fn run_mlog(mlog: &str, world: &str) -> String {
+    let world = if world.is_empty() { "message1 = message" } else { world };
+    let program = emulator::parse_mlog(mlog).expect("valid mlog");
+    let buildings = world::parse_world(world).expect("valid world");
+    let mut emu = Emulator::new(program, buildings, 1000);
+    emu.once = true;
+    let mut flushed = Vec::<String>::new();
+    emulator::drive(&mut emu, 100, &opts(&[]), |emu| flushed.extend(emu.output.iter().map(|x| x.1.clone())));
+    return flushed.join("\n");
+}
+
+/// Compiles and runs a program at every optimization level, which must
+/// all print the same. Returns what was printed.
+pub fn run(source: &str, world: &str) -> String {
+    let mut printed = Vec::<String>::new();
+    for level in ["0", "1", "2"] {
+        let mlog = compile(source, &[("opt-level", level)]).unwrap_or_else(|e| panic!("{:?}", e));
+        printed.push(run_mlog(&mlog, world));
+    }
+    assert_eq!(printed[0], printed[1], "-O0 and -O1 differ");
+    assert_eq!(printed[0], printed[2], "-O0 and -O2 differ");
+    return printed.remove(0);
+}
+
+/// Runs every `test` block of `source` at every optimization level and
+/// fails with the first failed one.
+pub fn check_tests(source: &str, world: &str) {
+    let world = if world.is_empty() { "message1 = message" } else { world };
+    for level in ["0", "1", "2"] {
+        let opts = opts(&[("opt-level", level)]);
+        let root = compile::parse_source(source.to_string(), &opts).and_then(|x| codegen::prepare(&x, &opts));
+        let root = root.unwrap_or_else(|e| panic!("{:?}", e));
+        assert!(root.tests().count() > 0, "no tests");
+        for test in root.tests() {
+            let mlog = unittest::compile_test(&root, test, &opts).unwrap_or_else(|e| panic!("{:?}", e));
+            let buildings = world::parse_world(world).expect("valid world");
+            let failure = unittest::run_test(&mlog, buildings, 1000, 100, &opts).expect("valid mlog");
+            assert_eq!(failure, None, "test \"{}\" at -O{}:\n{}", test.name, level, mlog);
+        }
+    }
+}
@@ -15,6 +15,44 @@ Commands:                                                                 -
                                 it with a backslash and put it in quotation
                                 marks: <application file> compile \"\\--.txt\"
 
+    test [file] [world]         Compile every test \"name\" {{ ... }} block of
+                                the file on its own, run it on the built-in
+                                emulator and report which tests passed. A
+                                test fails when an assert!, assert_eq! or
+                                assert_ne! fails or it runs out of --ticks.
+                                Links a message1 block unless a world file
+                                is given (see simulate).
+
+    debug [file] [world]        Step through a program interactively on the
+                                built-in emulator. Source files are compiled
+                                first and every mlog instruction is shown
+                                next to the source line it came from. Set
+                                breakpoints by source line, watch variables;
+                                type \"help\" inside the debugger for more.
+                                Compiles with --opt-level 0 unless another
+                                level is given, since optimization can remove
+                                the code of a line.
+
+    run [file path]             Execute a compiled <file.mlog> headlessly on
+                                a built-in emulator with a message1 block,
+                                printing every printflush to stdout. Stops
+                                on a stop instruction or after --ticks.
+
+    simulate [file] [world]     Like run, but links the blocks listed in a
+                                world file: one \"<name> = <block> [opts]\"
+                                per line, e.g. \"cell1 = memory-cell\" or
+                                \"switch1 = switch enabled=false\". Supports
+                                message, memory-cell, memory-bank, switch,
+                                (large-)logic-display, (inverted-)sorter.
+                                Set memory with \"cell1[0] = 5\". Prints the
+                                final world state in the same format.
+
+    render [file] [world]       Run the program like simulate and write the
+                                content of every linked display to <file>.
+                                <display>.png. Without a world file, links
+                                a logic-display as display1. Base path can
+                                be set with --out-file.
+
     help [command/option]       Display this help message, shows additional
                                 about the provided command or option if one
                                 is provided. Options should be placed after
@@ -44,6 +82,55 @@ Options:                                                                  -
                                 [ DEBUG ] level. Be aware: this will likely
                                 generate large amounts of output to stdout.
 
+    --ipt [count]               Instructions the emulator executes per tick
+                                when running programs. Defaults to 8, same
+                                as a logic processor.
+
+    --ticks [count]             Maximum number of ticks (1/60 s of game time)
+                                the emulator runs for. Defaults to 600.
+
+    --once                      Stop the emulator once the program reaches
+                                its end instead of looping back to the top.
+
+    --all-frames                Make render write a numbered PNG for every
+                                drawflush, <file>.<display>.<frame>.png.
+
+    --source-map                Make compile also write <file.mlog>.map, a
+                                JSON file giving the source line and column
+                                range every mlog instruction came from.
+
+    --opt-level [level]         How much compile and test optimize the mlog.
+                                0 turns optimization off, 1 (the default)
+                                folds constants, removes unreachable code,
+                                needless jumps and unused compiler
+                                temporaries and merges wasteful pairs of
+                                instructions. Temporaries that never hold
+                                a value at the same time share one short
+                                name. 2 also removes unused program
+                                variables.
+
+    --explain-opt               Log every rewrite the peephole optimizer
+                                makes as a [ DEBUG ] entry, shown together
+                                with --debug.
+
+    --minify-names              Rename every variable the program assigns
+                                to a single CJK character, shrinking the
+                                code of size-limited schematics. Jumps
+                                already use instruction numbers. Writes
+                                <file.mlog>.names with one \"<short> =
+                                <name>\" line per renamed variable.
+
+    --emit [stage]              What compile writes: ast for the syntax tree
+                                (<file.ast>), ir for the optimized program
+                                split into basic blocks (<file.ir>) or mlog
+                                (<file.mlog>, the default). Options taking
+                                a value also accept --option=value.
+
+    --target [version]          Mindustry version the mlog is written for,
+                                7 (the default) or 8. On 8 print! prints
+                                its message once and fills the values in
+                                with the format instruction.
+
 ",
         filename,
         filename,
@@ -0,0 +1,261 @@
+//! Three-address intermediate representation between the syntax tree and
+//! mlog. `codegen` lowers the tree to a flat list of instructions, which is
+//! split here into basic blocks: straight-line code ending in one explicit
+//! exit. Jumps name blocks instead of labels, so the successors of every
+//! block are known without scanning for labels.
+
+use std::collections::{ HashMap, HashSet };
+use std::fmt::Display;
+use crate::codegen::Ins;
+use crate::ast::Span;
+use crate::optimize::{ self, Role };
+
+/// How control leaves a basic block.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exit {
+    /// Continue with the next block, or the first one after the last.
+    Next,
+    /// Jump to a block, always or when `cond` holds for the operands, and
+    /// otherwise continue with the next block.
+    Jump { target: usize, cond: String, operands: Vec<String>, span: Span },
+    /// `end`: start over at the first block.
+    End(Span),
+    /// `stop`: halt the processor.
+    Stop(Span),
+    /// The last instruction wrote `@counter`, which can lead anywhere.
+    Computed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Labels of the flat instruction list that pointed here.
+    pub labels: Vec<String>,
+    /// Instructions other than jumps, `end` and `stop`.
+    pub code: Vec<Ins>,
+    pub exit: Exit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ir {
+    pub blocks: Vec<Block>,
+}
+
+/// Whether an instruction writes `@counter`.
+fn writes_counter(op: &str, args: &[String]) -> bool {
+    return args.iter().zip(optimize::roles(op, args.len()).unwrap_or_default()).any(|(arg, role)| role == Role::Out && arg == "@counter");
+}
+
+impl Ir {
+    /// Splits a flat instruction list into basic blocks. Jumps to labels
+    /// the list does not contain are kept as instructions of their block.
+    pub fn build(out: &[Ins]) -> Ir {
+        let mut blocks = vec![Block { labels: Vec::<String>::new(), code: Vec::<Ins>::new(), exit: Exit::Next }];
+        // block every label belongs to, and jumps to resolve once known
+        let mut label_block = HashMap::<String, usize>::new();
+        let mut jumps = Vec::<(usize, String)>::new();
+        let labels = out.iter().filter_map(|x| match x {
+            Ins::Label(name) => Some(name.as_str()),
+            Ins::Code { .. } => None,
+        }).collect::<HashSet<_>>();
+        let new_block = |blocks: &mut Vec<Block>| blocks.push(Block { labels: Vec::<String>::new(), code: Vec::<Ins>::new(), exit: Exit::Next });
+        for ins in out {
+            let current = blocks.len() - 1;
+            match ins {
+                Ins::Label(name) => {
+                    if !blocks[current].code.is_empty() {
+                        new_block(&mut blocks);
+                    }
+                    blocks.last_mut().unwrap(/* never empty */).labels.push(name.clone());
+                    label_block.insert(name.clone(), blocks.len() - 1);
+                }
+                Ins::Code { op, args, span } => {
+                    let exit = match op.as_str() {
+                        "end" => Exit::End(*span),
+                        "stop" => Exit::Stop(*span),
+                        "jump" if args.len() >= 2 && labels.contains(args[0].as_str()) => {
+                            jumps.push((current, args[0].clone()));
+                            Exit::Jump { target: 0, cond: args[1].clone(), operands: args[2..].to_vec(), span: *span }
+                        }
+                        _ if writes_counter(op, args) => {
+                            blocks[current].code.push(ins.clone());
+                            Exit::Computed
+                        }
+                        _ => {
+                            blocks[current].code.push(ins.clone());
+                            continue;
+                        }
+                    };
+                    blocks[current].exit = exit;
+                    new_block(&mut blocks);
+                }
+            }
+        }
+        for (block, label) in jumps {
+            if let Exit::Jump { target, .. } = &mut blocks[block].exit {
+                *target = label_block[&label];
+            }
+        }
+        // the block opened after the last exit stays only if jumps land there
+        if blocks.len() > 1 && blocks.last().is_some_and(|x| x.labels.is_empty() && x.code.is_empty()) {
+            blocks.pop();
+        }
+        return Ir { blocks };
+    }
+
+    /// Blocks control can continue with after block `idx`.
+    pub fn successors(&self, idx: usize) -> Vec<usize> {
+        let next = (idx + 1) % self.blocks.len();
+        match &self.blocks[idx].exit {
+            Exit::Next => vec![next],
+            Exit::Jump { target, cond, .. } if cond == "always" => vec![*target],
+            Exit::Jump { target, .. } => vec![*target, next],
+            Exit::End(_) => vec![0],
+            Exit::Stop(_) => vec![],
+            Exit::Computed => (0..self.blocks.len()).collect(),
+        }
+    }
+
+    /// Drops the blocks control never reaches from the first one. Returns
+    /// the number of dropped instructions.
+    pub fn remove_unreachable(&mut self) -> usize {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            if idx >= reachable.len() || reachable[idx] {
+                continue;
+            }
+            reachable[idx] = true;
+            stack.extend(self.successors(idx));
+        }
+        let mut new_idx = vec![0; self.blocks.len()];
+        let mut removed = 0;
+        let mut count = 0;
+        for (idx, block) in self.blocks.iter().enumerate() {
+            if reachable[idx] {
+                new_idx[idx] = count;
+                count += 1;
+            }
+            else {
+                removed += block.code.len() + if matches!(block.exit, Exit::Next | Exit::Computed) { 0 } else { 1 };
+            }
+        }
+        let mut reachable = reachable.into_iter();
+        self.blocks.retain(|_| reachable.next().unwrap_or(true));
+        for block in &mut self.blocks {
+            if let Exit::Jump { target, .. } = &mut block.exit {
+                *target = new_idx[*target];
+            }
+        }
+        return removed;
+    }
+
+    /// Label a jump to block `idx` uses when lowered.
+    fn label(&self, idx: usize) -> String {
+        return self.blocks[idx].labels.first().cloned().unwrap_or_else(|| format!("__B{}", idx));
+    }
+
+    /// Turns the blocks back into a flat instruction list. Only labels
+    /// jumps go to are kept.
+    pub fn lower(&self) -> Vec<Ins> {
+        let mut targets = vec![false; self.blocks.len()];
+        for block in &self.blocks {
+            if let Exit::Jump { target, .. } = block.exit {
+                targets[target] = true;
+            }
+        }
+        let mut out = Vec::<Ins>::new();
+        for (idx, block) in self.blocks.iter().enumerate() {
+            if targets[idx] {
+                out.push(Ins::Label(self.label(idx)));
+            }
+            out.extend(block.code.iter().cloned());
+            match &block.exit {
+                Exit::Jump { target, cond, operands, span } => {
+                    let mut args = vec![self.label(*target), cond.clone()];
+                    args.extend(operands.iter().cloned());
+                    out.push(Ins::Code { op: "jump".to_string(), args, span: *span });
+                }
+                Exit::End(span) => out.push(Ins::Code { op: "end".to_string(), args: vec![], span: *span }),
+                Exit::Stop(span) => out.push(Ins::Code { op: "stop".to_string(), args: vec![], span: *span }),
+                Exit::Next | Exit::Computed => {}
+            }
+        }
+        return out;
+    }
+}
+
+impl Display for Ir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let succ = self.successors(idx).iter().map(|x| format!("b{}", x)).collect::<Vec<_>>();
+            write!(f, "b{}:", idx)?;
+            if !block.labels.is_empty() {
+                write!(f, " ({})", block.labels.join(", "))?;
+            }
+            writeln!(f, " -> [{}]", succ.join(", "))?;
+            for ins in &block.code {
+                if let Ins::Code { op, args, .. } = ins {
+                    writeln!(f, "    {}", [op.clone()].iter().chain(args).cloned().collect::<Vec<_>>().join(" "))?;
+                }
+            }
+            match &block.exit {
+                Exit::Jump { target, cond, operands, .. } => {
+                    let words = [format!("b{}", target), cond.clone()].into_iter().chain(operands.iter().cloned()).collect::<Vec<_>>();
+                    writeln!(f, "    jump {}", words.join(" "))?;
+                }
+                Exit::End(_) => writeln!(f, "    end")?,
+                Exit::Stop(_) => writeln!(f, "    stop")?,
+                Exit::Next | Exit::Computed => {}
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use crate::harness::{ code, opts, text };
+
+    const LOOP: &str = "set i 0\nL:\nop add i i 1\njump L lessThan i 5\nprint i\nstop\nprint 0";
+
+    #[test]
+    fn blocks_and_edges() {
+        let ir = Ir::build(&code(LOOP));
+        assert_eq!(ir.to_string(), "b0: -> [b1]\n    set i 0\nb1: (L) -> [b1, b2]\n    op add i i 1\n    jump b1 lessThan i 5\nb2: -> []\n    print i\n    stop\nb3: -> [b0]\n    print 0\n");
+        assert_eq!(ir.successors(3), vec![0]);
+    }
+
+    #[test]
+    fn exits() {
+        let ir = Ir::build(&code("set @counter x\nend\nop add @counter @counter 1\njump X always"));
+        let exits = ir.blocks.iter().map(|x| x.exit.clone()).collect::<Vec<_>>();
+        assert_eq!(exits, vec![Exit::Computed, Exit::End(Span::default()), Exit::Computed, Exit::Next]);
+        // a jump to a label that does not exist stays an instruction
+        assert_eq!(text(&ir.blocks[3].code), "jump X always");
+        assert_eq!(ir.successors(0), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn unreachable_blocks_and_lowering() {
+        let mut ir = Ir::build(&code(LOOP));
+        assert_eq!(ir.remove_unreachable(), 1);
+        assert_eq!(text(&ir.lower()), "set i 0\nL:\nop add i i 1\njump L lessThan i 5\nprint i\nstop");
+        // labels no jump uses are dropped
+        let ir = Ir::build(&code("jump A equal x 1\nB:\nprint 1\nA:\nprint 2"));
+        assert_eq!(text(&ir.lower()), "jump A equal x 1\nprint 1\nA:\nprint 2");
+    }
+
+    #[test]
+    fn emitted_for_programs() {
+        let opts = opts(&[("opt-level", "0")]);
+        let source = "let x = @links;\nmatch x { 1 => { print(\"one\"); } _ => { print(\"many\"); } }";
+        let generated = codegen::generate(&compile::parse_source(source.to_string(), &opts).unwrap(), &opts).unwrap();
+        let blocks = "b0: -> [b2, b1]\n    set x @links\n    jump b2 equal x 1\nb1: -> [b3]\n    jump b3 always\nb2: (__L1) -> [b4]\n    print \"one\"\n    jump b4 always\nb3: (__L2) -> [b4]\n    print \"many\"\n    jump b4 always\nb4: (__L0) -> [b0]\n";
+        assert_eq!(generated.ir.to_string(), blocks);
+        // jumps to the last label need an added `end` to land on
+        assert_eq!(text(&generated.ir.lower()).lines().filter(|x| !x.ends_with(':')).count() + 1, generated.mlog.lines().count());
+        assert!(generated.mlog.ends_with("jump 7 always\nend\n"));
+    }
+}
@@ -7,16 +7,16 @@ macro_rules! log {
         $decoration:expr,
         $($params:tt)*
     ) => {
-        if !crate::flag_set(opts!(), "silent") {
+        if !$crate::flag_set(opts!(), "silent") {
             let e_logger_msg = format!("{}", format!($($params)*));
             if (e_logger_msg.contains("\n")) {
                 for e_logger_line in e_logger_msg.split("\n") {
                     let e_logger_line = e_logger_line.strip_prefix("\r").unwrap_or(e_logger_line);
-                    println!("[{}{} {} {}[0m]: {}", crate::logging::ESCAPE, $color, $decoration, crate::logging::ESCAPE, e_logger_line);
+                    println!("[{}{} {} {}[0m]: {}", $crate::logging::ESCAPE, $color, $decoration, $crate::logging::ESCAPE, e_logger_line);
                 }
             }
             else {
-                println!("[{}{} {} {}[0m]: {}", crate::logging::ESCAPE, $color, $decoration, crate::logging::ESCAPE, e_logger_msg);
+                println!("[{}{} {} {}[0m]: {}", $crate::logging::ESCAPE, $color, $decoration, $crate::logging::ESCAPE, e_logger_msg);
             }
         }
     };
@@ -25,7 +25,7 @@ macro_rules! log {
 #[macro_export]
 macro_rules! debug {
     ($($params:tt)*) => {
-        if crate::flag_set(opts!(), "debug") {
+        if $crate::flag_set(opts!(), "debug") {
             log!("[1;35m", "DEBUG", $($params)*);
         }
     };
@@ -34,7 +34,7 @@ macro_rules! debug {
 #[macro_export]
 macro_rules! info {
     ($($params:tt)*) => {
-        if crate::flag_set(opts!(), "verbose") || crate::flag_set(opts!(), "debug") {
+        if $crate::flag_set(opts!(), "verbose") || $crate::flag_set(opts!(), "debug") {
             log!("[1;36m", "INFO", $($params)*);
         }
     };
@@ -43,7 +43,7 @@ macro_rules! info {
 #[macro_export]
 macro_rules! ok {
     ($($params:tt)*) => {
-        if !crate::flag_set(opts!(), "soft-silent") {
+        if !$crate::flag_set(opts!(), "soft-silent") {
             log!("[1;32m", "OK", $($params)*);
         }
     };
@@ -52,7 +52,7 @@ macro_rules! ok {
 #[macro_export]
 macro_rules! cwarn {
     ($($params:tt)*) => {
-        if !crate::flag_set(opts!(), "soft-silent") && !crate::flag_set(opts!(), "no-warn") {
+        if !$crate::flag_set(opts!(), "soft-silent") && !$crate::flag_set(opts!(), "no-warn") {
             log!("[1;33m", "WARN", $($params)*);
         }
     };
@@ -61,7 +61,7 @@ macro_rules! cwarn {
 #[macro_export]
 macro_rules! warn {
     ($($params:tt)*) => {
-        if !crate::flag_set(opts!(), "soft-silent") {
+        if !$crate::flag_set(opts!(), "soft-silent") {
             log!("[1;33m", "WARN", $($params)*);
         }
     };
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_return)]
+
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
@@ -8,14 +10,30 @@ mod argparse;
 mod help;
 mod commands;
 mod tokenize;
+mod ast;
 mod compile;
 mod logging;
+mod emulator;
+mod world;
+mod png;
+mod render;
+mod scope;
+mod consts;
+mod typecheck;
+mod codegen;
+mod ir;
+mod optimize;
+mod names;
+mod unittest;
+mod debugger;
+#[cfg(test)]
+mod harness;
 
 use argparse::{ parse_args, flag_set };
 use help::default_help_msg;
 use commands::run_command;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum ExitReason {
     #[allow(dead_code)]
     OK,
@@ -26,10 +44,44 @@ enum ExitReason {
     CommandExpectedInputArgument,
     CompileCharTooLong,
     CompileBadTokenAfterIdentifier,
+    #[allow(dead_code)]
     CompileWipArgsUnwrapFailed,
+    #[allow(dead_code)]
     CompileFuncArgNotValue,
     IncompatibleLogLevelFlags,
     CompileExpectedParenthesisAfterMacro,
+    RunFileNotFound,
+    OptionBadValue,
+    WorldBadLine,
+    RenderWriteFailed,
+    CompileUnexpectedToken,
+    CompileExpectedValue,
+    CompileBadItemDef,
+    CompileDuplicateItem,
+    CompileUnknownOperator,
+    CompileUnknownFunction,
+    CompileUnknownMacro,
+    CompileRecursiveFunction,
+    CompileWrongArgCount,
+    CompileUnusedValue,
+    CompileOutputConflict,
+    CompileWriteFailed,
+    TestFailed,
+    CompileUnknownType,
+    CompileTypeMismatch,
+    CompileNotConstant,
+    CompileUnknownVariant,
+    CompileAssignToConst,
+    CompileBadMatch,
+    CompileBadArray,
+    CompileIndexOutOfBounds,
+    CompileBadStruct,
+    CompileBadFormat,
+    CompileBadEscape,
+    CompileBadNumber,
+    CompileBadChar,
+    CompileUnterminatedComment,
+    CompileReservedName,
 }
 
 #[macro_export]
@@ -63,7 +115,7 @@ fn main() -> Result<(), String> {
     let mut args = Vec::<String>::new();
     let filename = Path::new(&argv[0])
         .file_name()
-        .unwrap_or(&OsStr::new("HOW-DID-YOU-EXECUTE-A-DIRECTORY"))
+        .unwrap_or(OsStr::new("HOW-DID-YOU-EXECUTE-A-DIRECTORY"))
         .to_str()
         .unwrap_or("INVALID-FILE-NAME");
     unwrap!(parse_args(argv.clone(), &mut opts, &mut args));
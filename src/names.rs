@@ -0,0 +1,96 @@
+//! Short variable names drawn from the table of CJK characters shipped with
+//! the repository. Every character is a single letter to the game, so each
+//! name is one character long.
+
+use std::collections::{ HashMap, HashSet };
+use crate::codegen::Ins;
+use crate::optimize::{ self, Role };
+
+const CHARACTERS: &str = include_str!("../chinese_characters.txt");
+
+/// Single character names not in `used`, in table order. Should the table
+/// ever run out, `__r0`, `__r1` and so on follow.
+pub fn short_names(used: &HashSet<String>) -> impl Iterator<Item = String> + '_ {
+    return CHARACTERS
+        .chars()
+        .filter(|x| !x.is_whitespace())
+        .map(|x| x.to_string())
+        .chain((0..).map(|x| format!("__r{}", x)))
+        .filter(|x| !used.contains(x));
+}
+
+/// Renames every variable the program writes whose name is longer than
+/// one character to a single character. Variables that are only read,
+/// like links to buildings, keep their names as the game assigns those.
+/// Variables used by instructions the optimizer does not know are kept
+/// too. Returns the new and original name of every renamed variable.
+pub fn minify(out: &mut [Ins]) -> Vec<(String, String)> {
+    let mut written = Vec::<String>::new();
+    let mut unknown = HashSet::<String>::new();
+    let mut all = HashSet::<String>::new();
+    for ins in out.iter() {
+        if let Ins::Code { op, args, .. } = ins {
+            all.extend(args.iter().cloned());
+            match optimize::roles(op, args.len()) {
+                Some(arg_roles) => {
+                    for (arg, role) in args.iter().zip(arg_roles) {
+                        if role == Role::Out && !written.contains(arg) {
+                            written.push(arg.clone());
+                        }
+                    }
+                }
+                None => unknown.extend(args.iter().cloned()),
+            }
+        }
+    }
+    written.retain(|x| x.chars().count() > 1 && !x.starts_with('@') && !unknown.contains(x));
+    all.retain(|x| !written.contains(x));
+
+    let renames = written.iter().cloned().zip(short_names(&all)).collect::<HashMap<_, _>>();
+    for ins in out.iter_mut() {
+        if let Ins::Code { op, args, .. } = ins {
+            let arg_roles = optimize::roles(op, args.len()).unwrap_or_default();
+            for (arg, role) in args.iter_mut().zip(arg_roles) {
+                if role != Role::Other {
+                    if let Some(renamed) = renames.get(arg.as_str()) {
+                        *arg = renamed.clone();
+                    }
+                }
+            }
+        }
+    }
+    return written.into_iter().map(|x| (renames[&x].clone(), x)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use crate::harness::{ compile, opts, run_mlog };
+
+    const SOURCE: &str = "let count = @links;\nlet x = count * 2;\nlet total = x + count;\n`ucontrol within 1 2 3 inside 0`;\nprint(total);\nprint(inside);\nprintflush(message1);";
+
+    #[test]
+    fn renames_written_variables() {
+        let minified = compile(SOURCE, &[("opt-level", "0"), ("minify-names", "")]).unwrap();
+        // single characters, links and variables of unknown instructions stay
+        assert_eq!(minified, "set 一 @links\nop mul x 一 2\nop add 丁 x 一\nucontrol within 1 2 3 inside 0\nprint 丁\nprint inside\nprintflush message1\n");
+        let plain = compile(SOURCE, &[("opt-level", "0")]).unwrap();
+        assert_eq!(run_mlog(&minified, ""), run_mlog(&plain, ""));
+    }
+
+    #[test]
+    fn name_map() {
+        let opts = opts(&[("opt-level", "0"), ("minify-names", "")]);
+        let generated = codegen::generate(&compile::parse_source(SOURCE.to_string(), &opts).unwrap(), &opts).unwrap();
+        assert_eq!(generated.names, vec![("一".to_string(), "count".to_string()), ("丁".to_string(), "total".to_string())]);
+    }
+
+    #[test]
+    fn short_names_skip_used_ones() {
+        let used = HashSet::from(["丁".to_string(), "丄".to_string()]);
+        assert_eq!(short_names(&used).take(4).collect::<Vec<_>>(), vec!["一", "丂", "七", "丅"]);
+        let count = CHARACTERS.chars().filter(|x| !x.is_whitespace()).count();
+        assert_eq!(short_names(&HashSet::new()).nth(count), Some("__r0".to_string()));
+    }
+}
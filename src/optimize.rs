@@ -0,0 +1,854 @@
+//! Optimization passes over the instruction model built by `codegen`.
+//! Passes only rewrite or drop instructions, so every remaining
+//! instruction keeps the source span it was generated from.
+
+use std::collections::{ HashMap, HashSet };
+use crate::codegen::Ins;
+use crate::ast::Span;
+use crate::ir::{ Exit, Ir };
+use crate::emulator::{ Cond, OpKind, Value };
+use crate::*;
+
+/// How an instruction uses one of its arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// The value is read.
+    In,
+    /// A variable is written.
+    Out,
+    /// A keyword, label or jump condition.
+    Other,
+}
+
+/// Roles of the arguments of an instruction. `None` for instructions the
+/// optimizer does not know, which have to be treated as reading and
+/// writing anything.
+pub fn roles(op: &str, argc: usize) -> Option<Vec<Role>> {
+    use Role::*;
+    let table: &[Role] = match op {
+        "noop" | "end" | "stop" => &[],
+        "set" => &[Out, In],
+        "op" => &[Other, Out, In, In],
+        "select" => &[Out, Other, In, In, In, In],
+        "jump" => &[Other, Other, In, In],
+        "wait" | "print" | "printchar" | "format" | "printflush" | "drawflush" | "ubind" => &[In],
+        "packcolor" => &[Out, In, In, In, In],
+        "unpackcolor" => &[Out, Out, Out, Out, In],
+        "lookup" => &[Other, Out, In],
+        "read" => &[Out, In, In],
+        "write" => &[In, In, In],
+        "getlink" => &[Out, In],
+        "sensor" => &[Out, In, In],
+        "control" | "draw" => &[Other, In, In, In, In, In, In],
+        _ => {
+            return None;
+        }
+    };
+    return Some((0..argc).map(|i| table.get(i).copied().unwrap_or(Other)).collect());
+}
+
+/// Whether `var` is a compiler temporary. Names starting with `__` are
+/// reserved for the code generator.
+fn is_temp(var: &str) -> bool {
+    return var.starts_with("__");
+}
+
+/// Instructions without side effects, which can be dropped when nothing
+/// reads the variables they write.
+const PURE: [&str; 9] = ["set", "op", "select", "packcolor", "unpackcolor", "lookup", "read", "getlink", "sensor"];
+
+/// Reads the `--opt-level` option: 0 turns the optimizer off, 1 (default)
+/// folds and propagates constants, cleans up control flow, applies the
+/// peephole rules and drops unused compiler temporaries, 2 also lets those
+/// remove program variables.
+pub fn opt_level(opts: &HashMap<String, String>) -> Result<u8, (String, ExitReason)> {
+    return match opts.get("opt-level").map(|x| x.as_str()) {
+        None => Ok(1),
+        Some("0") => Ok(0),
+        Some("1") => Ok(1),
+        Some("2") => Ok(2),
+        Some(v) => Err((
+            format!("Option \"--opt-level\" expected 0, 1 or 2, got \"{}\".", v),
+            ExitReason::OptionBadValue,
+        )),
+    };
+}
+
+/// Writes a computed value as an mlog literal. Very large and very small
+/// numbers are written with an exponent, like number literals.
+pub fn to_literal(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => Some("null".to_string()),
+        Value::Num(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => Some(format!("{}", *n as i64)),
+        // packed colors are tiny doubles holding the rgba bits
+        Value::Num(n) if *n > 0.0 && n.to_bits() <= u32::MAX as u64 => Some(format!("%{:08x}", n.to_bits())),
+        Value::Num(n) if (1e-3..1e16).contains(&n.abs()) => Some(format!("{}", n)),
+        Value::Num(n) if n.is_finite() => Some(format!("{:e}", n)),
+        _ => None,
+    }
+}
+
+/// Computes an instruction whose operands are all literals, following the
+/// emulator's numeric rules. Returns the replacement: `Some(None)` when
+/// the instruction can be dropped, `None` when it cannot be folded.
+fn fold(op: &str, args: &[String]) -> Option<Option<(String, Vec<String>)>> {
+    let lit = |i: usize| emulator::parse_literal(args.get(i).map(|x| x.as_str()).unwrap_or("null"));
+    match op {
+        "op" => {
+            let kind = OpKind::parse(args.first()?)?;
+            if kind == OpKind::Rand || kind == OpKind::Noise {
+                return None;
+            }
+            let b = if kind.is_unary() { Value::Null } else { lit(3)? };
+            let result = to_literal(&emulator::eval_op(kind, &lit(2)?, &b))?;
+            return Some(Some(("set".to_string(), vec![args.get(1)?.clone(), result])));
+        }
+        "jump" => {
+            let kind = Cond::parse(args.get(1)?)?.op()?;
+            if emulator::eval_op(kind, &lit(2)?, &lit(3)?).num() != 0.0 {
+                return Some(Some(("jump".to_string(), vec![args[0].clone(), "always".to_string()])));
+            }
+            return Some(None);
+        }
+        "packcolor" => {
+            let c = |i: usize| lit(i).map(|x| x.num());
+            let color = emulator::pack_color(c(1)?, c(2)?, c(3)?, c(4)?);
+            return Some(Some(("set".to_string(), vec![args.first()?.clone(), to_literal(&Value::Num(color))?])));
+        }
+        "select" => {
+            let taken = match Cond::parse(args.get(1)?)?.op() {
+                None => true,
+                Some(kind) => emulator::eval_op(kind, &lit(2)?, &lit(3)?).num() != 0.0,
+            };
+            let value = args.get(if taken { 4 } else { 5 })?.clone();
+            return Some(Some(("set".to_string(), vec![args[0].clone(), value])));
+        }
+        _ => {
+            return None;
+        }
+    }
+}
+
+/// Replaces reads of variables holding a known literal with the literal
+/// and folds instructions computing only literals. Values are only known
+/// within straight-line code: every label is a point other code can jump
+/// to, so everything known is forgotten there. Returns the number of
+/// rewritten instructions.
+fn propagate(out: &mut Vec<Ins>) -> usize {
+    let mut known = HashMap::<String, String>::new();
+    let mut changes = 0;
+    let mut i = 0;
+    while i < out.len() {
+        let (op, args) = match &mut out[i] {
+            Ins::Label(_) => {
+                known.clear();
+                i += 1;
+                continue;
+            }
+            Ins::Code { op, args, .. } => (op, args),
+        };
+        let arg_roles = match roles(op, args.len()) {
+            Some(r) => r,
+            None => {
+                known.clear();
+                i += 1;
+                continue;
+            }
+        };
+        let mut changed = false;
+        for (arg, role) in args.iter_mut().zip(&arg_roles) {
+            if *role == Role::In {
+                if let Some(value) = known.get(arg.as_str()) {
+                    *arg = value.clone();
+                    changed = true;
+                }
+            }
+        }
+        match fold(op, args) {
+            Some(Some((new_op, new_args))) => {
+                *op = new_op;
+                *args = new_args;
+                changed = true;
+            }
+            Some(None) => {
+                out.remove(i);
+                changes += 1;
+                continue;
+            }
+            None => {}
+        }
+        // folding may have changed the instruction, so look its roles up again
+        for (arg, role) in args.iter().zip(roles(op, args.len()).unwrap_or_default()) {
+            if role == Role::Out {
+                known.remove(arg.as_str());
+                if arg == "@counter" {
+                    known.clear();
+                }
+            }
+        }
+        if op == "set" && args.len() == 2 && args[0] != "@counter" && emulator::parse_literal(&args[1]).is_some() {
+            known.insert(args[0].clone(), args[1].clone());
+        }
+        if changed {
+            changes += 1;
+        }
+        i += 1;
+    }
+    return changes;
+}
+
+/// Drops side effect free instructions writing only variables nothing
+/// reads. With `all_vars` unset only compiler temporaries are considered. Returns the number of dropped instructions.
+fn drop_unused(out: &mut Vec<Ins>, all_vars: bool) -> usize {
+    let mut reads = HashMap::<&str, usize>::new();
+    for ins in out.iter() {
+        if let Ins::Code { op, args, .. } = ins {
+            let arg_roles = roles(op, args.len());
+            for (i, arg) in args.iter().enumerate() {
+                let role = arg_roles.as_ref().map(|x| x[i]).unwrap_or(Role::In);
+                if role == Role::In {
+                    *reads.entry(arg.as_str()).or_default() += 1;
+                }
+            }
+        }
+    }
+    let unused = |ins: &Ins| match ins {
+        Ins::Code { op, args, .. } if PURE.contains(&op.as_str()) => {
+            args.iter().zip(roles(op, args.len()).unwrap_or_default()).all(|(arg, role)| {
+                role != Role::Out || (
+                    !reads.contains_key(arg.as_str()) &&
+                    !arg.starts_with('@') &&
+                    (all_vars || is_temp(arg))
+                )
+            })
+        }
+        _ => false,
+    };
+    let keep = out.iter().map(|x| !unused(x)).collect::<Vec<_>>();
+    let before = out.len();
+    let mut keep = keep.into_iter();
+    out.retain(|_| keep.next().unwrap_or(true));
+    return before - out.len();
+}
+
+/// Label a jump instruction goes to.
+fn jump_target(ins: &Ins) -> Option<&str> {
+    match ins {
+        Ins::Code { op, args, .. } if op == "jump" => args.first().map(|x| x.as_str()),
+        _ => None,
+    }
+}
+
+/// Whether execution never continues with the next instruction.
+fn ends_flow(ins: &Ins) -> bool {
+    match ins {
+        Ins::Code { op, args, .. } => match op.as_str() {
+            "end" | "stop" => true,
+            "jump" => args.get(1).is_some_and(|x| x == "always"),
+            _ => args.iter().zip(roles(op, args.len()).unwrap_or_default()).any(|(arg, role)| role == Role::Out && arg == "@counter"),
+        },
+        Ins::Label(_) => false,
+    }
+}
+
+/// Cleans up control flow:
+/// - jumps to a `jump ... always` go straight to where that one leads,
+/// - jumps to the instruction right after them are dropped,
+/// - blocks of the IR control never reaches are dropped,
+/// - labels no jump refers to are dropped, so constants can be
+///   propagated past them.
+///
+/// Returns the number of changed or dropped instructions and labels.
+fn simplify_flow(out: &mut Vec<Ins>) -> usize {
+    let mut changes = 0;
+    let mut labels = HashMap::<String, usize>::new();
+    for (i, ins) in out.iter().enumerate() {
+        if let Ins::Label(name) = ins {
+            labels.insert(name.clone(), i);
+        }
+    }
+    // first instruction executed after jumping to a label
+    let landing = |out: &Vec<Ins>, label: &str| {
+        let start = *labels.get(label)?;
+        return out[start..].iter().find(|x| matches!(x, Ins::Code { .. })).cloned();
+    };
+
+    for i in 0..out.len() {
+        let mut target = match jump_target(&out[i]) {
+            Some(t) => t.to_string(),
+            None => continue,
+        };
+        let mut seen = vec![target.clone()];
+        while let Some(next) = landing(out, &target).filter(ends_flow).and_then(|x| jump_target(&x).map(|x| x.to_string())) {
+            if seen.contains(&next) {
+                break;
+            }
+            seen.push(next.clone());
+            target = next;
+        }
+        if let Ins::Code { args, .. } = &mut out[i] {
+            if args[0] != target {
+                args[0] = target;
+                changes += 1;
+            }
+        }
+    }
+
+    let mut i = 0;
+    while i < out.len() {
+        let to_next = jump_target(&out[i]).is_some_and(|target| {
+            out[i + 1..].iter().take_while(|x| matches!(x, Ins::Label(_))).any(|x| *x == Ins::Label(target.to_string()))
+        });
+        if to_next {
+            out.remove(i);
+            changes += 1;
+        }
+        else {
+            i += 1;
+        }
+    }
+
+    let before = out.len();
+    let mut graph = Ir::build(out);
+    graph.remove_unreachable();
+    *out = graph.lower();
+    return changes + before - out.len();
+}
+
+/// What peephole rules may rely on besides the instructions they look at.
+struct Context {
+    /// How often every variable is read in the whole program.
+    reads: HashMap<String, usize>,
+    /// Whether program variables may disappear, not only temporaries.
+    all_vars: bool,
+}
+
+impl Context {
+    /// Whether `var` is read exactly once, so the instruction reading it
+    /// can take its value from somewhere else.
+    fn read_once(&self, var: &str) -> bool {
+        return (self.all_vars || is_temp(var)) && !var.starts_with('@') && self.reads.get(var) == Some(&1);
+    }
+}
+
+/// An instruction as peephole rules see it: operation and arguments.
+type Code<'a> = (&'a str, &'a [String]);
+
+/// Instructions a peephole rule replaces its window with.
+type Replacement = Vec<(String, Vec<String>)>;
+
+/// A peephole rule: looks at `window` instructions in a row, without a
+/// label between them, and returns what to replace them with.
+struct Rule {
+    name: &'static str,
+    window: usize,
+    rewrite: fn(&[Code], &Context) -> Option<Replacement>,
+}
+
+const RULES: [Rule; 4] = [
+    Rule { name: "self-set", window: 1, rewrite: rule_self_set },
+    Rule { name: "compare-and-jump", window: 2, rewrite: rule_compare_and_jump },
+    Rule { name: "forward-set", window: 2, rewrite: rule_forward_set },
+    Rule { name: "write-directly", window: 2, rewrite: rule_write_directly },
+];
+
+/// `set x x` does nothing.
+fn rule_self_set(ins: &[Code], _: &Context) -> Option<Replacement> {
+    let (op, args) = ins[0];
+    if op == "set" && args.len() == 2 && args[0] == args[1] {
+        return Some(vec![]);
+    }
+    return None;
+}
+
+/// Condition a comparison `op` becomes as a jump, and the one testing the
+/// opposite.
+fn jump_conds(op: &str) -> Option<(&'static str, Option<&'static str>)> {
+    Some(match op {
+        "equal" => ("equal", Some("notEqual")),
+        "notEqual" => ("notEqual", Some("equal")),
+        "lessThan" => ("lessThan", Some("greaterThanEq")),
+        "lessThanEq" => ("lessThanEq", Some("greaterThan")),
+        "greaterThan" => ("greaterThan", Some("lessThanEq")),
+        "greaterThanEq" => ("greaterThanEq", Some("lessThan")),
+        "strictEqual" => ("strictEqual", None),
+        _ => {
+            return None;
+        }
+    })
+}
+
+/// `op lessThan t a b` + `jump L equal t false` is `jump L greaterThanEq a b`,
+/// and with `notEqual` it is `jump L lessThan a b`.
+fn rule_compare_and_jump(ins: &[Code], ctx: &Context) -> Option<Replacement> {
+    let ((op, cmp), (jump, branch)) = (ins[0], ins[1]);
+    if op != "op" || jump != "jump" || cmp.len() < 4 || branch.len() < 4 || !ctx.read_once(&cmp[1]) {
+        return None;
+    }
+    let (same, opposite) = jump_conds(&cmp[0])?;
+    let tests_t = |a: &str, b: &str| a == cmp[1] && (b == "false" || b == "0");
+    let cond = match branch[1].as_str() {
+        "equal" if tests_t(&branch[2], &branch[3]) => opposite?,
+        "notEqual" if tests_t(&branch[2], &branch[3]) => same,
+        _ => {
+            return None;
+        }
+    };
+    return Some(vec![("jump".to_string(), vec![branch[0].clone(), cond.to_string(), cmp[2].clone(), cmp[3].clone()])]);
+}
+
+/// `set t x` + an instruction reading `t` reads `x` instead.
+fn rule_forward_set(ins: &[Code], ctx: &Context) -> Option<Replacement> {
+    let ((set, copy), (op, args)) = (ins[0], ins[1]);
+    if set != "set" || copy.len() != 2 || !ctx.read_once(&copy[0]) {
+        return None;
+    }
+    // globals like @time may change between two instructions
+    if copy[1].starts_with('@') && emulator::parse_literal(&copy[1]).is_none() {
+        return None;
+    }
+    let arg_roles = roles(op, args.len())?;
+    let mut new_args = args.to_vec();
+    let mut found = false;
+    for (arg, role) in new_args.iter_mut().zip(arg_roles) {
+        if role == Role::In && *arg == copy[0] {
+            *arg = copy[1].clone();
+            found = true;
+        }
+    }
+    if !found {
+        return None;
+    }
+    return Some(vec![(op.to_string(), new_args)]);
+}
+
+/// An instruction writing `t` + `set v t` writes `v` directly.
+fn rule_write_directly(ins: &[Code], ctx: &Context) -> Option<Replacement> {
+    let ((op, args), (set, copy)) = (ins[0], ins[1]);
+    if set != "set" || copy.len() != 2 || !PURE.contains(&op) || !ctx.read_once(&copy[1]) {
+        return None;
+    }
+    let arg_roles = roles(op, args.len())?;
+    let mut new_args = args.to_vec();
+    let mut found = false;
+    for (arg, role) in new_args.iter_mut().zip(arg_roles) {
+        if role == Role::Out && *arg == copy[1] {
+            *arg = copy[0].clone();
+            found = true;
+        }
+    }
+    if !found {
+        return None;
+    }
+    return Some(vec![(op.to_string(), new_args)]);
+}
+
+/// Writes instructions the way they appear in mlog, separated by `; `.
+fn show(ins: &[Code]) -> String {
+    if ins.is_empty() {
+        return "nothing".to_string();
+    }
+    return ins.iter().map(|(op, args)| [*op].into_iter().chain(args.iter().map(|x| x.as_str())).collect::<Vec<_>>().join(" ")).collect::<Vec<_>>().join("; ");
+}
+
+/// How often every variable is read in `out`.
+fn count_reads(out: &[Ins]) -> HashMap<String, usize> {
+    let mut reads = HashMap::<String, usize>::new();
+    for ins in out {
+        if let Ins::Code { op, args, .. } = ins {
+            let arg_roles = roles(op, args.len());
+            for (n, arg) in args.iter().enumerate() {
+                if arg_roles.as_ref().map(|x| x[n]).unwrap_or(Role::In) == Role::In {
+                    *reads.entry(arg.clone()).or_default() += 1;
+                }
+            }
+        }
+    }
+    return reads;
+}
+
+/// Applies the first matching rule of `RULES` wherever one matches. The
+/// replacement instructions take the source span of the first replaced
+/// one. Returns the number of rewrites.
+fn peephole(out: &mut Vec<Ins>, all_vars: bool, opts: &HashMap<String, String>) -> usize {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let explain = argparse::flag_set(opts, "explain-opt");
+    let mut changes = 0;
+    let mut i = 0;
+    let mut ctx = Context { reads: count_reads(out), all_vars };
+    while i < out.len() {
+        let mut rewritten = false;
+        for rule in &RULES {
+            let window = match out.get(i..i + rule.window) {
+                Some(w) => w,
+                None => continue,
+            };
+            let code = window.iter().filter_map(|x| match x {
+                Ins::Code { op, args, .. } => Some((op.as_str(), args.as_slice())),
+                Ins::Label(_) => None,
+            }).collect::<Vec<_>>();
+            if code.len() != rule.window {
+                continue;
+            }
+            let replacement = match (rule.rewrite)(&code, &ctx) {
+                Some(r) => r,
+                None => continue,
+            };
+            if explain {
+                let after = replacement.iter().map(|(op, args)| (op.as_str(), args.as_slice())).collect::<Vec<_>>();
+                debug!("Peephole {}: {} => {}", rule.name, show(&code), show(&after));
+            }
+            let span = match &window[0] {
+                Ins::Code { span, .. } => *span,
+                Ins::Label(_) => Span::default(),
+            };
+            let new = replacement.into_iter().map(|(op, args)| Ins::Code { op, args, span }).collect::<Vec<_>>();
+            out.splice(i..i + rule.window, new);
+            // only a rewrite changes what is read
+            ctx.reads = count_reads(out);
+            changes += 1;
+            rewritten = true;
+            break;
+        }
+        if !rewritten {
+            i += 1;
+        }
+    }
+    return changes;
+}
+
+/// Temporaries an instruction reads and writes.
+fn temp_uses(ins: &Ins) -> (Vec<&str>, Vec<&str>) {
+    let (mut uses, mut defs) = (Vec::<&str>::new(), Vec::<&str>::new());
+    if let Ins::Code { op, args, .. } = ins {
+        let arg_roles = roles(op, args.len()).unwrap_or_else(|| vec![Role::In; args.len()]);
+        for (arg, role) in args.iter().zip(arg_roles) {
+            match role {
+                Role::In if is_temp(arg) => uses.push(arg),
+                Role::Out if is_temp(arg) => defs.push(arg),
+                _ => {}
+            }
+        }
+    }
+    return (uses, defs);
+}
+
+/// Gives compiler temporaries short names from `names::short_names`. Two
+/// temporaries share a name when liveness analysis over the blocks of the
+/// IR shows they never hold a value at the same time. Programs changing
+/// `@counter` directly or jumping to unknown places are left alone.
+/// Returns the amount of temporaries before and after.
+fn allocate(out: &mut [Ins]) -> (usize, usize) {
+    let mut temps = Vec::<String>::new();
+    for ins in out.iter() {
+        let (uses, defs) = temp_uses(ins);
+        for temp in uses.into_iter().chain(defs) {
+            if !temps.iter().any(|x| x == temp) {
+                temps.push(temp.to_string());
+            }
+        }
+    }
+    let graph = Ir::build(out);
+    let unknown_flow = graph.blocks.iter().any(|block| {
+        block.exit == Exit::Computed || block.code.iter().any(|x| matches!(x, Ins::Code { op, .. } if op == "jump"))
+    });
+    if unknown_flow || graph.blocks.is_empty() {
+        return (temps.len(), temps.len());
+    }
+    let id = |name: &str| temps.iter().position(|x| x == name).unwrap_or_default();
+
+    // the instructions of every block, its exit included, as uses and defs
+    let mut steps = Vec::<Vec<(Vec<usize>, Vec<usize>)>>::new();
+    for block in &graph.blocks {
+        let mut block_steps = block.code.iter().map(|ins| {
+            let (uses, defs) = temp_uses(ins);
+            (uses.into_iter().map(id).collect(), defs.into_iter().map(id).collect())
+        }).collect::<Vec<(Vec<usize>, Vec<usize>)>>();
+        if let Exit::Jump { operands, .. } = &block.exit {
+            block_steps.push((operands.iter().filter(|x| is_temp(x)).map(|x| id(x)).collect(), vec![]));
+        }
+        steps.push(block_steps);
+    }
+    // variables a block reads before writing them, and the ones it writes
+    let (mut gen, mut kill) = (vec![HashSet::<usize>::new(); steps.len()], vec![HashSet::<usize>::new(); steps.len()]);
+    for (b, block_steps) in steps.iter().enumerate() {
+        for (uses, defs) in block_steps {
+            gen[b].extend(uses.iter().filter(|x| !kill[b].contains(x)));
+            kill[b].extend(defs);
+        }
+    }
+
+    // variables live when each block starts and ends, until nothing changes
+    let mut live_in = vec![HashSet::<usize>::new(); steps.len()];
+    let mut live_out = vec![HashSet::<usize>::new(); steps.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..steps.len()).rev() {
+            live_out[b] = graph.successors(b).iter().flat_map(|s| live_in[*s].iter().copied()).collect();
+            let mut new_in = live_out[b].iter().copied().filter(|x| !kill[b].contains(x)).collect::<HashSet<_>>();
+            new_in.extend(&gen[b]);
+            if new_in != live_in[b] {
+                live_in[b] = new_in;
+                changed = true;
+            }
+        }
+    }
+
+    // a temporary written while another one is live cannot share its name
+    let mut conflicts = vec![HashSet::<usize>::new(); temps.len()];
+    for (b, block_steps) in steps.iter().enumerate() {
+        let mut live = live_out[b].clone();
+        for (uses, defs) in block_steps.iter().rev() {
+            for d in defs {
+                for other in live.iter().chain(defs) {
+                    if other != d {
+                        conflicts[*d].insert(*other);
+                        conflicts[*other].insert(*d);
+                    }
+                }
+            }
+            live.retain(|x| !defs.contains(x));
+            live.extend(uses);
+        }
+    }
+    let mut slot = Vec::<usize>::new();
+    for conflict in &conflicts {
+        let taken = conflict.iter().filter_map(|x| slot.get(*x)).collect::<HashSet<_>>();
+        slot.push((0..).find(|x| !taken.contains(x)).unwrap_or_default());
+    }
+    let slots = slot.iter().max().map(|x| x + 1).unwrap_or(0);
+
+    let mut used = HashSet::<String>::new();
+    for ins in out.iter() {
+        match ins {
+            Ins::Label(name) => {
+                used.insert(name.clone());
+            }
+            Ins::Code { args, .. } => {
+                used.extend(args.iter().filter(|x| !is_temp(x)).cloned());
+            }
+        }
+    }
+    let short = names::short_names(&used).take(slots).collect::<Vec<_>>();
+    let renames = temps.iter().zip(&slot).map(|(temp, s)| (temp.clone(), short[*s].clone())).collect::<HashMap<_, _>>();
+    for ins in out.iter_mut() {
+        if let Ins::Code { op, args, .. } = ins {
+            let arg_roles = roles(op, args.len()).unwrap_or_else(|| vec![Role::In; args.len()]);
+            for (arg, role) in args.iter_mut().zip(arg_roles) {
+                if role != Role::Other {
+                    if let Some(renamed) = renames.get(arg.as_str()) {
+                        *arg = renamed.clone();
+                    }
+                }
+            }
+        }
+    }
+    return (temps.len(), slots);
+}
+
+/// Runs the passes enabled by `level` until none of them changes anything,
+/// then names the remaining temporaries.
+pub fn optimize(out: &mut Vec<Ins>, level: u8, opts: &HashMap<String, String>) {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if level == 0 {
+        return;
+    }
+    let count = |out: &Vec<Ins>| out.iter().filter(|x| matches!(x, Ins::Code { .. })).count();
+    let before = count(out);
+    loop {
+        let changes = simplify_flow(out) + propagate(out) + peephole(out, level >= 2, opts) + drop_unused(out, level >= 2);
+        if changes == 0 {
+            break;
+        }
+    }
+    info!("Optimizer (level {}) went from {} to {} instructions", level, before, count(out));
+    let (temps, slots) = allocate(out);
+    info!("Stored {} temporaries in {} variables", temps, slots);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ code, compile, opts, run, text };
+
+    /// Output of a pass over `source`.
+    fn pass(source: &str, f: impl Fn(&mut Vec<Ins>) -> usize) -> String {
+        let mut out = code(source);
+        f(&mut out);
+        return text(&out);
+    }
+
+    #[test]
+    fn folds_with_mindustry_rules() {
+        let folded = pass("op add a 1 2\nop div b 1 0\nop equal c 0.1 0.1000000001\nop idiv d -7 2\nop not e 5\npackcolor f 1 0 0 1", propagate);
+        assert_eq!(folded, "set a 3\nset b null\nset c 1\nset d -4\nset e -6\nset f %ff0000ff");
+        let jumps = pass("jump L lessThan 1 2\njump M greaterThan 1 2\nselect x strictEqual 1 1 \"a\" \"b\"\nL:\nM:", propagate);
+        assert_eq!(jumps, "jump L always\nset x \"a\"\nL:\nM:");
+    }
+
+    #[test]
+    fn propagates_within_straight_line_code() {
+        let out = pass("set a 2\nop mul b a 3\nprint b\nL:\nprint a", propagate);
+        assert_eq!(out, "set a 2\nset b 6\nprint 6\nL:\nprint a");
+        // a write forgets the value, unknown instructions forget everything
+        let out = pass("set a 2\nread a cell1 0\nprint a\nset b 1\nucontrol move b b\nprint b", propagate);
+        assert_eq!(out, "set a 2\nread a cell1 0\nprint a\nset b 1\nucontrol move b b\nprint b");
+    }
+
+    #[test]
+    fn runtime_globals_are_not_constants() {
+        let out = pass("op add a @mapw 1\nset b @time\nprint b\nop add c @ctrlPlayer 1\nop mul d @pi 0", propagate);
+        assert_eq!(out, "op add a @mapw 1\nset b @time\nprint b\nset c 3\nset d 0");
+    }
+
+    #[test]
+    fn literals_and_unfoldable_values() {
+        assert_eq!(to_literal(&Value::Num(0.5)), Some("0.5".to_string()));
+        assert_eq!(to_literal(&Value::Num(-3.0)), Some("-3".to_string()));
+        assert_eq!(to_literal(&Value::Num(1e300)), Some("1e300".to_string()));
+        assert_eq!(to_literal(&Value::Num(-2.5e-7)), Some("-2.5e-7".to_string()));
+        assert_eq!(to_literal(&Value::Num(f64::INFINITY)), None);
+        assert_eq!(to_literal(&Value::Str("a".to_string())), None);
+        assert_eq!(pass("op pow a 10 300\nop rand b 1", propagate), "set a 1e300\nop rand b 1");
+    }
+
+    #[test]
+    fn constants_are_inlined_by_level() {
+        let source = "const W = 176;\nlet x = W / 2;\nlet y = @mapw / 2;\nprint(x);\nprint(y);";
+        assert_eq!(compile(source, &[("opt-level", "0")]).unwrap(), "op div x 176 2\nop div y @mapw 2\nprint x\nprint y\n");
+        assert_eq!(compile(source, &[("opt-level", "1")]).unwrap(), "set x 88\nop div y @mapw 2\nprint 88\nprint y\n");
+        assert_eq!(compile(source, &[("opt-level", "2")]).unwrap(), "op div y @mapw 2\nprint 88\nprint y\n");
+        assert_eq!(opt_level(&opts(&[("opt-level", "3")])).unwrap_err().1, ExitReason::OptionBadValue);
+    }
+
+    /// Output of running `f` over `source` until it changes nothing.
+    fn settle(source: &str, f: impl Fn(&mut Vec<Ins>) -> usize) -> String {
+        let mut out = code(source);
+        while f(&mut out) > 0 {}
+        return text(&out);
+    }
+
+    #[test]
+    fn unreachable_code_is_dropped() {
+        assert_eq!(settle("print 1\nstop\nprint 2\nL:\nprint 3", simplify_flow), "print 1\nstop");
+        assert_eq!(settle("jump A always\nprint 1\nA:\nprint 2", simplify_flow), "print 2");
+        assert_eq!(settle("jump A lessThan x 1\nA:\nprint 2", simplify_flow), "print 2");
+        // code after a computed jump may be its target
+        assert_eq!(settle("set @counter x\nprint 1\nL:\nprint 2", simplify_flow), "set @counter x\nprint 1\nprint 2");
+    }
+
+    #[test]
+    fn jump_chains_are_shortened() {
+        let out = settle("jump A equal x 1\nprint 0\nend\nA:\njump B always\nprint 1\nB:\nprint 2", simplify_flow);
+        assert_eq!(out, "jump B equal x 1\nprint 0\nend\nB:\nprint 2");
+        // an endless loop of jumps stays one
+        assert_eq!(settle("A:\njump B always\nB:\njump A always", simplify_flow), "A:\njump A always");
+    }
+
+    #[test]
+    fn unread_variables_are_dropped() {
+        let source = "set a 1\nop add __t0 a 1\nset b 2\nprint a\nsensor c @this @x\nwrite 1 cell1 0\nset @counter 3";
+        assert_eq!(pass(source, |x| drop_unused(x, false)), "set a 1\nset b 2\nprint a\nsensor c @this @x\nwrite 1 cell1 0\nset @counter 3");
+        assert_eq!(pass(source, |x| drop_unused(x, true)), "set a 1\nprint a\nwrite 1 cell1 0\nset @counter 3");
+    }
+
+    #[test]
+    fn no_jumps_to_the_next_instruction() {
+        let source = "fn f(x) { match x { 0 => { return 1; } _ => {} } return 2; }\nprint(f(@links));\nprintflush(message1);";
+        let mlog = compile(source, &[]).unwrap();
+        for (i, line) in mlog.lines().enumerate() {
+            assert!(!line.starts_with(&format!("jump {} ", i + 1)), "{}", mlog);
+        }
+        assert_eq!(run(source, ""), "2");
+    }
+
+    fn peep(source: &str, all_vars: bool) -> String {
+        return settle(source, |out| peephole(out, all_vars, &opts(&[("explain-opt", "")])));
+    }
+
+    #[test]
+    fn compare_and_jump() {
+        assert_eq!(peep("op lessThan __t0 a b\njump L equal __t0 false\nL:", false), "jump L greaterThanEq a b\nL:");
+        assert_eq!(peep("op equal __t0 a b\njump L notEqual __t0 0\nL:", false), "jump L equal a b\nL:");
+        // strictEqual has no opposite condition
+        assert_eq!(peep("op strictEqual __t0 a b\njump L equal __t0 false\nL:", false), "op strictEqual __t0 a b\njump L equal __t0 false\nL:");
+        // the result is needed elsewhere
+        let kept = "op lessThan __t0 a b\njump L equal __t0 false\nL:\nprint __t0";
+        assert_eq!(peep(kept, false), kept);
+    }
+
+    #[test]
+    fn forward_set_and_write_directly() {
+        assert_eq!(peep("set __t0 x\nop add y __t0 1", false), "op add y x 1");
+        assert_eq!(peep("op add __t0 x 1\nset y __t0", false), "op add y x 1");
+        // globals can change between two instructions, constants cannot
+        assert_eq!(peep("set __t0 @time\nop add y __t0 1", false), "set __t0 @time\nop add y __t0 1");
+        assert_eq!(peep("set __t0 @pi\nop add y __t0 1", false), "op add y @pi 1");
+        // the label splits the window
+        assert_eq!(peep("set __t0 x\nL:\nop add y __t0 1", false), "set __t0 x\nL:\nop add y __t0 1");
+        // only temporaries are replaced below level 2
+        assert_eq!(peep("set t x\nop add y t 1", false), "set t x\nop add y t 1");
+        assert_eq!(peep("set t x\nop add y t 1", true), "op add y x 1");
+        // an instruction with side effects has to stay as it is
+        assert_eq!(peep("getlink __t0 0\nset y __t0", false), "getlink y 0");
+        assert_eq!(peep("ucontrol getBlock 1 2 __t0\nset y __t0", false), "ucontrol getBlock 1 2 __t0\nset y __t0");
+    }
+
+    #[test]
+    fn self_set() {
+        assert_eq!(peep("set x x\nset x y", false), "set x y");
+    }
+
+    #[test]
+    fn rewrites_keep_the_first_span() {
+        let mut out = code("set __t0 x\nop add y __t0 1");
+        let span = Span { line: 3, col: 1, end_line: 3, end_col: 5 };
+        if let Ins::Code { span: s, .. } = &mut out[0] {
+            *s = span;
+        }
+        peephole(&mut out, false, &opts(&[]));
+        assert!(matches!(&out[..], [Ins::Code { span: s, .. }] if *s == span));
+    }
+
+    fn allocated(source: &str) -> (String, (usize, usize)) {
+        let mut out = code(source);
+        let counts = allocate(&mut out);
+        return (text(&out), counts);
+    }
+
+    #[test]
+    fn temporaries_share_names_when_lifetimes_do_not_overlap() {
+        let (out, counts) = allocated("op add __t0 a 1\nprint __t0\nop add __t1 b 1\nprint __t1");
+        assert_eq!((out.as_str(), counts), ("op add 一 a 1\nprint 一\nop add 一 b 1\nprint 一", (2, 1)));
+        let (out, counts) = allocated("op add __t0 a 1\nop add __t1 b 1\nprint __t0\nprint __t1");
+        assert_eq!((out.as_str(), counts), ("op add 一 a 1\nop add 丁 b 1\nprint 一\nprint 丁", (2, 2)));
+    }
+
+    #[test]
+    fn liveness_follows_branches() {
+        // __t0 is still needed where the jump lands
+        let (out, counts) = allocated("set __t0 1\njump L equal a 0\nset __t1 2\nprint __t1\nL:\nprint __t0");
+        assert_eq!((out.as_str(), counts), ("set 一 1\njump L equal a 0\nset 丁 2\nprint 丁\nL:\nprint 一", (2, 2)));
+    }
+
+    #[test]
+    fn names_do_not_collide_with_program_variables() {
+        let (out, _) = allocated("set 一 3\nop add __t0 a 1\nprint __t0\nprint 一");
+        assert_eq!(out, "set 一 3\nop add 丁 a 1\nprint 丁\nprint 一");
+    }
+
+    #[test]
+    fn computed_jumps_are_left_alone() {
+        let source = "set __t0 1\nset @counter x\nprint __t0";
+        assert_eq!(allocated(source), (source.to_string(), (1, 1)));
+    }
+}
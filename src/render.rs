@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fs::write;
+use std::path::Path;
+use crate::emulator::{ BlockKind, Building, DrawCmd, DrawKind, Emulator };
+use crate::*;
+
+/// Pixel buffer of a logic display. Coordinates start at the bottom left
+/// corner like in game.
+pub struct Canvas {
+    pub size: usize,
+    pub pixels: Vec<[u8; 4]>,
+    color: [u8; 4],
+    stroke: f64,
+}
+
+type Poly = Vec<(f64, f64)>;
+
+/// Corners of a regular polygon, like `Fill.poly` draws them.
+fn regular_poly(x: f64, y: f64, sides: i32, radius: f64, rotation: f64) -> Poly {
+    let sides = sides.max(3);
+    return (0..sides)
+        .map(|i| {
+            let angle = (rotation + i as f64 * 360.0 / sides as f64).to_radians();
+            (x + angle.cos() * radius, y + angle.sin() * radius)
+        })
+        .collect();
+}
+
+/// Quad covering a line of width `stroke`, extended by half the stroke on
+/// both ends like `Lines.line` with caps.
+fn line_quad(x1: f64, y1: f64, x2: f64, y2: f64, stroke: f64) -> Poly {
+    let len = (x2 - x1).hypot(y2 - y1);
+    let (dx, dy) = if len == 0.0 { (1.0, 0.0) } else { ((x2 - x1) / len, (y2 - y1) / len) };
+    let (hx, hy) = (dx * stroke / 2.0, dy * stroke / 2.0);
+    let (nx, ny) = (-hy, hx);
+    return vec![
+        (x1 - hx + nx, y1 - hy + ny),
+        (x2 + hx + nx, y2 + hy + ny),
+        (x2 + hx - nx, y2 + hy - ny),
+        (x1 - hx - nx, y1 - hy - ny),
+    ];
+}
+
+/// Lines along every edge of a closed polygon.
+fn outline(points: &Poly, stroke: f64) -> Vec<Poly> {
+    return (0..points.len())
+        .map(|i| {
+            let (x1, y1) = points[i];
+            let (x2, y2) = points[(i + 1) % points.len()];
+            line_quad(x1, y1, x2, y2, stroke)
+        })
+        .collect();
+}
+
+impl Canvas {
+    pub fn new(size: usize) -> Canvas {
+        Canvas { size, pixels: vec![[0, 0, 0, 255]; size * size], color: [255, 255, 255, 255], stroke: 1.0 }
+    }
+
+    fn blend(&mut self, x: usize, y: usize) {
+        let dst = &mut self.pixels[y * self.size + x];
+        let a = self.color[3] as u32;
+        for (d, s) in dst.iter_mut().zip(self.color).take(3) {
+            *d = ((s as u32 * a + *d as u32 * (255 - a)) / 255) as u8;
+        }
+        dst[3] = (a + dst[3] as u32 * (255 - a) / 255) as u8;
+    }
+
+    /// Fills the union of `polys` with the current color, sampling pixel
+    /// centers so overlapping shapes are only blended once.
+    fn fill(&mut self, polys: &[Poly]) {
+        let mut covered = vec![false; self.size * self.size];
+        for poly in polys {
+            let ymin = poly.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+            let ymax = poly.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+            let start = (ymin - 0.5).ceil().max(0.0) as usize;
+            let end = ((ymax - 0.5).ceil().max(0.0) as usize).min(self.size);
+            for py in start..end {
+                let sy = py as f64 + 0.5;
+                let mut xs = Vec::<f64>::new();
+                for i in 0..poly.len() {
+                    let (x1, y1) = poly[i];
+                    let (x2, y2) = poly[(i + 1) % poly.len()];
+                    if (y1 <= sy && sy < y2) || (y2 <= sy && sy < y1) {
+                        xs.push(x1 + (sy - y1) / (y2 - y1) * (x2 - x1));
+                    }
+                }
+                xs.sort_by(|a, b| a.total_cmp(b));
+                for pair in xs.chunks(2) {
+                    if pair.len() < 2 {
+                        continue;
+                    }
+                    let from = (pair[0] - 0.5).ceil().max(0.0) as usize;
+                    let to = ((pair[1] - 0.5).ceil().max(0.0) as usize).min(self.size);
+                    for px in from..to {
+                        covered[py * self.size + px] = true;
+                    }
+                }
+            }
+        }
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if covered[y * self.size + x] {
+                    self.blend(x, y);
+                }
+            }
+        }
+    }
+
+    /// Applies one draw command. Returns false for commands that can only
+    /// be approximated, currently `image`, which is drawn as a square.
+    pub fn apply(&mut self, cmd: &DrawCmd) -> bool {
+        let a = cmd.args.map(|x| x as f64);
+        match cmd.kind {
+            DrawKind::Clear => {
+                let color = [a[0] as u8, a[1] as u8, a[2] as u8, 255];
+                self.pixels.fill(color);
+            }
+            DrawKind::Color | DrawKind::Col => {
+                self.color = [a[0] as u8, a[1] as u8, a[2] as u8, a[3] as u8];
+            }
+            DrawKind::Stroke => {
+                self.stroke = a[0];
+            }
+            DrawKind::Line => {
+                self.fill(&[line_quad(a[0], a[1], a[2], a[3], self.stroke)]);
+            }
+            DrawKind::Rect => {
+                self.fill(&[vec![(a[0], a[1]), (a[0] + a[2], a[1]), (a[0] + a[2], a[1] + a[3]), (a[0], a[1] + a[3])]]);
+            }
+            DrawKind::LineRect => {
+                let corners = vec![(a[0], a[1]), (a[0] + a[2], a[1]), (a[0] + a[2], a[1] + a[3]), (a[0], a[1] + a[3])];
+                self.fill(&outline(&corners, self.stroke));
+            }
+            DrawKind::Poly => {
+                self.fill(&[regular_poly(a[0], a[1], cmd.args[2], a[3], a[4])]);
+            }
+            DrawKind::LinePoly => {
+                self.fill(&outline(&regular_poly(a[0], a[1], cmd.args[2], a[3], a[4]), self.stroke));
+            }
+            DrawKind::Triangle => {
+                self.fill(&[vec![(a[0], a[1]), (a[2], a[3]), (a[4], a[5])]]);
+            }
+            DrawKind::Image => {
+                let half = a[3] / 2.0;
+                let square = regular_poly(a[0], a[1], 4, half * 2f64.sqrt(), a[4] + 45.0);
+                self.fill(&[square]);
+                return false;
+            }
+        }
+        return true;
+    }
+
+    /// Encodes the canvas as PNG, flipping it so the top row comes first.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut rgba = Vec::<u8>::with_capacity(self.size * self.size * 4);
+        for y in (0..self.size).rev() {
+            for x in 0..self.size {
+                rgba.extend_from_slice(&self.pixels[y * self.size + x]);
+            }
+        }
+        return png::encode_rgba(self.size as u32, self.size as u32, &rgba);
+    }
+}
+
+fn write_png(path: &str, canvas: &Canvas) -> Result<(), (String, ExitReason)> {
+    return match write(path, canvas.to_png()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err((format!("Writing file \"{}\" failed. Error: {}", path, e.kind()), ExitReason::RenderWriteFailed)),
+    };
+}
+
+pub fn render(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"render\" expected 1 or 2 arguments. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let (ipt, ticks) = emulator::emulator_opts(opts)?;
+    info!("Reading file");
+    let program = emulator::read_mlog(&args[2])?;
+    let buildings = match args.get(3) {
+        Some(path) => world::read_world(path)?,
+        None => vec![Building::new("display1", BlockKind::Display { size: 80, large: false })],
+    };
+    let mut canvases = HashMap::<usize, Canvas>::new();
+    for (idx, building) in buildings.iter().enumerate() {
+        if let BlockKind::Display { size, .. } = building.kind {
+            canvases.insert(idx, Canvas::new(size));
+        }
+    }
+    if canvases.is_empty() {
+        cwarn!("No displays are linked, nothing will be rendered.");
+    }
+
+    let base = match opts.get("out-file") {
+        Some(path) => path.strip_suffix(".png").unwrap_or(path).to_string(),
+        None => {
+            let path = Path::new(&args[2]);
+            path.with_extension("").to_string_lossy().to_string()
+        }
+    };
+    let all_frames = flag_set(opts, "all-frames");
+    let mut frames = HashMap::<usize, usize>::new();
+    let mut approximated = false;
+    let mut result = Ok(());
+
+    let mut emu = Emulator::new(program, buildings, ipt);
+    emu.once = flag_set(opts, "once");
+    emulator::drive(&mut emu, ticks, opts, |emu| {
+        for (idx, cmds) in &emu.draw_output {
+            let canvas = canvases.get_mut(idx).unwrap(/* only displays receive draws */);
+            for cmd in cmds {
+                approximated |= !canvas.apply(cmd);
+            }
+            let frame = frames.entry(*idx).or_insert(0);
+            if all_frames && result.is_ok() {
+                let path = format!("{}.{}.{:04}.png", base, emu.buildings[*idx].name, frame);
+                debug!("Writing frame {}", path);
+                result = write_png(&path, canvas);
+            }
+            *frame += 1;
+        }
+    });
+    result?;
+    if approximated {
+        cwarn!("Images have no textures available and were drawn as squares of the current color.");
+    }
+
+    let mut indices = canvases.keys().copied().collect::<Vec<_>>();
+    indices.sort();
+    for idx in indices {
+        let name = &emu.buildings[idx].name;
+        let count = frames.get(&idx).copied().unwrap_or(0);
+        if all_frames {
+            ok!("Wrote {} frames of {} to {}.{}.*.png", count, name, base, name);
+        }
+        else {
+            let path = format!("{}.{}.png", base, name);
+            write_png(&path, &canvases[&idx])?;
+            ok!("Wrote {} after {} flushes to {}", name, count, path);
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::opts;
+
+    /// Draws the frames `mlog` flushes to `display1` in one run, returning
+    /// the canvas and the number of flushes.
+    fn draw(mlog: &str) -> (Canvas, usize) {
+        let buildings = world::parse_world("display1 = logic-display").unwrap();
+        let mut emu = Emulator::new(emulator::parse_mlog(mlog).unwrap(), buildings, 1000);
+        emu.once = true;
+        let mut canvas = Canvas::new(80);
+        let mut flushes = 0;
+        emulator::drive(&mut emu, 10, &opts(&[]), |emu| {
+            for (_, cmds) in &emu.draw_output {
+                for cmd in cmds {
+                    canvas.apply(cmd);
+                }
+                flushes += 1;
+            }
+        });
+        return (canvas, flushes);
+    }
+
+    fn pixel(canvas: &Canvas, x: usize, y: usize) -> [u8; 4] {
+        return canvas.pixels[y * canvas.size + x];
+    }
+
+    #[test]
+    fn rect_covers_pixel_centers_from_the_bottom_left() {
+        let (canvas, flushes) = draw("draw clear 0 0 255\ndraw color 255 0 0 255\ndraw rect 10 20 5 3\ndrawflush display1");
+        assert_eq!(flushes, 1);
+        assert_eq!(pixel(&canvas, 10, 20), [255, 0, 0, 255]);
+        assert_eq!(pixel(&canvas, 14, 22), [255, 0, 0, 255]);
+        assert_eq!(pixel(&canvas, 15, 22), [0, 0, 255, 255]);
+        assert_eq!(pixel(&canvas, 14, 23), [0, 0, 255, 255]);
+        assert_eq!(canvas.pixels.iter().filter(|x| x[0] == 255).count(), 15);
+    }
+
+    #[test]
+    fn unflushed_commands_are_not_drawn() {
+        let (canvas, flushes) = draw("draw clear 0 255 0\ndrawflush display1\ndraw clear 255 0 0");
+        assert_eq!(flushes, 1);
+        assert!(canvas.pixels.iter().all(|x| *x == [0, 255, 0, 255]));
+        // a flush to something that is not a display drops the buffer
+        let (canvas, flushes) = draw("draw clear 255 0 0\ndrawflush message1\ndrawflush display1");
+        assert_eq!(flushes, 1);
+        assert!(canvas.pixels.iter().all(|x| *x == [0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn colors_blend_and_col_unpacks() {
+        let (canvas, _) = draw("draw color 255 255 255 128\ndraw rect 0 0 1 1\ndrawflush display1");
+        assert_eq!(pixel(&canvas, 0, 0), [128, 128, 128, 255]);
+        let (canvas, _) = draw("packcolor c 0 1 0 1\ndraw col c\ndraw rect 0 0 1 1\ndrawflush display1");
+        assert_eq!(pixel(&canvas, 0, 0), [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn coordinates_wrap_at_ten_bits() {
+        let (canvas, _) = draw("draw rect 1025 0 1 1\ndrawflush display1");
+        assert_eq!(pixel(&canvas, 1, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn png_layout() {
+        let mut canvas = Canvas::new(2);
+        canvas.pixels[0] = [1, 2, 3, 4];
+        let png = canvas.to_png();
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        // stored zlib data after the chunk type: 2 byte header, 5 byte block
+        // header, then a filter byte before every row, top row first
+        let data = png.windows(4).position(|x| x == b"IDAT").unwrap() + 4 + 2 + 5;
+        assert_eq!(&png[data..data + 18], &[0, 0, 0, 0, 255, 0, 0, 0, 255, 0, 1, 2, 3, 4, 0, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,321 @@
+//! Name resolution. `let` declares a variable for the rest of the block it
+//! is in, and may shadow a variable of the same name, also in the same
+//! block. Variables declared at the top level of the program keep their
+//! name in mlog, every other one gets a unique name like `x:1`, which
+//! source code cannot spell. `var` declares a global, which keeps its
+//! name wherever it is declared. Names that are never declared, like
+//! links to buildings or variables only assigned, are globals too.
+
+use std::collections::{ HashMap, HashSet };
+use crate::ast::{ Arm, Expr, Function, Item, Module, Param, Pattern, Span, Stmt, Test };
+use crate::*;
+
+struct Decl {
+    name: String,
+    /// Name of the variable in mlog.
+    mlog: String,
+    span: Span,
+    read: bool,
+    assigned: bool,
+    /// Whether a read before the first assignment was reported.
+    warned: bool,
+}
+
+struct Resolver {
+    decls: Vec<Decl>,
+    /// Declarations visible at this point, the innermost block last.
+    scopes: Vec<HashMap<String, usize>>,
+    /// Number of outer scopes belonging to the top level of the program
+    /// while resolving a function or test. Reads of those are not checked
+    /// for assignment, as the function may be called anytime later.
+    shared: usize,
+    /// Unique names handed out per name.
+    counts: HashMap<String, usize>,
+    /// Names read where no declaration is visible, which may be globals
+    /// declared later or in a function.
+    undeclared: HashSet<String>,
+    /// Warnings in the order they were found.
+    warnings: Vec<String>,
+}
+
+macro_rules! pos {
+    ($span:expr) => {
+        format!(" (line {}, col {})", $span.line, $span.col)
+    };
+}
+
+impl Resolver {
+    fn lookup(&self, name: &str) -> Option<(usize, usize)> {
+        return self.scopes.iter().enumerate().rev().find_map(|(depth, scope)| scope.get(name).map(|x| (depth, *x)));
+    }
+
+    fn declare(&mut self, name: &str, global: bool, assigned: bool, span: Span) -> String {
+        let top_level = self.shared == 0 && self.scopes.len() == 1 && !self.scopes[0].contains_key(name);
+        let mlog = if global || top_level {
+            name.to_string()
+        }
+        else {
+            let count = self.counts.entry(name.to_string()).or_insert(0);
+            *count += 1;
+            format!("{}:{}", name, count)
+        };
+        self.decls.push(Decl { name: name.to_string(), mlog: mlog.clone(), span, read: false, assigned, warned: false });
+        let scope = match global {
+            true => self.scopes.first_mut(),
+            false => self.scopes.last_mut(),
+        };
+        scope.unwrap(/* never empty */).insert(name.to_string(), self.decls.len() - 1);
+        return mlog;
+    }
+
+    fn read(&mut self, name: &str, span: Span) -> String {
+        let (depth, idx) = match self.lookup(name) {
+            Some(found) => found,
+            None => {
+                self.undeclared.insert(name.to_string());
+                return name.to_string();
+            }
+        };
+        let decl = &mut self.decls[idx];
+        decl.read = true;
+        if depth >= self.shared && !decl.assigned && !decl.warned {
+            decl.warned = true;
+            self.warnings.push(format!("Variable \"{}\" is used before being assigned.{}", name, pos!(span)));
+        }
+        return self.decls[idx].mlog.clone();
+    }
+
+    fn assign(&mut self, name: &str) -> String {
+        return match self.lookup(name) {
+            Some((_, idx)) => {
+                self.decls[idx].assigned = true;
+                self.decls[idx].mlog.clone()
+            }
+            None => name.to_string(),
+        };
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        return match expr {
+            Expr::Literal { .. } | Expr::Variant { .. } => expr.clone(),
+            Expr::Var { name, span } => Expr::Var { name: self.read(name, *span), span: *span },
+            Expr::Index { name, index, span } => {
+                let index = Box::new(self.expr(index));
+                Expr::Index { name: self.read(name, *span), index, span: *span }
+            }
+            Expr::Struct { name, fields, span } => Expr::Struct {
+                name: name.clone(),
+                fields: fields.iter().map(|(field, value)| (field.clone(), self.expr(value))).collect(),
+                span: *span,
+            },
+            Expr::Field { base, field, span } => Expr::Field { base: Box::new(self.expr(base)), field: field.clone(), span: *span },
+            Expr::Binary { op, left, right, span } => Expr::Binary {
+                op: op.clone(),
+                left: Box::new(self.expr(left)),
+                right: Box::new(self.expr(right)),
+                span: *span,
+            },
+            Expr::Unary { op, operand, span } => Expr::Unary { op: op.clone(), operand: Box::new(self.expr(operand)), span: *span },
+            Expr::Call { name, args, span } => Expr::Call { name: name.clone(), args: args.iter().map(|x| self.expr(x)).collect(), span: *span },
+            Expr::Macro { name, args, span } => Expr::Macro { name: name.clone(), args: args.iter().map(|x| self.expr(x)).collect(), span: *span },
+        };
+    }
+
+    /// Resolves the variable or field `expr` is, as assigned rather than
+    /// read.
+    fn place(&mut self, expr: &Expr) -> Expr {
+        return match expr {
+            Expr::Var { name, span } => Expr::Var { name: self.assign(name), span: *span },
+            Expr::Field { base, field, span } => Expr::Field { base: Box::new(self.place(base)), field: field.clone(), span: *span },
+            _ => self.expr(expr),
+        };
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Stmt {
+        return match stmt {
+            Stmt::Assign { target, value, span } => {
+                let value = self.expr(value);
+                Stmt::Assign { target: self.assign(target), value, span: *span }
+            }
+            Stmt::Let { name, ty, value, global, span } => {
+                // the value still sees a variable the declaration shadows
+                let value = value.as_ref().map(|x| self.expr(x));
+                let mlog = self.declare(name, *global, value.is_some(), *span);
+                Stmt::Let { name: mlog, ty: *ty, value, global: *global, span: *span }
+            }
+            Stmt::Array { name, ty, len, memory, global, span } => {
+                let (len, memory) = (self.expr(len), memory.as_ref().map(|x| self.expr(x)));
+                // elements of register arrays start out null like any variable
+                let mlog = self.declare(name, *global, true, *span);
+                Stmt::Array { name: mlog, ty: *ty, len, memory, global: *global, span: *span }
+            }
+            Stmt::Store { name, index, value, span } => {
+                let (index, value) = (self.expr(index), self.expr(value));
+                Stmt::Store { name: self.assign(name), index, value, span: *span }
+            }
+            Stmt::SetField { base, field, value, span } => {
+                let value = self.expr(value);
+                Stmt::SetField { base: self.place(base), field: field.clone(), value, span: *span }
+            }
+            Stmt::Block { body, span } => Stmt::Block { body: self.block(body), span: *span },
+            Stmt::Match { value, arms, span } => Stmt::Match {
+                value: self.expr(value),
+                arms: arms.iter().map(|arm| Arm {
+                    patterns: arm.patterns.iter().map(|x| match x {
+                        Pattern::Value(expr) => Pattern::Value(self.expr(expr)),
+                        Pattern::Any => Pattern::Any,
+                    }).collect(),
+                    body: self.block(&arm.body),
+                    span: arm.span,
+                }).collect(),
+                span: *span,
+            },
+            Stmt::Return { value, span } => Stmt::Return { value: value.as_ref().map(|x| self.expr(x)), span: *span },
+            Stmt::Expr(expr) => Stmt::Expr(self.expr(expr)),
+        };
+    }
+
+    /// Resolves the statements of a block in a new scope.
+    fn block(&mut self, body: &[Stmt]) -> Vec<Stmt> {
+        self.scopes.push(HashMap::<String, usize>::new());
+        let body = body.iter().map(|x| self.stmt(x)).collect();
+        self.scopes.pop();
+        return body;
+    }
+
+    /// Resolves the body of a function or test, which sees the globals
+    /// declared at the top level and its parameters.
+    fn item_body(&mut self, globals: &HashMap<String, usize>, params: &[Param], body: &[Stmt]) -> Vec<Stmt> {
+        self.scopes = vec![globals.clone()];
+        self.shared = 1;
+        // parameters are renamed when inlined, they only need to be known
+        // to hide globals of the same name
+        self.scopes.push(HashMap::<String, usize>::new());
+        for param in params {
+            let name = param.name.clone();
+            self.decls.push(Decl { name: name.clone(), mlog: name.clone(), span: param.span, read: true, assigned: true, warned: false });
+            self.scopes[1].insert(name, self.decls.len() - 1);
+        }
+        let body = self.block(body);
+        self.scopes.clear();
+        return body;
+    }
+}
+
+/// Resolves every variable of `root` to its mlog name. Also returns
+/// warnings about variables never read or read before being assigned.
+fn resolve_names(root: &Module) -> (Module, Vec<String>) {
+    let mut resolver = Resolver {
+        decls: Vec::<Decl>::new(),
+        scopes: vec![HashMap::<String, usize>::new()],
+        shared: 0,
+        counts: HashMap::<String, usize>::new(),
+        undeclared: HashSet::<String>::new(),
+        warnings: Vec::<String>::new(),
+    };
+    let mut items = root.items.iter().map(|item| match item {
+        Item::Stmt(stmt) => Some(Item::Stmt(resolver.stmt(stmt))),
+        _ => None,
+    }).collect::<Vec<_>>();
+    // only declarations that kept their name can be seen from functions
+    let mut globals = resolver.scopes.pop().unwrap_or_default();
+    globals.retain(|_, idx| resolver.decls[*idx].mlog == resolver.decls[*idx].name);
+    for (item, resolved) in root.items.iter().zip(items.iter_mut()) {
+        *resolved = match item {
+            Item::Fn(func) => Some(Item::Fn(Function {
+                name: func.name.clone(),
+                params: func.params.clone(),
+                body: resolver.item_body(&globals, &func.params, &func.body),
+                doc: func.doc.clone(),
+                span: func.span,
+            })),
+            Item::Test(test) => Some(Item::Test(Test {
+                name: test.name.clone(),
+                body: resolver.item_body(&globals, &[], &test.body),
+                span: test.span,
+            })),
+            Item::Const(_) | Item::Enum(_) | Item::Struct(_) => Some(item.clone()),
+            Item::Stmt(_) => resolved.take(),
+        };
+    }
+    for decl in &resolver.decls {
+        let read = decl.read || (decl.mlog == decl.name && resolver.undeclared.contains(&decl.name));
+        if !read && !decl.name.starts_with('_') {
+            resolver.warnings.push(format!("Variable \"{}\" is never read.{}", decl.name, pos!(decl.span)));
+        }
+    }
+    return (Module { items: items.into_iter().flatten().collect() }, resolver.warnings);
+}
+
+/// Resolves every variable of `root` to its mlog name and warns about
+/// variables never read or read before being assigned.
+pub fn resolve(root: &Module, opts: &HashMap<String, String>) -> Module {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let (resolved, warnings) = resolve_names(root);
+    for warning in warnings {
+        cwarn!("{}", warning);
+    }
+    return resolved;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ error, parse, run };
+
+    /// Resolved syntax tree and warnings of `source`.
+    fn resolved(source: &str) -> (String, Vec<String>) {
+        let (module, warnings) = resolve_names(&parse(source).unwrap());
+        return (module.to_string(), warnings);
+    }
+
+    #[test]
+    fn shadowing_in_the_same_block() {
+        let (tree, warnings) = resolved("let x = 1;\nlet x = x + 1;\nprint(x);");
+        assert_eq!(tree, "<Let \"x\" <Literal \"1\" (Num)>>\n<Let \"x:1\" <Binary <Var \"x\"> \"+\" <Literal \"1\" (Num)>>>\n<Call print([<Var \"x:1\">])>\n");
+        assert!(warnings.is_empty());
+        assert_eq!(run("let x = 1;\nlet x = x + 1;\nprint(x);\nprintflush(message1);", ""), "2");
+    }
+
+    #[test]
+    fn inner_blocks_do_not_leak() {
+        let source = "let x = 1;\nmatch @links { 1 => { let x = 5; print(x); } _ => {} }\nprint(x);\nprintflush(message1);";
+        assert_eq!(run(source, ""), "51");
+    }
+
+    #[test]
+    fn functions_get_their_own_names() {
+        let (tree, _) = resolved("fn f() { let y = 2; return y; }\nfn g() { let y = 3; return y; }");
+        assert_eq!(tree, "<Fn \"f\"() [<Let \"y:1\" <Literal \"2\" (Num)>>, <Return <Var \"y:1\">>]>\n<Fn \"g\"() [<Let \"y:2\" <Literal \"3\" (Num)>>, <Return <Var \"y:2\">>]>\n");
+        // `var` keeps its name, and top level variables are visible in functions
+        let (tree, warnings) = resolved("fn f() { var g = 2; return n; }\nlet n = 4;\nprint(g);");
+        assert_eq!(tree, "<Fn \"f\"() [<GlobalLet \"g\" <Literal \"2\" (Num)>>, <Return <Var \"n\">>]>\n<Let \"n\" <Literal \"4\" (Num)>>\n<Call print([<Var \"g\">])>\n");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn warnings() {
+        let (_, warnings) = resolved("let a;\nprint(a);\nprint(a);\nlet _unused = 1;\nlet b = 1;\nfn f(p) { let c = 1; }");
+        assert_eq!(warnings, vec![
+            "Variable \"a\" is used before being assigned. (line 2, col 7)",
+            "Variable \"b\" is never read. (line 5, col 1)",
+            "Variable \"c\" is never read. (line 6, col 11)",
+        ]);
+        // functions may run after the top level assigned a variable
+        let (_, warnings) = resolved("let n;\nfn f() { return n; }\nn = 1;\nprint(f());");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn keywords_are_not_names() {
+        for source in ["let = 3;", "let match = 1;", "var in = 2;"] {
+            let keyword = source.split(' ').next().unwrap();
+            let message = format!("Expected a name after '{}'. (line 1, col 1)", keyword);
+            assert_eq!(error(source), (message, ExitReason::CompileUnexpectedToken));
+        }
+    }
+}
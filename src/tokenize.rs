@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use crate::ExitReason;
 
 #[derive(PartialEq)]
 pub enum StringType {
@@ -6,6 +7,8 @@ pub enum StringType {
     String,
     Char,
     Backtick,
+    /// A `## doc comment`, holding the text after the `##`.
+    Doc,
 }
 
 pub struct Token {
@@ -31,6 +34,7 @@ impl Display for Token {
                 StringType::Backtick => { "b" }
                 StringType::String => { "s" }
                 StringType::Char => { "c" }
+                StringType::Doc => { "d" }
             },
             self.content,
             self.line,
@@ -39,7 +43,57 @@ impl Display for Token {
     }
 }
 
-pub fn tokenize(file: String) -> Vec<Token> {
+/// Words with a meaning of their own. They can't name a variable, and a
+/// `%` or `.` after them starts a literal instead of being an operator.
+pub const KEYWORDS: [&str; 12] = ["let", "var", "const", "fn", "test", "enum", "struct", "match", "return", "in", "if", "else"];
+
+/// Length in chars of the number literal starting at `start`, or 0 when
+/// there is none: digits, `.5`, or a `%rrggbb` color. Letters right after
+/// the number are included so the parser can reject them in one piece.
+/// After a value, `%` and `.` are operators instead.
+fn number_len(chars: &[(usize, char)], start: usize, after_value: bool) -> usize {
+    let at = |i: usize| chars.get(i).map(|x| x.1).unwrap_or(' ');
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let before = if start == 0 { ' ' } else { at(start - 1) };
+    if word(before) || ['.', ')', ']', '"', '\'', '`'].contains(&before) {
+        return 0;
+    }
+    let (first, next) = (at(start), at(start + 1));
+    if after_value && !first.is_ascii_digit() {
+        return 0;
+    }
+    if first == '%' {
+        let digits = (start + 1..).take_while(|&i| at(i).is_ascii_hexdigit()).count();
+        if (digits == 6 || digits == 8) && !word(at(start + 1 + digits)) {
+            return 1 + digits;
+        }
+        return 0;
+    }
+    if !(first.is_ascii_digit() || first == '.' && next.is_ascii_digit()) {
+        return 0;
+    }
+    let radix = first == '0' && (next == 'x' || next == 'b');
+    // a point or exponent was read, so no point may follow
+    let mut fraction = first == '.';
+    let mut i = start + 1;
+    loop {
+        let c = at(i);
+        if word(c) {
+            fraction |= !radix && (c == 'e' || c == 'E');
+        } else if c == '.' && !fraction && !radix && at(i + 1).is_ascii_digit() {
+            fraction = true;
+        } else if !(['+', '-'].contains(&c) && !radix && ['e', 'E'].contains(&at(i - 1)) && at(i + 1).is_ascii_digit()) {
+            break;
+        }
+        i += 1;
+    }
+    return i - start;
+}
+
+/// Splits source text into tokens. `#` starts a comment until the end of
+/// the line and `/* */` comments may be nested, both are dropped. `##`
+/// comments are kept as `Doc` tokens.
+pub fn tokenize(file: String) -> Result<Vec<Token>, (String, ExitReason)> {
     let punctuation = [
         ';',
         ':',
@@ -53,7 +107,6 @@ pub fn tokenize(file: String) -> Vec<Token> {
         ')',
         '!',
         '@',
-        '#',
         '$',
         '%',
         '/',
@@ -100,7 +153,12 @@ pub fn tokenize(file: String) -> Vec<Token> {
         "||=",
         "&&=",
         "%%=",
+        "%=",
+        "**",
+        "**=",
         "..",
+        "::",
+        "=>",
     ];
     let string_specifiers = ['\"', '\'', '`'];
     let whitespace = [
@@ -127,6 +185,9 @@ pub fn tokenize(file: String) -> Vec<Token> {
     let mut intok = false;
     let mut line = 1;
     let mut col:i128 = -1;
+    // position of the first character of the token being read
+    let mut tok_line = 1;
+    let mut tok_col:i128 = 0;
     macro_rules! flush_token {
         () => {
             if left != right - 1 {
@@ -136,11 +197,11 @@ pub fn tokenize(file: String) -> Vec<Token> {
                                     .0.to_string();
                 tokens.push(Token {
                     content: macro_str,
-                        line,
-                        col: match (col < 0, col > u64::MAX as i128) {
+                        line: tok_line,
+                        col: match (tok_col+1 < 0, tok_col+1 > u64::MAX as i128) {
                                 (true, _) => 0,
                                 (_, true) => u64::MAX,
-                                _ => col as u64
+                                _ => (tok_col+1) as u64
                             },
                         ..Default::default()
                     });
@@ -157,10 +218,9 @@ pub fn tokenize(file: String) -> Vec<Token> {
             charidx[$idx].0
         };
     }
-    let ch: char = '\0';
     macro_rules! cont {
-        () => {
-            if ch == '\n' {
+        ($ch:expr) => {
+            if $ch == '\n' {
                 col = -1;
                 line += 1;
             }
@@ -171,12 +231,17 @@ pub fn tokenize(file: String) -> Vec<Token> {
     while let Some(ch) = chars.next() {
         right += 1;
         col += 1;
+        if left == right - 1 {
+            tok_line = line;
+            tok_col = col;
+        }
 
         if instr != StringType::Not && strescape {
             strescape = false;
-            cont!();
+            cont!(ch);
         }
         if string_specifiers.contains(&ch) {
+            let opening = instr == StringType::Not;
             instr = match (&instr, ch) {
                 (StringType::Not, '\'') => StringType::Char,
                 (StringType::Not, '\"') => StringType::String,
@@ -187,29 +252,27 @@ pub fn tokenize(file: String) -> Vec<Token> {
                 _ => instr,
             };
             if instr == StringType::Not {
-                if left != right - 1 {
-                    let macro_str = file
-                        .split_at(i!(left))
-                        .1.split_at(i!(right - 1) - i!(left))
-                        .0.to_string();
-                    tokens.push(Token {
-                        content: macro_str,
-                        line,
-                        col: match (col < 0, col > u64::MAX as i128) {
-                                (true, _) => 0,
-                                (_, true) => u64::MAX,
-                                _ => col as u64
-                            },
-                        strtype: match ch {
-                            '\'' => StringType::Char,
-                            '\"' => StringType::String,
-                            '`' => StringType::Backtick,
-                            _ => StringType::Not,
+                let macro_str = file
+                    .split_at(i!(left))
+                    .1.split_at(i!(right - 1) - i!(left))
+                    .0.to_string();
+                tokens.push(Token {
+                    content: macro_str,
+                    line: tok_line,
+                    col: match (tok_col < 0, tok_col > u64::MAX as i128) {
+                            (true, _) => 0,
+                            (_, true) => u64::MAX,
+                            _ => tok_col as u64
                         },
-                    });
-                }
+                    strtype: match ch {
+                        '\'' => StringType::Char,
+                        '\"' => StringType::String,
+                        '`' => StringType::Backtick,
+                        _ => StringType::Not,
+                    },
+                });
                 left = right;
-            } else {
+            } else if opening {
                 flush_token!();
             }
         }
@@ -217,15 +280,100 @@ pub fn tokenize(file: String) -> Vec<Token> {
             if ch == '\\' {
                 strescape = true;
             }
-            cont!();
+            cont!(ch);
         }
         if ch == '\\' {
             intok = !intok;
             flush_token!();
-            continue;
+            cont!(ch);
         }
         if intok {
-            cont!();
+            cont!(ch);
+        }
+
+        if ch == '#' {
+            flush_token!();
+            let doc_col = col + 1;
+            let mut text = String::new();
+            while let Some(c) = chars.next_if(|x| *x != '\n') {
+                left += 1;
+                right += 1;
+                col += 1;
+                text.push(c);
+            }
+            multipunc_invalid = true;
+            if let Some(doc) = text.strip_prefix('#') {
+                tokens.push(Token {
+                    content: doc.trim().to_string(),
+                    line,
+                    col: doc_col as u64,
+                    strtype: StringType::Doc,
+                });
+            }
+            cont!(ch);
+        }
+        if ch == '/' && chars.peek() == Some(&'*') {
+            flush_token!();
+            let (start_line, start_col) = (line, col + 1);
+            let mut nesting = 0;
+            let mut last = ch;
+            for c in chars.by_ref() {
+                left += 1;
+                right += 1;
+                col += 1;
+                match (last, c) {
+                    ('/', '*') => {
+                        nesting += 1;
+                        last = ' ';
+                        continue;
+                    }
+                    ('*', '/') => {
+                        nesting -= 1;
+                        last = ' ';
+                        if nesting == 0 {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                if c == '\n' {
+                    line += 1;
+                    col = -1;
+                }
+                last = c;
+            }
+            if nesting > 0 {
+                return Err((
+                    format!("Unterminated block comment. (line {}, col {})", start_line, start_col),
+                    ExitReason::CompileUnterminatedComment,
+                ));
+            }
+            multipunc_invalid = true;
+            cont!(ch);
+        }
+
+        let after_value = tokens.last().is_some_and(|x| {
+            x.strtype != StringType::Not ||
+                x.content == ")" || x.content == "]" ||
+                (x.content.chars().all(|c| c.is_alphanumeric() || c == '_') && !KEYWORDS.contains(&x.content.as_str()))
+        });
+        let numlen = if left == right - 1 { number_len(&charidx, right - 1, after_value) } else { 0 };
+        if numlen > 0 {
+            for _ in 1..numlen {
+                chars.next();
+            }
+            right += numlen - 1;
+            tokens.push(Token {
+                content: file[i!(left)..i!(right)].to_string(),
+                line,
+                col: (col + 1) as u64,
+                ..Default::default()
+            });
+            col += numlen as i128 - 1;
+            left = right;
+            multipunc_invalid = true;
+            cont!(ch);
         }
 
         if whitespace.contains(&ch) {
@@ -250,14 +398,10 @@ pub fn tokenize(file: String) -> Vec<Token> {
                 filtered = Vec::<_>::new();
                 lastpunc = &deftok;
             }
-            if !multipunc_invalid && filtered.len() > 0 {
+            if !multipunc_invalid && !filtered.is_empty() {
                 let new_token = Token {
-                    line,
-                    col: match (col < 0, col > u64::MAX as i128) {
-                            (true, _) => 0,
-                            (_, true) => u64::MAX,
-                            _ => col as u64
-                        },
+                    line: lastpunc.line,
+                    col: lastpunc.col,
                     content: lastpunc.content.clone() + ch.to_string().as_str(),
                     ..Default::default()
                 };
@@ -282,7 +426,44 @@ pub fn tokenize(file: String) -> Vec<Token> {
         else {
             multipunc_invalid = true;
         }
-        cont!();
+        cont!(ch);
+    }
+    return Ok(tokens);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::parse;
+
+    fn tokens(source: &str) -> String {
+        return tokenize(source.to_string()).unwrap().iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" ");
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        // `//` stays integer division, `#` and `/* */` are comments
+        let source = "let a = 7 // 2; # half\n/* block /* nested */ still */ print(a);\n/* multi\nline */ let b";
+        let expected = "<Token \"let\" 1:1> <Token \"a\" 1:5> <Token \"=\" 1:7> <Token \"7\" 1:9> <Token \"//\" 1:11> <Token \"2\" 1:14> <Token \";\" 1:15> \
+            <Token \"print\" 2:32> <Token \"(\" 2:37> <Token \"a\" 2:38> <Token \")\" 2:39> <Token \";\" 2:40> <Token \"let\" 4:9> <Token \"b\" 4:13>";
+        assert_eq!(tokens(source), expected);
+        // comments split tokens
+        assert_eq!(tokens("a /* x */ b/**/c # d\ne"), "<Token \"a\" 1:1> <Token \"b\" 1:11> <Token \"c\" 1:16> <Token \"e\" 2:1>");
+        assert_eq!(tokens("print(\"# no /* no */\");"), "<Token \"print\" 1:1> <Token \"(\" 1:6> <Token s\"# no /* no */\" 1:7> <Token \")\" 1:22> <Token \";\" 1:23>");
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let error = tokenize("let a = 1; /* open /* inner */\nnever closed".to_string()).err();
+        assert_eq!(error, Some(("Unterminated block comment. (line 1, col 12)".to_string(), ExitReason::CompileUnterminatedComment)));
+    }
+
+    #[test]
+    fn doc_comments() {
+        assert_eq!(tokens("## Doubles.\n  ## Twice.\nfn"), "<Token d\"Doubles.\" 1:1> <Token d\"Twice.\" 2:3> <Token \"fn\" 3:1>");
+        let root = parse("## Doubles a number.\n## Twice.\nfn double(x) { return x * 2; }").unwrap();
+        assert_eq!(root.items[0].to_string(), "<Fn \"double\"(x) \"Doubles a number.\\nTwice.\" [<Return <Binary <Var \"x\"> \"*\" <Literal \"2\" (Num)>>>]>");
+        // a doc comment before anything else is dropped with a warning
+        assert_eq!(parse("## Nothing.\nlet a = 1;").unwrap().items.len(), 1);
     }
-    return tokens;
 }
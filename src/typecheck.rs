@@ -0,0 +1,460 @@
+//! Static checking of value kinds. Values in mlog are dynamically typed,
+//! but most instructions only make sense for some kinds: `sensor` reads a
+//! building, unit or content, arithmetic wants numbers. Every variable has
+//! the kind its `let` annotation names, or else the kind of the values
+//! assigned to it anywhere in the program. Operators and instructions are
+//! checked against the kinds of their operands. Values whose kind cannot
+//! be known, like what `sensor` returns, are never flagged.
+
+use std::collections::{ HashMap, HashSet };
+use crate::ast::{ Expr, Module, Pattern, Span, Stmt, VarType };
+use crate::codegen;
+use crate::*;
+
+const ANY: &[VarType] = &[];
+const NUM: &[VarType] = &[VarType::Num];
+const BUILDING: &[VarType] = &[VarType::Building];
+const CONTENT: &[VarType] = &[VarType::Content];
+const SENSABLE: &[VarType] = &[VarType::Building, VarType::Unit, VarType::Content];
+const UNIT: &[VarType] = &[VarType::Unit];
+const UNIT_TYPE: &[VarType] = &[VarType::Content, VarType::Unit];
+
+/// Name of an instruction, kinds every argument may have (`ANY` for all of
+/// them) and the kind of its result (`None` when it cannot be known).
+type Signature = (&'static str, &'static [&'static [VarType]], Option<VarType>);
+
+const SIGNATURES: [Signature; 11] = [
+    ("printflush", &[BUILDING], Some(VarType::Null)),
+    ("drawflush", &[BUILDING], Some(VarType::Null)),
+    ("wait", &[NUM], Some(VarType::Null)),
+    ("write", &[ANY, BUILDING, NUM], Some(VarType::Null)),
+    ("read", &[BUILDING, NUM], None),
+    ("getlink", &[NUM], Some(VarType::Building)),
+    ("sensor", &[SENSABLE, CONTENT], None),
+    ("packcolor", &[NUM, NUM, NUM, NUM], Some(VarType::Num)),
+    ("lookup", &[ANY, NUM], Some(VarType::Content)),
+    ("control", &[ANY, BUILDING], Some(VarType::Null)),
+    ("ubind", &[UNIT_TYPE], Some(VarType::Null)),
+];
+
+/// Commands of `ucontrol` and the kinds of the arguments after the command.
+/// Outputs like the result of `within` take any variable.
+const UCONTROL: [(&str, &[&[VarType]]); 21] = [
+    ("idle", &[]),
+    ("stop", &[]),
+    ("move", &[NUM, NUM]),
+    ("approach", &[NUM, NUM, NUM]),
+    ("pathfind", &[NUM, NUM]),
+    ("autoPathfind", &[]),
+    ("boost", &[NUM]),
+    ("target", &[NUM, NUM, NUM]),
+    ("targetp", &[UNIT, NUM]),
+    ("itemDrop", &[BUILDING, NUM]),
+    ("itemTake", &[BUILDING, CONTENT, NUM]),
+    ("payDrop", &[]),
+    ("payTake", &[NUM]),
+    ("payEnter", &[]),
+    ("mine", &[NUM, NUM]),
+    ("flag", &[NUM]),
+    ("build", &[NUM, NUM, CONTENT, NUM, ANY]),
+    ("getBlock", &[NUM, NUM, ANY, ANY, ANY]),
+    ("within", &[NUM, NUM, NUM, ANY]),
+    ("unbind", &[]),
+    ("deconstruct", &[NUM, NUM]),
+];
+
+/// Globals holding numbers. Other `@` names are content, teams or one of
+/// `@unit` and `@this`.
+const NUM_GLOBALS: [&str; 26] = [
+    "@thisx", "@thisy", "@ipt", "@links", "@time", "@tick", "@second", "@minute", "@waveNumber", "@waveTime", "@mapw",
+    "@maph", "@counter", "@pi", "@e", "@degToRad", "@radToDeg", "@server", "@client", "@ctrlProcessor", "@ctrlPlayer",
+    "@ctrlCommand", "@blockCount", "@unitCount", "@itemCount", "@liquidCount",
+];
+
+const TEAMS: [&str; 6] = ["@derelict", "@sharded", "@crux", "@malis", "@green", "@blue"];
+
+/// Operators taking values of every kind.
+const ANY_OPERANDS: [&str; 6] = ["==", "!=", "===", "!==", "&&", "||"];
+
+fn global_kind(name: &str) -> VarType {
+    return match name {
+        "@unit" => VarType::Unit,
+        "@this" => VarType::Building,
+        _ if NUM_GLOBALS.contains(&name) => VarType::Num,
+        _ if TEAMS.contains(&name) => VarType::Team,
+        _ => VarType::Content,
+    };
+}
+
+/// Whether a value of kind `kind` may be used where `allowed` are. Null
+/// and values of unknown kind fit everywhere, chars fit numbers and the
+/// other way around.
+fn fits(kind: Option<VarType>, allowed: &[VarType]) -> bool {
+    return match kind {
+        None | Some(VarType::Null) => true,
+        Some(VarType::Num | VarType::Char) if allowed.contains(&VarType::Num) || allowed.contains(&VarType::Char) => true,
+        Some(k) => allowed.is_empty() || allowed.contains(&k),
+    };
+}
+
+fn kind_list(kinds: &[VarType]) -> String {
+    let names = kinds.iter().map(|x| format!("{:?}", x)).collect::<Vec<_>>();
+    return match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        _ => names.join(""),
+    };
+}
+
+struct Checker<'a> {
+    /// Kinds named by `let` annotations.
+    declared: HashMap<String, VarType>,
+    /// Kinds of the values assigned to every other variable, `None` when
+    /// they differ.
+    assigned: HashMap<String, Option<VarType>>,
+    funcs: HashSet<&'a str>,
+    /// Parameters of the function being checked, which can hold anything.
+    params: HashSet<&'a str>,
+    /// Whether misuse is reported. Off while the kinds of variables are
+    /// still being collected.
+    report: bool,
+    changed: bool,
+}
+
+type TcResult<T> = Result<T, (String, ExitReason)>;
+
+macro_rules! pos {
+    ($span:expr) => {
+        format!(" (line {}, col {})", $span.line, $span.col)
+    };
+}
+
+impl<'a> Checker<'a> {
+    fn mismatch(&self, message: String, span: Span) -> TcResult<()> {
+        if !self.report {
+            return Ok(());
+        }
+        return Err((format!("{}{}", message, pos!(span)), ExitReason::CompileTypeMismatch));
+    }
+
+    fn var_kind(&self, name: &str) -> Option<VarType> {
+        if self.params.contains(name) {
+            return None;
+        }
+        if let Some(kind) = self.declared.get(name) {
+            return Some(*kind);
+        }
+        return match name {
+            "true" | "false" => Some(VarType::Num),
+            "null" => Some(VarType::Null),
+            _ if name.starts_with('@') => Some(global_kind(name)),
+            _ => self.assigned.get(name).copied().flatten(),
+        };
+    }
+
+    /// Records that `kind` is assigned to `name`.
+    fn assign(&mut self, name: &str, kind: Option<VarType>, span: Span) -> TcResult<()> {
+        if self.params.contains(name) {
+            return Ok(());
+        }
+        if let Some(declared) = self.declared.get(name).copied() {
+            if !fits(kind, &[declared]) {
+                let message = format!("Variable \"{}\" is declared as {:?}, but is assigned {:?}.", name, declared, kind.unwrap_or(VarType::Null));
+                return self.mismatch(message, span);
+            }
+            return Ok(());
+        }
+        let joined = match self.assigned.get(name).copied() {
+            None | Some(Some(VarType::Null)) => kind,
+            Some(old) if kind == Some(VarType::Null) || old == kind => old,
+            Some(_) => None,
+        };
+        if self.assigned.insert(name.to_string(), joined) != Some(joined) {
+            self.changed = true;
+        }
+        return Ok(());
+    }
+
+    fn index(&mut self, index: &Expr) -> TcResult<()> {
+        let kind = self.expr(index)?;
+        if !fits(kind, NUM) {
+            self.mismatch(format!("Array index should be Num, got {:?}.", kind.unwrap_or(VarType::Null)), index.span())?;
+        }
+        return Ok(());
+    }
+
+    /// Checks `expr` and returns its kind, `None` if it cannot be known.
+    fn expr(&mut self, expr: &Expr) -> TcResult<Option<VarType>> {
+        match expr {
+            Expr::Literal { vt, .. } => {
+                return Ok(Some(*vt));
+            }
+            Expr::Var { name, .. } => {
+                return Ok(self.var_kind(name));
+            }
+            Expr::Variant { .. } => {
+                return Ok(Some(VarType::Num));
+            }
+            Expr::Index { name, index, .. } => {
+                self.index(index)?;
+                return Ok(self.var_kind(name));
+            }
+            Expr::Struct { fields, .. } => {
+                for (_, value) in fields {
+                    self.expr(value)?;
+                }
+                return Ok(None);
+            }
+            Expr::Field { base, .. } => {
+                self.expr(base)?;
+                return Ok(None);
+            }
+            Expr::Binary { op, left, right, .. } => {
+                for operand in [left, right] {
+                    let kind = self.expr(operand)?;
+                    if !ANY_OPERANDS.contains(&op.as_str()) && !fits(kind, NUM) {
+                        self.mismatch(format!("Operator '{}' expects numbers, got {:?}.", op, kind.unwrap_or(VarType::Null)), operand.span())?;
+                    }
+                }
+                return Ok(Some(VarType::Num));
+            }
+            Expr::Unary { op, operand, .. } => {
+                let kind = self.expr(operand)?;
+                if op != "!" && !fits(kind, NUM) {
+                    self.mismatch(format!("Operator '{}' expects a number, got {:?}.", op, kind.unwrap_or(VarType::Null)), operand.span())?;
+                }
+                return Ok(Some(VarType::Num));
+            }
+            Expr::Call { name, args, .. } => {
+                let mut kinds = Vec::<Option<VarType>>::new();
+                for arg in args {
+                    kinds.push(self.expr(arg)?);
+                }
+                if self.funcs.contains(name.as_str()) {
+                    return Ok(None);
+                }
+                let ucontrol = match (name.as_str(), args.first()) {
+                    ("ucontrol", Some(Expr::Var { name: command, span })) => match UCONTROL.iter().find(|x| x.0 == command) {
+                        Some((_, params)) => Some([ANY].iter().chain(params.iter()).copied().collect::<Vec<_>>()),
+                        None => {
+                            self.mismatch(format!("Unknown ucontrol command \"{}\".", command), *span)?;
+                            return Ok(Some(VarType::Null));
+                        }
+                    },
+                    _ => None,
+                };
+                let (params, result) = match (ucontrol, SIGNATURES.iter().find(|x| x.0 == name)) {
+                    (Some(params), _) => (params, Some(VarType::Null)),
+                    (None, Some((_, params, result))) => (params.to_vec(), *result),
+                    (None, None) if codegen::MATH.iter().any(|x| x.0 == name) => (vec![NUM; args.len()], Some(VarType::Num)),
+                    (None, None) => {
+                        return Ok(None);
+                    }
+                };
+                for (n, ((arg, kind), allowed)) in args.iter().zip(kinds).zip(params).enumerate() {
+                    if !fits(kind, allowed) {
+                        let message = format!(
+                            "Argument {} of \"{}\" should be {}, got {:?}.",
+                            n + 1,
+                            name,
+                            kind_list(allowed),
+                            kind.unwrap_or(VarType::Null)
+                        );
+                        self.mismatch(message, arg.span())?;
+                    }
+                }
+                return Ok(result);
+            }
+            Expr::Macro { args, .. } => {
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    fn block<'b>(&mut self, body: impl IntoIterator<Item = &'b Stmt>) -> TcResult<()> {
+        for stmt in body {
+            match stmt {
+                Stmt::Assign { target, value, span } | Stmt::Let { name: target, value: Some(value), span, .. } => {
+                    let kind = self.expr(value)?;
+                    self.assign(target, kind, *span)?;
+                }
+                Stmt::Store { name, index, value, span } => {
+                    self.index(index)?;
+                    let kind = self.expr(value)?;
+                    self.assign(name, kind, *span)?;
+                }
+                Stmt::SetField { base, value, .. } => {
+                    self.expr(base)?;
+                    self.expr(value)?;
+                }
+                Stmt::Array { ty, len, memory, span, .. } => {
+                    self.expr(len)?;
+                    if let Some(memory) = memory {
+                        let kind = self.expr(memory)?;
+                        if !fits(kind, BUILDING) {
+                            self.mismatch(format!("Expected a memory cell or bank after \"in\", got {:?}.", kind.unwrap_or(VarType::Null)), memory.span())?;
+                        }
+                        if !fits(Some(*ty), NUM) {
+                            self.mismatch(format!("Memory cells only hold numbers, not {:?}.", ty), *span)?;
+                        }
+                    }
+                }
+                Stmt::Let { value: None, .. } | Stmt::Return { value: None, .. } => {}
+                Stmt::Block { body, .. } => {
+                    self.block(body)?;
+                }
+                Stmt::Match { value, arms, .. } => {
+                    self.expr(value)?;
+                    for arm in arms {
+                        for pattern in &arm.patterns {
+                            if let Pattern::Value(expr) = pattern {
+                                self.expr(expr)?;
+                            }
+                        }
+                        self.block(&arm.body)?;
+                    }
+                }
+                Stmt::Return { value: Some(value), .. } | Stmt::Expr(value) => {
+                    self.expr(value)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Checks the program, every function and every test.
+    fn module(&mut self, root: &'a Module) -> TcResult<()> {
+        self.block(root.statements())?;
+        for func in root.functions() {
+            self.params = func.params.iter().map(|x| x.name.as_str()).collect();
+            self.block(&func.body)?;
+        }
+        self.params.clear();
+        for test in root.tests() {
+            self.block(&test.body)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Collects the annotations of `let` statements in `body`.
+fn annotations<'b>(body: impl IntoIterator<Item = &'b Stmt>, declared: &mut HashMap<String, VarType>) -> TcResult<()> {
+    for stmt in body {
+        match stmt {
+            Stmt::Let { name, ty: Some(ty), span, .. } | Stmt::Array { name, ty, span, .. } => {
+                match declared.insert(name.clone(), *ty) {
+                    Some(old) if old != *ty => {
+                        return Err((
+                            format!("Variable \"{}\" is declared as {:?} and as {:?}.{}", name, old, ty, pos!(span)),
+                            ExitReason::CompileTypeMismatch,
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            Stmt::Block { body, .. } => annotations(body, declared)?,
+            Stmt::Match { arms, .. } => {
+                for arm in arms {
+                    annotations(&arm.body, declared)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    return Ok(());
+}
+
+/// Infers the kind of every variable of `root` and reports the first
+/// misused value.
+pub fn check(root: &Module, opts: &HashMap<String, String>) -> TcResult<()> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    let mut declared = HashMap::<String, VarType>::new();
+    annotations(root.statements(), &mut declared)?;
+    for func in root.functions() {
+        annotations(&func.body, &mut declared)?;
+    }
+    for test in root.tests() {
+        annotations(&test.body, &mut declared)?;
+    }
+    let mut checker = Checker {
+        declared,
+        assigned: HashMap::<String, Option<VarType>>::new(),
+        funcs: root.functions().map(|x| x.name.as_str()).collect(),
+        params: HashSet::<&str>::new(),
+        report: false,
+        changed: true,
+    };
+    // kinds only ever widen, so this settles after a few rounds
+    while checker.changed {
+        checker.changed = false;
+        checker.module(root)?;
+    }
+    for (name, kind) in &checker.assigned {
+        debug!("Kind of \"{}\": {}", name, kind.map(|x| format!("{:?}", x)).unwrap_or("unknown".to_string()));
+    }
+    checker.report = true;
+    checker.module(root)?;
+    info!("Type check success");
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ compile, error };
+
+    fn mismatch(source: &str) -> String {
+        let (message, reason) = error(source);
+        assert_eq!(reason, ExitReason::CompileTypeMismatch, "{}", message);
+        return message;
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(mismatch("let s = \"a\";\nlet n = s * 2;"), "Operator '*' expects numbers, got Str. (line 2, col 9)");
+        assert_eq!(mismatch("let t = @sharded;\nlet n = -t;"), "Operator '-' expects a number, got Team. (line 2, col 10)");
+        assert_eq!(mismatch("let n = @copper + 1;"), "Operator '+' expects numbers, got Content. (line 1, col 9)");
+        // comparisons take anything, chars are numbers
+        compile("let a = @copper == \"a\";\nlet c = 'a' + 1;\nprint(a);\nprint(c);", &[]).unwrap();
+        compile("let n = @blockCount + @unitCount;\nprint(n);", &[]).unwrap();
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(mismatch("sensor(1, @x);"), "Argument 1 of \"sensor\" should be Building, Unit or Content, got Num. (line 1, col 8)");
+        assert_eq!(mismatch("printflush(\"message1\");"), "Argument 1 of \"printflush\" should be Building, got Str. (line 1, col 12)");
+        assert_eq!(mismatch("let b = getlink(0);\nlet x = read(b, @copper);"), "Argument 2 of \"read\" should be Num, got Content. (line 2, col 17)");
+        compile("let x = @unit;\nlet y = sensor(x, @x);\nubind(@poly);\nprint(y);", &[]).unwrap();
+    }
+
+    #[test]
+    fn ucontrol_commands() {
+        assert_eq!(mismatch("let b = getlink(0);\nucontrol(targetp, b, 1);"), "Argument 2 of \"ucontrol\" should be Unit, got Building. (line 2, col 19)");
+        assert_eq!(mismatch("ucontrol(itemTake, @this, 1, 5);"), "Argument 3 of \"ucontrol\" should be Content, got Num. (line 1, col 27)");
+        assert_eq!(mismatch("ucontrol(fly, 1, 2);"), "Unknown ucontrol command \"fly\". (line 1, col 10)");
+        compile("ucontrol(targetp, @unit, 1);\nucontrol(within, 1, 2, 3, inside);\nprint(inside);", &[]).unwrap();
+    }
+
+    #[test]
+    fn annotations() {
+        assert_eq!(mismatch("let u: Unit = @copper;"), "Variable \"u\" is declared as Unit, but is assigned Content. (line 1, col 1)");
+        assert_eq!(mismatch("let x: Num;\nx = \"a\";"), "Variable \"x\" is declared as Num, but is assigned Str. (line 2, col 1)");
+        assert_eq!(mismatch("var x: Num;\nfn f() { var x: Str; }"), "Variable \"x\" is declared as Num and as Str. (line 2, col 10)");
+        // an annotation decides over the values assigned elsewhere
+        assert_eq!(mismatch("let b: Building = getlink(0);\nlet n = b + 1;"), "Operator '+' expects numbers, got Building. (line 2, col 9)");
+    }
+
+    #[test]
+    fn unknown_kinds_are_not_flagged() {
+        // read results, parameters and variables assigned different kinds
+        compile("let v = read(cell1, 0);\nprint(v + 1);", &[]).unwrap();
+        compile("fn f(x) { return x + 1; }\nprint(f(\"a\"));", &[]).unwrap();
+        compile("let a = 1;\na = \"x\";\nprint(-a);", &[]).unwrap();
+    }
+}
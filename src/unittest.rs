@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use crate::codegen::Codegen;
+use crate::ast::{ Module, Test };
+use crate::emulator::{ BlockKind, Building, Emulator };
+use crate::*;
+
+/// Compiles a `test` block on its own. Functions it calls are inlined, so
+/// the program contains exactly the code the test depends on. It is
+/// optimized like a compiled program, so tests check the shipped code.
+pub fn compile_test(root: &Module, test: &Test, opts: &HashMap<String, String>) -> Result<String, (String, ExitReason)> {
+    let level = optimize::opt_level(opts)?;
+    let mut cg = Codegen::new(root, opts)?;
+    cg.block(&test.body)?;
+    optimize::optimize(&mut cg.out, level, opts);
+    return Ok(cg.finish());
+}
+
+/// Runs a compiled test until it ends. Returns the failure message if an
+/// assertion failed, the processor stopped or it ran out of ticks.
+pub fn run_test(
+    mlog: &str,
+    buildings: Vec<Building>,
+    ipt: u64,
+    ticks: u64,
+    opts: &HashMap<String, String>
+) -> Result<Option<String>, (String, ExitReason)> {
+    let program = emulator::parse_mlog(mlog)?;
+    // every assertion was proven at compile time
+    if program.instructions.is_empty() {
+        return Ok(None);
+    }
+    let mut emu = Emulator::new(program, buildings, ipt);
+    emu.once = true;
+    emulator::drive(&mut emu, ticks, opts, |_| {});
+    if emu.wrapped {
+        return Ok(None);
+    }
+    if !emu.stopped {
+        return Ok(Some(format!("did not finish within {} ticks", ticks)));
+    }
+    return Ok(Some(match emu.text_buffer.rfind("assertion failed") {
+        Some(idx) => emu.text_buffer[idx..].to_string(),
+        None => "processor stopped".to_string(),
+    }));
+}
+
+pub fn test(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"test\" expected 1 or 2 arguments. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let (ipt, ticks) = emulator::emulator_opts(opts)?;
+    let root = codegen::prepare(&compile::parse_file(&args[2], opts)?, opts)?;
+    let buildings = match args.get(3) {
+        Some(path) => world::read_world(path)?,
+        None => vec![Building::new("message1", BlockKind::Message { text: String::new() })],
+    };
+
+    let tests = root.tests().collect::<Vec<_>>();
+    if tests.is_empty() {
+        cwarn!("No tests found in \"{}\".", args[2]);
+        return Ok(());
+    }
+    println!("running {} tests", tests.len());
+    let mut failed = Vec::<String>::new();
+    for test in &tests {
+        let name = test.name.clone();
+        info!("Compiling test \"{}\"", name);
+        let mlog = compile_test(&root, test, opts)?;
+        debug!("------ Test \"{}\":\n{}------", name, mlog);
+        match run_test(&mlog, buildings.clone(), ipt, ticks, opts)? {
+            None => {
+                println!("test {} ... ok", name);
+            }
+            Some(reason) => {
+                println!("test {} ... FAILED", name);
+                println!("    {} (line {}, col {}): {}", args[2], test.span.line, test.span.col, reason.replace('\n', "\n    "));
+                failed.push(name);
+            }
+        }
+    }
+    println!("test result: {} passed; {} failed", tests.len() - failed.len(), failed.len());
+    if !failed.is_empty() {
+        return Err((format!("Tests failed: {}", failed.join(", ")), ExitReason::TestFailed));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::harness::{ check_tests, opts };
+    use super::*;
+
+    /// Failure message of every test of `source` at the default level.
+    fn failures(source: &str) -> Vec<(String, Option<String>)> {
+        let opts = opts(&[]);
+        let root = codegen::prepare(&compile::parse_source(source.to_string(), &opts).unwrap(), &opts).unwrap();
+        return root.tests().map(|test| {
+            let mlog = compile_test(&root, test, &opts).unwrap();
+            let buildings = world::parse_world("message1 = message").unwrap();
+            return (test.name.clone(), run_test(&mlog, buildings, 1000, 20, &opts).unwrap());
+        }).collect();
+    }
+
+    #[test]
+    fn passing_tests() {
+        check_tests("fn double(x) { return x * 2; }\ntest \"double\" { assert_eq!(double(@links + 2), 6); assert!(1); }", "");
+    }
+
+    #[test]
+    fn failed_assertion_reports_position_and_message() {
+        let results = failures("test \"bad\" {\n    let a = @links + 1;\n    assert_eq!(a, 3, \"a is one\");\n}");
+        let reason = results[0].1.clone().expect("test should fail");
+        assert!(reason.starts_with("assertion failed (line 3, col 5): a is one"), "{}", reason);
+    }
+
+    #[test]
+    fn endless_test_runs_out_of_ticks() {
+        let results = failures("test \"forever\" { `loop:\njump loop always`; }");
+        assert_eq!(results[0].1.as_deref(), Some("did not finish within 20 ticks"));
+    }
+
+    #[test]
+    fn tests_only_contain_their_own_code() {
+        let opts = opts(&[("opt-level", "0")]);
+        let root = compile::parse_source("print(\"main\");\ntest \"t\" { print(\"test\"); }".to_string(), &opts).unwrap();
+        let root = codegen::prepare(&root, &opts).unwrap();
+        let mlog = compile_test(&root, root.tests().next().unwrap(), &opts).unwrap();
+        assert_eq!(mlog, "print \"test\"\n");
+    }
+}
@@ -0,0 +1,244 @@
+//! World files describe the blocks linked to an emulated processor, so
+//! `simulate`, `render`, `test` and `debug` can run programs against them.
+
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use crate::emulator::{ parse_mlog_num, BlockKind, Building, Emulator };
+use crate::*;
+
+/// Creates a building from its block name as used in game, for example
+/// `memory-cell` or `large-logic-display`.
+fn block_from_name(name: &str, block: &str) -> Option<Building> {
+    let kind = match block {
+        "message" => BlockKind::Message { text: String::new() },
+        "memory-cell" => BlockKind::Memory { memory: vec![0.0; 64], bank: false },
+        "memory-bank" => BlockKind::Memory { memory: vec![0.0; 512], bank: true },
+        "logic-display" => BlockKind::Display { size: 80, large: false },
+        "large-logic-display" => BlockKind::Display { size: 176, large: true },
+        "switch" => BlockKind::Switch,
+        "sorter" => BlockKind::Sorter { item: None, inverted: false },
+        "inverted-sorter" => BlockKind::Sorter { item: None, inverted: true },
+        _ => {
+            return None;
+        }
+    };
+    return Some(Building::new(name, kind));
+}
+
+/// Parses a world file. Every line links one block to the processor, in
+/// order, and may set options or initial memory contents:
+///
+/// ```text
+/// cell1 = memory-cell
+/// cell1[0] = 42
+/// switch1 = switch enabled=false
+/// sorter1 = sorter config=@copper
+/// ```
+pub fn parse_world(source: &str) -> Result<Vec<Building>, (String, ExitReason)> {
+    let mut buildings = Vec::<Building>::new();
+    for (lineidx, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        macro_rules! bad_line {
+            ($($params:tt)*) => {
+                return Err((format!("World file line {}: {}", lineidx + 1, format!($($params)*)), ExitReason::WorldBadLine))
+            };
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => bad_line!("expected \"<name> = <block>\", got \"{}\".", line),
+        };
+
+        if let Some((name, index)) = name.strip_suffix(']').and_then(|x| x.split_once('[')) {
+            let building = match buildings.iter_mut().find(|x| x.name == name) {
+                Some(b) => b,
+                None => bad_line!("block \"{}\" must be linked before setting its memory.", name),
+            };
+            let value = match parse_mlog_num(value) {
+                Some(v) => v,
+                None => bad_line!("\"{}\" is not a number.", value),
+            };
+            match (&mut building.kind, index.trim().parse::<usize>()) {
+                (BlockKind::Memory { memory, .. }, Ok(i)) if i < memory.len() => {
+                    memory[i] = value;
+                }
+                (BlockKind::Memory { memory, .. }, _) => {
+                    bad_line!("index \"{}\" out of range, \"{}\" has {} slots.", index, name, memory.len())
+                }
+                _ => bad_line!("block \"{}\" has no memory.", name),
+            }
+            continue;
+        }
+
+        if buildings.iter().any(|x| x.name == name) {
+            bad_line!("block \"{}\" is linked twice.", name);
+        }
+        let mut parts = value.split_whitespace();
+        let block = parts.next().unwrap_or_default();
+        let mut building = match block_from_name(name, block) {
+            Some(b) => b,
+            None => bad_line!("unknown block \"{}\".", block),
+        };
+        for part in parts {
+            match (part.split_once('='), &mut building.kind) {
+                (Some(("enabled", v)), _) if v == "true" || v == "false" => {
+                    building.enabled = v == "true";
+                }
+                (Some(("config", v)), BlockKind::Sorter { item, .. }) if v.starts_with('@') => {
+                    *item = Some(v[1..].to_string());
+                }
+                (Some(("text", v)), BlockKind::Message { text }) => {
+                    *text = v.to_string();
+                }
+                _ => bad_line!("unknown option \"{}\" for {}.", part, block),
+            }
+        }
+        buildings.push(building);
+    }
+    return Ok(buildings);
+}
+
+/// Writes the state of every linked block back in world file format, only
+/// listing options and memory slots that differ from the defaults.
+pub fn dump_world(emu: &Emulator) -> String {
+    let mut out = String::new();
+    for building in &emu.buildings {
+        out += format!("{} = {}", building.name, building.block_name()).as_str();
+        if !building.enabled {
+            out += " enabled=false";
+        }
+        if let BlockKind::Sorter { item: Some(item), .. } = &building.kind {
+            out += format!(" config=@{}", item).as_str();
+        }
+        out += "\n";
+        match &building.kind {
+            BlockKind::Memory { memory, .. } => {
+                for (i, v) in memory.iter().enumerate() {
+                    if *v != 0.0 {
+                        out += format!("{}[{}] = {}\n", building.name, i, v).as_str();
+                    }
+                }
+            }
+            BlockKind::Message { text } if !text.is_empty() => {
+                for line in text.lines() {
+                    out += format!("# {}: {}\n", building.name, line).as_str();
+                }
+            }
+            _ => {}
+        }
+    }
+    return out;
+}
+
+pub fn read_world(path: &str) -> Result<Vec<Building>, (String, ExitReason)> {
+    let file = match read_to_string(path) {
+        Ok(f) => f,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                return Err((format!("World file \"{}\" not found.", path), ExitReason::RunFileNotFound));
+            }
+            else {
+                return Err((format!("Reading world file \"{}\" failed. Error: {}", path, e.kind()), ExitReason::RunFileNotFound));
+            }
+        }
+    };
+    return parse_world(&file);
+}
+
+pub fn simulate(
+    args: &[String],
+    opts: &HashMap<String, String>
+) -> Result<(), (String, ExitReason)> {
+    macro_rules! opts {
+        () => {
+            &opts
+        };
+    }
+    if args.len() < 3 {
+        return Err((
+            "Command \"simulate\" expected 1 or 2 arguments. 0 were provided.".to_string(),
+            ExitReason::CommandExpectedInputArgument,
+        ));
+    }
+    let (ipt, ticks) = emulator::emulator_opts(opts)?;
+    info!("Reading file");
+    let program = emulator::read_mlog(&args[2])?;
+    let buildings = match args.get(3) {
+        Some(path) => {
+            info!("Reading world file");
+            read_world(path)?
+        }
+        None => {
+            cwarn!("No world file given, the processor has no linked blocks.");
+            Vec::<Building>::new()
+        }
+    };
+    info!("Linked {} blocks", buildings.len());
+    let mut emu = Emulator::new(program, buildings, ipt);
+    emu.once = flag_set(opts, "once");
+    emulator::drive(&mut emu, ticks, opts, |emu| {
+        for (name, text) in &emu.output {
+            for line in text.lines() {
+                println!("{}: {}", name, line);
+            }
+        }
+    });
+    print!("{}", dump_world(&emu));
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{ opts, run_mlog };
+
+    /// Runs mlog once through and returns the world afterwards.
+    fn simulated(mlog: &str, world: &str) -> String {
+        let mut emu = Emulator::new(emulator::parse_mlog(mlog).unwrap(), parse_world(world).unwrap(), 1000);
+        emu.once = true;
+        emulator::drive(&mut emu, 10, &opts(&[]), |_| {});
+        return dump_world(&emu);
+    }
+
+    fn world_error(source: &str) -> String {
+        let (message, reason) = parse_world(source).unwrap_err();
+        assert_eq!(reason, ExitReason::WorldBadLine);
+        return message;
+    }
+
+    #[test]
+    fn memory_sizes_and_initial_contents() {
+        let world = "cell1 = memory-cell\nbank1 = memory-bank\ncell1[63] = 7\nbank1[511] = 0x10 # last slot";
+        let mlog = "read a cell1 63\nread b bank1 511\nread c cell1 64\nprint a\nprint b\nprint c\nprintflush message1";
+        assert_eq!(run_mlog(mlog, &format!("message1 = message\n{}", world)), "7160");
+        assert_eq!(world_error("cell1 = memory-cell\ncell1[64] = 1"), "World file line 2: index \"64\" out of range, \"cell1\" has 64 slots.");
+    }
+
+    #[test]
+    fn writes_show_up_in_the_dumped_world() {
+        let dumped = simulated("write 5 cell1 3\nwrite 1 cell1 99\ncontrol enabled switch1 0", "cell1 = memory-cell\nswitch1 = switch");
+        assert_eq!(dumped, "cell1 = memory-cell\ncell1[3] = 5\nswitch1 = switch enabled=false\n");
+    }
+
+    #[test]
+    fn links_and_sensors() {
+        let world = "message1 = message\nswitch1 = switch enabled=false\nsorter1 = sorter config=@copper";
+        let mlog = "getlink s 1\nsensor e s @enabled\nsensor c sorter1 @config\nprint @links\nprint e\nprint c\nprintflush message1";
+        assert_eq!(run_mlog(mlog, world), "30copper");
+        let dumped = simulated("control config sorter1 @lead", "sorter1 = inverted-sorter");
+        assert_eq!(dumped, "sorter1 = inverted-sorter config=@lead\n");
+    }
+
+    #[test]
+    fn bad_world_lines() {
+        assert_eq!(world_error("cell1"), "World file line 1: expected \"<name> = <block>\", got \"cell1\".");
+        assert_eq!(world_error("\n\nx = reactor"), "World file line 3: unknown block \"reactor\".");
+        assert_eq!(world_error("a = switch\na = switch"), "World file line 2: block \"a\" is linked twice.");
+        assert_eq!(world_error("a = switch\na[0] = 1"), "World file line 2: block \"a\" has no memory.");
+        assert_eq!(world_error("a[0] = 1"), "World file line 1: block \"a\" must be linked before setting its memory.");
+        assert_eq!(world_error("a = switch config=@copper"), "World file line 1: unknown option \"config=@copper\" for switch.");
+    }
+}